@@ -0,0 +1,74 @@
+//! Compares the table-driven decoder against the original decoder, which
+//! scanned every code in the table at each bit position.
+//!
+//! Run with `cargo bench --bench decode`.
+
+use press::huffman::{Code, HuffmanEncoding, Symbol};
+use std::time::{Duration, Instant};
+
+fn naive_decode(encoding: &HuffmanEncoding, input: &[u8]) -> Vec<u8> {
+    let in_bits: Code = Code::from_slice(input);
+    let mut out: Vec<u8> = vec![];
+    let mut cursor = 0;
+
+    while cursor < in_bits.len() {
+        for (s, pat) in encoding.encoding.iter() {
+            let len = pat.len();
+            if cursor + len <= in_bits.len() && *pat == in_bits[cursor..cursor + len] {
+                match s {
+                    Symbol::Char(c) => {
+                        out.push(*c);
+                        cursor += len;
+                        break;
+                    }
+                    Symbol::EOT => return out,
+                    _ => unreachable!("byte tables have no LZ77 codes"),
+                }
+            }
+        }
+    }
+    out
+}
+
+fn time<F: FnMut() -> Vec<u8>>(mut f: F) -> (Duration, Vec<u8>) {
+    let start = Instant::now();
+    let out = f();
+    (start.elapsed(), out)
+}
+
+fn mb_per_s(bytes: usize, d: Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / d.as_secs_f64()
+}
+
+fn main() {
+    let mut paths: Vec<_> = std::fs::read_dir("tests")
+        .expect("Run from the press directory")
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+
+    println!(
+        "{:<30} {:>10} {:>12} {:>12} {:>9}",
+        "file", "bytes", "naive MB/s", "table MB/s", "speed-up"
+    );
+    for path in paths {
+        let data = std::fs::read(&path).unwrap();
+        let encoding = HuffmanEncoding::from_data_vec(&data);
+        let encoded = encoding.encode(&data);
+
+        let (naive_t, naive_out) = time(|| naive_decode(&encoding, &encoded));
+        let (table_t, table_out) = time(|| encoding.decode(&encoded).unwrap());
+        assert_eq!(naive_out, data);
+        assert_eq!(table_out, data);
+
+        println!(
+            "{:<30} {:>10} {:>12.2} {:>12.2} {:>8.1}x",
+            path.file_name().unwrap().to_string_lossy(),
+            data.len(),
+            mb_per_s(data.len(), naive_t),
+            mb_per_s(data.len(), table_t),
+            naive_t.as_secs_f64() / table_t.as_secs_f64()
+        );
+    }
+}
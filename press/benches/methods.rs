@@ -0,0 +1,73 @@
+//! Compares the compressed size and speed of each compression method over
+//! the files in `tests/`.
+//!
+//! Run with `cargo bench --bench methods`.
+
+use press::{CompressOptions, Method};
+use std::time::Instant;
+
+const METHODS: [(Method, u8); 5] = [
+    (Method::Huffman, 0),
+    (Method::Lzh, 0),
+    (Method::Adaptive, 0),
+    (Method::Range, 0),
+    (Method::Range, 1),
+];
+
+fn main() {
+    let mut paths: Vec<_> = std::fs::read_dir("tests")
+        .expect("Run from the press directory")
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+
+    print!("{:<30} {:>10}", "file", "bytes");
+    for (method, order) in METHODS {
+        let name = match method {
+            Method::Range => format!("Range o{}", order),
+            _ => format!("{:?}", method),
+        };
+        print!(" {:>20}", format!("{} (MB/s)", name));
+    }
+    println!();
+
+    for path in paths {
+        let data = std::fs::read(&path).unwrap();
+        print!(
+            "{:<30} {:>10}",
+            path.file_name().unwrap().to_string_lossy(),
+            data.len()
+        );
+
+        for (method, context_order) in METHODS {
+            let options = CompressOptions {
+                method,
+                context_order,
+                ..CompressOptions::default()
+            };
+            let mut compressed = Vec::new();
+            let start = Instant::now();
+            press::compress_with(&data[..], &mut compressed, &options).unwrap();
+            let secs = start.elapsed().as_secs_f64();
+
+            let mut decoded = Vec::new();
+            press::decompress(&compressed[..], &mut decoded).unwrap();
+            assert_eq!(
+                decoded,
+                data,
+                "{:?} round trip of {}",
+                method,
+                path.display()
+            );
+
+            let cell = format!(
+                "{} ({:.1})",
+                compressed.len(),
+                data.len() as f64 / 1_000_000.0 / secs
+            );
+            print!(" {:>20}", cell);
+        }
+        println!();
+    }
+}
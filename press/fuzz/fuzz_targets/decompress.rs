@@ -0,0 +1,9 @@
+//! Arbitrary bytes, press or gzip, must decompress or fail with an error.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = press::decompress(data, std::io::sink());
+});
@@ -0,0 +1,13 @@
+//! Arbitrary bytes must restore to a code table or fail with an error, and
+//! a table that restores must decode anything without panicking.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use press::HuffmanEncoding;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(encoding) = HuffmanEncoding::restore_from(data) {
+        let _ = encoding.decode(data);
+    }
+});
@@ -0,0 +1,304 @@
+//! Adaptive Huffman coding with the FGK algorithm, for the `adaptive`
+//! method.
+//!
+//! Encoder and decoder start from the same tree holding only the NYT ("not
+//! yet transmitted") node and update it identically after every symbol, so
+//! no code table is stored and the data is coded in a single pass. A symbol
+//! seen for the first time is sent as the code of NYT followed by its
+//! `SYMBOL_BITS`-bit index. The tree is kept across the blocks of a file.
+//!
+//! A payload is a bitstream ending with EOT and padded to a byte boundary.
+
+use crate::bits::{BitReader, BitSource, BitWriter};
+use crate::huffman::{HuffmanError, Symbol};
+
+/// Number of symbols: every byte value and EOT.
+const SYMBOLS: usize = 257;
+
+/// Bits used to send the index of a symbol the first time it is seen.
+const SYMBOL_BITS: usize = 9;
+
+/// Nodes of a full tree over every symbol and NYT.
+const NODES: usize = 2 * SYMBOLS + 1;
+
+const NONE: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct Node {
+    weight: u64,
+    parent: usize,
+    /// Children of an internal node; `NONE` for leaves.
+    left: usize,
+    right: usize,
+    /// Symbol of a leaf; `None` for internal nodes and NYT.
+    symbol: Option<Symbol>,
+}
+
+/// The shared model of the adaptive coder.
+///
+/// Nodes are stored at the index of their FGK node number, the root at the
+/// highest index, so that weights never decrease with the index. This
+/// sibling property is what keeps the tree a Huffman tree as weights change.
+pub(crate) struct AdaptiveHuffman {
+    nodes: Vec<Node>,
+    leaves: Vec<usize>,
+    nyt: usize,
+}
+
+impl AdaptiveHuffman {
+    pub(crate) fn new() -> Self {
+        let root = NODES - 1;
+        let mut nodes = vec![
+            Node {
+                weight: 0,
+                parent: NONE,
+                left: NONE,
+                right: NONE,
+                symbol: None,
+            };
+            NODES
+        ];
+        nodes[root].parent = NONE;
+        AdaptiveHuffman {
+            nodes,
+            leaves: vec![NONE; SYMBOLS],
+            nyt: root,
+        }
+    }
+
+    fn root(&self) -> usize {
+        NODES - 1
+    }
+
+    /// Code of a node, read from the root down. Left is 0 and right is 1.
+    fn write_path(&self, node: usize, bits: &mut BitWriter) {
+        let mut path = Vec::new();
+        let mut n = node;
+        while n != self.root() {
+            let parent = self.nodes[n].parent;
+            path.push(self.nodes[parent].right == n);
+            n = parent;
+        }
+        for bit in path.iter().rev() {
+            bits.write_bits(*bit as u32, 1);
+        }
+    }
+
+    pub(crate) fn encode(&mut self, symbol: Symbol, bits: &mut BitWriter) {
+        let leaf = self.leaves[symbol.index()];
+        if leaf == NONE {
+            self.write_path(self.nyt, bits);
+            bits.write_bits(symbol.index() as u32, SYMBOL_BITS);
+        } else {
+            self.write_path(leaf, bits);
+        }
+        self.update(symbol);
+    }
+
+    pub(crate) fn decode<B: BitSource>(&mut self, bits: &mut B) -> Result<Symbol, HuffmanError> {
+        let mut n = self.root();
+        while self.nodes[n].left != NONE {
+            n = if bits.read_bit()? {
+                self.nodes[n].right
+            } else {
+                self.nodes[n].left
+            };
+        }
+
+        let symbol = match self.nodes[n].symbol {
+            Some(symbol) => symbol,
+            None => {
+                let mut index = 0;
+                for i in 0..SYMBOL_BITS {
+                    index |= (bits.read_bit()? as usize) << i;
+                }
+                if index >= SYMBOLS || self.leaves[index] != NONE {
+                    return Err(HuffmanError::BadBitstream("bad new symbol"));
+                }
+                Symbol::from_index(index)
+            }
+        };
+        self.update(symbol);
+        Ok(symbol)
+    }
+
+    /// Give `symbol` a leaf if it has none, by splitting NYT into a new NYT
+    /// and the new leaf.
+    fn leaf_for(&mut self, symbol: Symbol) -> usize {
+        let leaf = self.leaves[symbol.index()];
+        if leaf != NONE {
+            return leaf;
+        }
+
+        let parent = self.nyt;
+        let (nyt, leaf) = (parent - 2, parent - 1);
+        self.nodes[parent].left = nyt;
+        self.nodes[parent].right = leaf;
+        self.nodes[nyt].parent = parent;
+        self.nodes[leaf].parent = parent;
+        self.nodes[leaf].symbol = Some(symbol);
+        self.leaves[symbol.index()] = leaf;
+        self.nyt = nyt;
+        leaf
+    }
+
+    /// Count one more occurrence of `symbol`. Each node on the way to the
+    /// root is first swapped with the highest numbered node of the same
+    /// weight, so that incrementing it keeps the sibling property.
+    fn update(&mut self, symbol: Symbol) {
+        let mut n = self.leaf_for(symbol);
+        loop {
+            let weight = self.nodes[n].weight;
+            let mut leader = n;
+            while leader + 1 < NODES && self.nodes[leader + 1].weight == weight {
+                leader += 1;
+            }
+            if leader != n && leader != self.nodes[n].parent {
+                self.swap(n, leader);
+                n = leader;
+            }
+
+            self.nodes[n].weight += 1;
+            if n == self.root() {
+                break;
+            }
+            n = self.nodes[n].parent;
+        }
+    }
+
+    /// Exchange the subtrees at node numbers `a` and `b`, which keep their
+    /// places under their parents.
+    fn swap(&mut self, a: usize, b: usize) {
+        let (parent_a, parent_b) = (self.nodes[a].parent, self.nodes[b].parent);
+        self.nodes.swap(a, b);
+        self.nodes[a].parent = parent_a;
+        self.nodes[b].parent = parent_b;
+
+        for n in [a, b] {
+            let node = self.nodes[n];
+            if node.left != NONE {
+                self.nodes[node.left].parent = n;
+                self.nodes[node.right].parent = n;
+            }
+            match node.symbol {
+                Some(symbol) => self.leaves[symbol.index()] = n,
+                None if node.left == NONE => self.nyt = n,
+                None => {}
+            }
+        }
+    }
+}
+
+/// Code a block with the model, updating it as it goes.
+pub(crate) fn encode_block(model: &mut AdaptiveHuffman, block: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    for c in block {
+        model.encode(Symbol::Char(*c), &mut bits);
+    }
+    model.encode(Symbol::EOT, &mut bits);
+    bits.finish()
+}
+
+/// Decode a payload written by `encode_block`, appending the data to `out`.
+/// Fails as soon as the block holds more than `limit` bytes.
+pub(crate) fn decode_block(
+    model: &mut AdaptiveHuffman,
+    payload: &[u8],
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), HuffmanError> {
+    let end = out.len().saturating_add(limit);
+    let mut bits = BitReader::new(payload);
+    loop {
+        match model.decode(&mut bits)? {
+            Symbol::Char(_) if out.len() >= end => {
+                return Err(HuffmanError::BadBitstream("more data than the block holds"))
+            }
+            Symbol::Char(c) => out.push(c),
+            Symbol::EOT => break,
+            _ => return Err(HuffmanError::BadBitstream("unexpected LZ77 code")),
+        }
+    }
+    if bits.remaining() >= 8 {
+        return Err(HuffmanError::BadBitstream("data after EOT"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Check the sibling property and that every link goes both ways.
+    fn check_tree(model: &AdaptiveHuffman) {
+        let root = model.root();
+        for n in model.nyt..NODES {
+            let node = model.nodes[n];
+            if n + 1 < NODES {
+                assert!(node.weight <= model.nodes[n + 1].weight);
+            }
+            if node.left != NONE {
+                assert_eq!(model.nodes[node.left].parent, n);
+                assert_eq!(model.nodes[node.right].parent, n);
+                let children = model.nodes[node.left].weight + model.nodes[node.right].weight;
+                assert_eq!(node.weight, children);
+            } else if n != model.nyt {
+                assert_eq!(model.leaves[node.symbol.unwrap().index()], n);
+            }
+            assert!(n == root || node.parent != NONE);
+        }
+    }
+
+    #[test]
+    fn keeps_sibling_property() {
+        let mut model = AdaptiveHuffman::new();
+        let mut bits = BitWriter::new();
+        for c in b"abracadabra, a tree grows as it is coded" {
+            model.encode(Symbol::Char(*c), &mut bits);
+            check_tree(&model);
+        }
+    }
+
+    #[test]
+    fn can_round_trip_blocks_with_shared_model() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut encoder = AdaptiveHuffman::new();
+        let mut decoder = AdaptiveHuffman::new();
+
+        let mut out = Vec::new();
+        for block in text.chunks(1000).chain([&b""[..]]) {
+            let payload = encode_block(&mut encoder, block);
+            decode_block(&mut decoder, &payload, &mut out, usize::MAX).unwrap();
+        }
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn codes_close_to_static_huffman() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let adaptive = encode_block(&mut AdaptiveHuffman::new(), &text).len();
+
+        let encoding = crate::huffman::HuffmanEncoding::from_data_vec(&text);
+        let static_len = encoding.encode(&text).len() + encoding.save().len();
+        assert!(adaptive < static_len + static_len / 50);
+    }
+
+    #[test]
+    fn rejects_repeated_new_symbol() {
+        let mut bits = BitWriter::new();
+        bits.write_bits(b'a' as u32, SYMBOL_BITS);
+        // NYT is now the left child of the root.
+        bits.write_bits(0, 1);
+        bits.write_bits(b'a' as u32, SYMBOL_BITS);
+        let payload = bits.finish();
+
+        let result = decode_block(
+            &mut AdaptiveHuffman::new(),
+            &payload,
+            &mut Vec::new(),
+            usize::MAX,
+        );
+        assert!(matches!(result, Err(HuffmanError::BadBitstream(_))));
+    }
+}
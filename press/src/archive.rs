@@ -0,0 +1,571 @@
+//! Archives of directory trees, with each file compressed as a press file of
+//! its own.
+//!
+//! An archive is laid out as:
+//!
+//! ```text
+//! MAGIC | version: u8
+//! { kind: u8 | mode: u32 | mtime: i64 | path_len: u16 | path | body }*
+//! 0: u8
+//! ```
+//!
+//! Paths are UTF-8, relative to the archived directory and separated by `/`.
+//! `mode` holds the Unix permission bits and `mtime` the modification time,
+//! in seconds since the Unix epoch. The body of a file is
+//! `size: u64 | payload_len: u64 | payload`, the payload being the press file
+//! of its data, and the body of a symlink is `target_len: u16 | target`.
+//! Directories have no body, and come before the entries they hold.
+
+use crate::{CompressOptions, Decoder, Encoder, HuffmanError};
+
+use std::fs::{self, File, Metadata};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: [u8; 5] = [b'P', b'R', b'E', b'S', b'A'];
+
+const VERSION: u8 = 1;
+
+const END: u8 = 0;
+const FILE: u8 = 1;
+const DIRECTORY: u8 = 2;
+const SYMLINK: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    /// A regular file of `size` bytes.
+    File {
+        size: u64,
+    },
+    Directory,
+    /// A symbolic link to `target`, which is stored as it is.
+    Symlink {
+        target: String,
+    },
+}
+
+/// An entry of an archive, as listed by `ArchiveReader::next_entry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub mtime: i64,
+}
+
+/// Writes an archive to a seekable output, which lets the length of each
+/// compressed file be filled in once it has been written.
+pub struct ArchiveWriter<W: Write + Seek> {
+    output: W,
+    options: CompressOptions,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    /// Start an archive whose files are compressed with `options`.
+    pub fn new(mut output: W, options: CompressOptions) -> Result<Self, HuffmanError> {
+        output.write_all(&MAGIC)?;
+        output.write_all(&[VERSION])?;
+        Ok(ArchiveWriter { output, options })
+    }
+
+    /// Add everything under `dir`, in name order, without following symlinks.
+    pub fn add_tree(&mut self, dir: &Path) -> Result<(), HuffmanError> {
+        self.add_dir_entries(dir, "")
+    }
+
+    fn add_dir_entries(&mut self, dir: &Path, prefix: &str) -> Result<(), HuffmanError> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().into_string().map_err(|name| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:?}: file name is not UTF-8", dir.join(name)),
+                )
+            })?;
+            let path = format!("{}{}", prefix, name);
+            let metadata = fs::symlink_metadata(entry.path())?;
+
+            if metadata.is_dir() {
+                self.write_entry_header(DIRECTORY, &path, &metadata)?;
+                self.add_dir_entries(&entry.path(), &format!("{}/", path))?;
+            } else if metadata.is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                let target = target.to_str().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?}: link target is not UTF-8", entry.path()),
+                    )
+                })?;
+                self.write_entry_header(SYMLINK, &path, &metadata)?;
+                write_string(&mut self.output, target)?;
+            } else if metadata.is_file() {
+                self.write_entry_header(FILE, &path, &metadata)?;
+                self.write_file(&mut File::open(entry.path())?)?;
+            }
+            // Sockets, devices and the like are left out.
+        }
+        Ok(())
+    }
+
+    fn write_entry_header(
+        &mut self,
+        kind: u8,
+        path: &str,
+        metadata: &Metadata,
+    ) -> Result<(), HuffmanError> {
+        self.output.write_all(&[kind])?;
+        self.output.write_all(&mode(metadata).to_be_bytes())?;
+        self.output.write_all(&mtime(metadata).to_be_bytes())?;
+        write_string(&mut self.output, path)
+    }
+
+    /// Compress `file` as the body of a file entry, going back to fill in its
+    /// size and payload length.
+    fn write_file(&mut self, file: &mut File) -> Result<(), HuffmanError> {
+        let lengths_at = self.output.stream_position()?;
+        self.output.write_all(&[0; 16])?;
+
+        let mut encoder = Encoder::with_options(&mut self.output, self.options);
+        let size = std::io::copy(file, &mut encoder)?;
+        encoder.finish()?;
+
+        let end = self.output.stream_position()?;
+        self.output.seek(SeekFrom::Start(lengths_at))?;
+        self.output.write_all(&size.to_be_bytes())?;
+        self.output
+            .write_all(&(end - lengths_at - 16).to_be_bytes())?;
+        self.output.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// Write the end of the archive and return the output.
+    pub fn finish(mut self) -> Result<W, HuffmanError> {
+        self.output.write_all(&[END])?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+/// Reads the entries of an archive in order.
+pub struct ArchiveReader<R: BufRead> {
+    input: R,
+    /// Bytes of the last file entry's payload not read yet.
+    payload_left: u64,
+    /// Size of the last file entry, while its payload is unread.
+    size: Option<u64>,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(mut input: R) -> Result<Self, HuffmanError> {
+        let mut magic = [0u8; MAGIC.len() + 1];
+        input.read_exact(&mut magic)?;
+        if magic[..MAGIC.len()] != MAGIC {
+            return Err(HuffmanError::BadMagic);
+        }
+        if magic[MAGIC.len()] != VERSION {
+            return Err(HuffmanError::UnsupportedVersion(magic[MAGIC.len()]));
+        }
+        Ok(ArchiveReader {
+            input,
+            payload_left: 0,
+            size: None,
+        })
+    }
+
+    /// Read the next entry, skipping the data of the one before if it was not
+    /// read. Returns `None` at the end of the archive.
+    pub fn next_entry(&mut self) -> Result<Option<Entry>, HuffmanError> {
+        let mut payload = self.input.by_ref().take(self.payload_left);
+        if std::io::copy(&mut payload, &mut std::io::sink())? < self.payload_left {
+            return Err(HuffmanError::Truncated);
+        }
+        self.payload_left = 0;
+        self.size = None;
+
+        let mut kind = [0u8; 1];
+        self.input.read_exact(&mut kind)?;
+        if kind[0] == END {
+            return Ok(None);
+        }
+        let mut fields = [0u8; 12];
+        self.input.read_exact(&mut fields)?;
+        let mode = u32::from_be_bytes(fields[..4].try_into().unwrap());
+        let mtime = i64::from_be_bytes(fields[4..].try_into().unwrap());
+        let path = read_string(&mut self.input)?;
+
+        let kind = match kind[0] {
+            FILE => {
+                let size = read_u64(&mut self.input)?;
+                self.payload_left = read_u64(&mut self.input)?;
+                self.size = Some(size);
+                EntryKind::File { size }
+            }
+            DIRECTORY => EntryKind::Directory,
+            SYMLINK => EntryKind::Symlink {
+                target: read_string(&mut self.input)?,
+            },
+            _ => return Err(HuffmanError::BadArchive("unknown entry kind")),
+        };
+        Ok(Some(Entry {
+            path,
+            kind,
+            mode,
+            mtime,
+        }))
+    }
+
+    /// Decompress the data of the file entry last returned by `next_entry`.
+    pub fn read_data<W: Write>(&mut self, output: &mut W) -> Result<(), HuffmanError> {
+        let size = self
+            .size
+            .take()
+            .ok_or(HuffmanError::BadArchive("no file data to read"))?;
+        let mut payload = self.input.by_ref().take(self.payload_left);
+        let mut decoder = Decoder::new(&mut payload);
+        let written = std::io::copy(&mut decoder, output)?;
+        if payload.limit() != 0 {
+            return Err(HuffmanError::BadArchive("data after the end of a file"));
+        }
+        self.payload_left = 0;
+        if written != size {
+            return Err(HuffmanError::SizeMismatch {
+                expected: size,
+                actual: written,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Options for `extract_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtractOptions {
+    /// Restore the setuid, setgid and sticky bits of entries as well as their
+    /// read, write and execute permissions. Off by default, so extracting an
+    /// archive from elsewhere cannot make a setuid program.
+    pub preserve_permissions: bool,
+}
+
+/// Extract every entry of `archive` under `dest`, returning the entries.
+///
+/// Entries whose path is absolute, goes up with `..`, or goes through a
+/// symlink are refused, so nothing is written outside `dest`. The targets of
+/// symlinks are not checked, as they are only followed when used. Only the
+/// read, write and execute permissions of entries are restored.
+pub fn extract<R: BufRead>(archive: R, dest: &Path) -> Result<Vec<Entry>, HuffmanError> {
+    extract_with(archive, dest, &ExtractOptions::default())
+}
+
+/// Extract every entry of `archive` under `dest` like `extract`, with the
+/// permissions `options` allows.
+pub fn extract_with<R: BufRead>(
+    archive: R,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<Vec<Entry>, HuffmanError> {
+    let mode_mask = if options.preserve_permissions {
+        0o7777
+    } else {
+        0o777
+    };
+    let mut reader = ArchiveReader::new(archive)?;
+    let mut entries = Vec::new();
+    fs::create_dir_all(dest)?;
+
+    while let Some(entry) = reader.next_entry()? {
+        let target = target_path(dest, &entry.path)?;
+        if fs::symlink_metadata(&target).is_ok_and(|m| m.is_symlink()) {
+            fs::remove_file(&target)?;
+        }
+
+        match &entry.kind {
+            EntryKind::File { .. } => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&target)?;
+                reader.read_data(&mut file)?;
+                set_mode(&target, entry.mode & mode_mask)?;
+                file.set_modified(system_time(entry.mtime))?;
+            }
+            EntryKind::Directory => fs::create_dir_all(&target)?,
+            EntryKind::Symlink { target: link } => make_symlink(link, &target)?,
+        }
+        entries.push(entry);
+    }
+
+    // Directories are set up last, as extracting into them changes their
+    // mtime and may need permissions they do not have.
+    for entry in entries.iter().rev() {
+        if entry.kind == EntryKind::Directory {
+            let target = dest.join(&entry.path);
+            File::open(&target)?.set_modified(system_time(entry.mtime))?;
+            set_mode(&target, entry.mode & mode_mask)?;
+        }
+    }
+    Ok(entries)
+}
+
+/// Where the entry at `path` goes under `dest`, refusing any path that could
+/// lead outside of it.
+fn target_path(dest: &Path, path: &str) -> Result<PathBuf, HuffmanError> {
+    let unsafe_path = || HuffmanError::UnsafePath(path.to_string());
+    let components: Vec<Component> = Path::new(path).components().collect();
+    if components.is_empty() || path.contains('\\') {
+        return Err(unsafe_path());
+    }
+
+    let mut target = dest.to_path_buf();
+    for (i, component) in components.iter().enumerate() {
+        let Component::Normal(name) = component else {
+            return Err(unsafe_path());
+        };
+        if i > 0 && fs::symlink_metadata(&target).is_ok_and(|m| m.is_symlink()) {
+            return Err(unsafe_path());
+        }
+        target.push(name);
+    }
+    Ok(target)
+}
+
+fn mtime(metadata: &Metadata) -> i64 {
+    match metadata.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(since)) => since.as_secs() as i64,
+        Ok(Err(before)) => -(before.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+fn system_time(mtime: i64) -> SystemTime {
+    if mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(mtime as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), HuffmanError> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), HuffmanError> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(link: &str, path: &Path) -> Result<(), HuffmanError> {
+    std::os::unix::fs::symlink(link, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_link: &str, _path: &Path) -> Result<(), HuffmanError> {
+    Err(HuffmanError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "symlinks can only be extracted on Unix",
+    )))
+}
+
+fn write_string<W: Write>(output: &mut W, s: &str) -> Result<(), HuffmanError> {
+    let len: u16 = s.len().try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: path too long", s),
+        )
+    })?;
+    output.write_all(&len.to_be_bytes())?;
+    output.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_string<R: Read>(input: &mut R) -> Result<String, HuffmanError> {
+    let mut len = [0u8; 2];
+    input.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u16::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| HuffmanError::BadArchive("path is not UTF-8"))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> Result<u64, HuffmanError> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    /// A fresh directory for the test called `name`.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("press-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn archive(dir: &Path) -> Vec<u8> {
+        let mut writer =
+            ArchiveWriter::new(Cursor::new(Vec::new()), CompressOptions::default()).unwrap();
+        writer.add_tree(dir).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// An archive of the given entries, each file holding its path.
+    fn archive_of(entries: &[(u8, &str)]) -> Vec<u8> {
+        archive_with_mode(entries, 0o644)
+    }
+
+    /// An archive of the given entries like `archive_of`, all with `mode`.
+    fn archive_with_mode(entries: &[(u8, &str)], mode: u32) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        for (kind, path) in entries {
+            out.push(*kind);
+            out.extend(mode.to_be_bytes());
+            out.extend(0i64.to_be_bytes());
+            write_string(&mut out, path).unwrap();
+            match *kind {
+                FILE => {
+                    let mut payload = Vec::new();
+                    crate::compress(path.as_bytes(), &mut payload).unwrap();
+                    out.extend((path.len() as u64).to_be_bytes());
+                    out.extend((payload.len() as u64).to_be_bytes());
+                    out.extend(payload);
+                }
+                SYMLINK => write_string(&mut out, "/tmp").unwrap(),
+                _ => {}
+            }
+        }
+        out.push(END);
+        out
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn can_round_trip_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let src = scratch_dir("archive-src");
+        fs::create_dir(src.join("sub")).unwrap();
+        fs::copy("tests/test-10kB.txt", src.join("sub/text.txt")).unwrap();
+        fs::write(src.join("empty"), b"").unwrap();
+        fs::set_permissions(src.join("empty"), fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink("sub/text.txt", src.join("link")).unwrap();
+        File::open(src.join("sub/text.txt"))
+            .unwrap()
+            .set_modified(system_time(1_000_000_000))
+            .unwrap();
+
+        let archive = archive(&src);
+        let mut reader = ArchiveReader::new(&archive[..]).unwrap();
+        let mut paths = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            paths.push(entry.path);
+        }
+        assert_eq!(paths, ["empty", "link", "sub", "sub/text.txt"]);
+
+        let dest = scratch_dir("archive-dest");
+        extract(&archive[..], &dest).unwrap();
+        assert_eq!(
+            fs::read(dest.join("sub/text.txt")).unwrap(),
+            fs::read("tests/test-10kB.txt").unwrap()
+        );
+        assert_eq!(
+            fs::read_link(dest.join("link")).unwrap(),
+            Path::new("sub/text.txt")
+        );
+        let empty = fs::metadata(dest.join("empty")).unwrap();
+        assert_eq!(empty.permissions().mode() & 0o7777, 0o600);
+        let text = fs::metadata(dest.join("sub/text.txt")).unwrap();
+        assert_eq!(mtime(&text), 1_000_000_000);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn drops_setuid_bits_unless_preserving_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let archive = archive_with_mode(&[(FILE, "tool")], 0o4755);
+        let dest = scratch_dir("archive-setuid");
+        extract(&archive[..], &dest).unwrap();
+        let tool = fs::metadata(dest.join("tool")).unwrap();
+        assert_eq!(tool.permissions().mode() & 0o7777, 0o755);
+
+        let dest = scratch_dir("archive-setuid-preserved");
+        let options = ExtractOptions {
+            preserve_permissions: true,
+        };
+        extract_with(&archive[..], &dest, &options).unwrap();
+        let tool = fs::metadata(dest.join("tool")).unwrap();
+        assert_eq!(tool.permissions().mode() & 0o7777, 0o4755);
+    }
+
+    #[test]
+    fn refuses_paths_leaving_destination() {
+        let dest = scratch_dir("archive-traversal");
+        for path in [
+            "../escaped",
+            "/tmp/escaped",
+            "a/../../escaped",
+            "",
+            "a\\..\\b",
+        ] {
+            let archive = archive_of(&[(FILE, path)]);
+            let result = extract(&archive[..], &dest);
+            assert!(
+                matches!(result, Err(HuffmanError::UnsafePath(_))),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refuses_paths_through_symlinks() {
+        let dest = scratch_dir("archive-symlink");
+        let archive = archive_of(&[(SYMLINK, "link"), (FILE, "link/escaped")]);
+
+        let result = extract(&archive[..], &dest);
+        assert!(matches!(result, Err(HuffmanError::UnsafePath(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_archive() {
+        let archive = archive_of(&[(DIRECTORY, "a"), (FILE, "a/b")]);
+        let mut reader = ArchiveReader::new(&archive[..archive.len() - 10]).unwrap();
+        reader.next_entry().unwrap();
+        reader.next_entry().unwrap();
+
+        let result = reader.read_data(&mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+    }
+}
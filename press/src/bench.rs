@@ -0,0 +1,485 @@
+//! Measures how small and how fast compression is over a corpus of files,
+//! for `press bench`.
+//!
+//! Heap use is only measured in programs that install [`TrackingAllocator`]
+//! as their global allocator and call [`TrackingAllocator::start_counting`],
+//! as the `press` binary does for `press bench`.
+
+use crate::inspect::Layout;
+use crate::{CompressOptions, DecompressOptions, HuffmanError};
+
+use serde::{Deserialize, Serialize};
+
+use std::alloc::{GlobalAlloc, Layout as AllocLayout, System};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::time::Instant;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+// Signed, as memory allocated before counting started may be freed after.
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+
+/// The system allocator, keeping count of the bytes allocated and of the
+/// most allocated at once once `start_counting` has been called. Until then
+/// it costs no more than one load of a flag per call.
+pub struct TrackingAllocator;
+
+impl TrackingAllocator {
+    /// Count allocations from now on.
+    pub fn start_counting() {
+        COUNTING.store(true, Ordering::Relaxed);
+    }
+
+    fn grow(size: usize) {
+        if COUNTING.load(Ordering::Relaxed) {
+            let now = ALLOCATED.fetch_add(size as isize, Ordering::Relaxed) + size as isize;
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+    }
+
+    fn shrink(size: usize) {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATED.fetch_sub(size as isize, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: AllocLayout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: AllocLayout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: AllocLayout) {
+        System.dealloc(ptr, layout);
+        Self::shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: AllocLayout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            Self::grow(new_size);
+            Self::shrink(layout.size());
+        }
+        new
+    }
+}
+
+/// Heap use measured from the point this is made, if `TrackingAllocator`
+/// is installed and counting.
+struct HeapWatch {
+    start: isize,
+}
+
+impl HeapWatch {
+    fn start() -> HeapWatch {
+        let start = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(start, Ordering::Relaxed);
+        HeapWatch { start }
+    }
+
+    /// Most bytes allocated at once on top of those allocated at the start,
+    /// or `None` if allocations are not being counted.
+    fn peak(&self) -> Option<u64> {
+        let peak = PEAK.load(Ordering::Relaxed);
+        // `HeapWatch::start` set `PEAK` to `start`, so it is never below it.
+        COUNTING
+            .load(Ordering::Relaxed)
+            .then(|| peak.saturating_sub(self.start) as u64)
+    }
+}
+
+/// How one file compressed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub name: String,
+    pub original_size: u64,
+    pub compressed_size: u64,
+    /// Bytes of the compressed file that are not coded data: the header,
+    /// block headers, code tables and footer.
+    pub overhead: u64,
+    /// Fastest of the runs, in seconds.
+    pub compress_secs: f64,
+    pub decompress_secs: f64,
+    /// Most heap allocated at once while compressing or decompressing, on
+    /// top of the input, if it was measured.
+    pub peak_heap: Option<u64>,
+}
+
+impl Measurement {
+    /// Compressed size as a fraction of the original size.
+    pub fn ratio(&self) -> f64 {
+        self.compressed_size as f64 / self.original_size.max(1) as f64
+    }
+
+    /// Original megabytes (10^6 bytes) compressed per second.
+    pub fn compress_speed(&self) -> f64 {
+        megabytes_per_second(self.original_size, self.compress_secs)
+    }
+
+    /// Original megabytes (10^6 bytes) decompressed per second.
+    pub fn decompress_speed(&self) -> f64 {
+        megabytes_per_second(self.original_size, self.decompress_secs)
+    }
+}
+
+fn megabytes_per_second(bytes: u64, secs: f64) -> f64 {
+    if secs > 0.0 {
+        bytes as f64 / 1e6 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Compress and decompress `data` `runs` times with `options`, keeping the
+/// fastest time of each, and check that it comes back unchanged.
+pub fn measure(
+    name: &str,
+    data: &[u8],
+    options: &CompressOptions,
+    runs: usize,
+) -> Result<Measurement, HuffmanError> {
+    let decompress_options = DecompressOptions {
+        threads: options.threads,
+        ..DecompressOptions::default()
+    };
+    let mut measurement = Measurement {
+        name: name.to_string(),
+        original_size: data.len() as u64,
+        compressed_size: 0,
+        overhead: 0,
+        compress_secs: f64::INFINITY,
+        decompress_secs: f64::INFINITY,
+        peak_heap: None,
+    };
+
+    for run in 0..runs.max(1) {
+        let watch = HeapWatch::start();
+        let start = Instant::now();
+        let mut compressed = Vec::new();
+        crate::compress_with(data, &mut compressed, options)?;
+        measurement.compress_secs = measurement.compress_secs.min(secs_since(start));
+        let compress_peak = watch.peak();
+
+        let watch = HeapWatch::start();
+        let start = Instant::now();
+        let mut decompressed = Vec::new();
+        crate::decompress_with(&compressed[..], &mut decompressed, &decompress_options)?;
+        measurement.decompress_secs = measurement.decompress_secs.min(secs_since(start));
+        let decompress_peak = watch.peak();
+
+        if decompressed != data {
+            return Err(HuffmanError::BadBitstream(
+                "decompressed data differs from the input",
+            ));
+        }
+        if run == 0 {
+            let layout = Layout::read(&compressed, decompress_options.clone())?;
+            measurement.compressed_size = compressed.len() as u64;
+            measurement.overhead = layout.overhead();
+            measurement.peak_heap = compress_peak.max(decompress_peak);
+        }
+    }
+    Ok(measurement)
+}
+
+fn secs_since(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64()
+}
+
+/// The files under `dir`, in subdirectories too, sorted by path.
+pub fn corpus(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The measurements of a whole corpus, as saved for later runs to compare
+/// against.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// The settings the files were compressed with, as given to `press`.
+    pub settings: String,
+    pub files: Vec<Measurement>,
+}
+
+impl Report {
+    /// All the files taken together: sizes and times added up, and the
+    /// largest peak heap.
+    pub fn total(&self) -> Measurement {
+        let sum = |f: fn(&Measurement) -> u64| self.files.iter().map(f).sum();
+        Measurement {
+            name: "total".to_string(),
+            original_size: sum(|m| m.original_size),
+            compressed_size: sum(|m| m.compressed_size),
+            overhead: sum(|m| m.overhead),
+            compress_secs: self.files.iter().map(|m| m.compress_secs).sum(),
+            decompress_secs: self.files.iter().map(|m| m.decompress_secs).sum(),
+            peak_heap: self.files.iter().filter_map(|m| m.peak_heap).max(),
+        }
+    }
+
+    /// The file of the same name in `baseline`, if it has one.
+    fn find<'a>(baseline: Option<&'a Report>, name: &str) -> Option<&'a Measurement> {
+        baseline?.files.iter().find(|m| m.name == name)
+    }
+
+    /// Write the measurements as CSV, with the change from `baseline` of
+    /// each file in it if one is given.
+    pub fn write_csv<W: Write>(
+        &self,
+        mut out: W,
+        baseline: Option<&Report>,
+    ) -> std::io::Result<()> {
+        write!(
+            out,
+            "file,original_size,compressed_size,ratio,overhead,compress_mb_s,decompress_mb_s,peak_heap"
+        )?;
+        if baseline.is_some() {
+            write!(
+                out,
+                ",size_change,compress_speed_change,decompress_speed_change"
+            )?;
+        }
+        writeln!(out)?;
+
+        for m in &self.files {
+            write!(
+                out,
+                "{},{},{},{:.4},{},{:.2},{:.2},{}",
+                csv_field(&m.name),
+                m.original_size,
+                m.compressed_size,
+                m.ratio(),
+                m.overhead,
+                m.compress_speed(),
+                m.decompress_speed(),
+                m.peak_heap.map_or(String::new(), |peak| peak.to_string())
+            )?;
+            if baseline.is_some() {
+                let changes = Changes::of(m, Report::find(baseline, &m.name));
+                write!(
+                    out,
+                    ",{},{},{}",
+                    changes.size, changes.compress, changes.decompress
+                )?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Write the measurements as a table for people to read, followed by
+    /// the total, with the change from `baseline` if one is given.
+    pub fn write_table<W: Write>(
+        &self,
+        mut out: W,
+        baseline: Option<&Report>,
+    ) -> std::io::Result<()> {
+        let width = self
+            .files
+            .iter()
+            .map(|m| m.name.len())
+            .max()
+            .unwrap_or(0)
+            .max(5);
+        write!(
+            out,
+            "{:<width$}  {:>10}  {:>10}  {:>6}  {:>8}  {:>9}  {:>9}  {:>10}",
+            "file",
+            "original",
+            "compressed",
+            "ratio",
+            "overhead",
+            "comp MB/s",
+            "dec MB/s",
+            "peak heap",
+        )?;
+        if baseline.is_some() {
+            write!(out, "  {:>8}  {:>9}  {:>9}", "size", "comp", "dec")?;
+        }
+        writeln!(out)?;
+
+        let total = self.total();
+        for m in self.files.iter().chain([&total]) {
+            write!(
+                out,
+                "{:<width$}  {:>10}  {:>10}  {:>6.3}  {:>8}  {:>9.2}  {:>9.2}  {:>10}",
+                m.name,
+                m.original_size,
+                m.compressed_size,
+                m.ratio(),
+                m.overhead,
+                m.compress_speed(),
+                m.decompress_speed(),
+                m.peak_heap.map_or("-".to_string(), |peak| format!(
+                    "{:.1} MiB",
+                    peak as f64 / (1 << 20) as f64
+                )),
+            )?;
+            if baseline.is_some() {
+                let before = match m.name.as_str() {
+                    "total" => baseline.map(Report::total),
+                    name => Report::find(baseline, name).cloned(),
+                };
+                let changes = Changes::of(m, before.as_ref());
+                write!(
+                    out,
+                    "  {:>8}  {:>9}  {:>9}",
+                    changes.size, changes.compress, changes.decompress
+                )?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// How a measurement changed from a baseline, as signed percentages, or
+/// blank without one to compare with.
+struct Changes {
+    size: Change,
+    compress: Change,
+    decompress: Change,
+}
+
+impl Changes {
+    fn of(now: &Measurement, before: Option<&Measurement>) -> Changes {
+        let change = |now: f64, before: Option<f64>| Change(before.map(|b| (now, b)));
+        Changes {
+            size: change(
+                now.compressed_size as f64,
+                before.map(|b| b.compressed_size as f64),
+            ),
+            compress: change(
+                now.compress_speed(),
+                before.map(Measurement::compress_speed),
+            ),
+            decompress: change(
+                now.decompress_speed(),
+                before.map(Measurement::decompress_speed),
+            ),
+        }
+    }
+}
+
+/// A value now and before.
+struct Change(Option<(f64, f64)>);
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self.0 {
+            Some((now, before)) if before > 0.0 => {
+                format!("{:+.1}%", 100.0 * (now - before) / before)
+            }
+            _ => "-".to_string(),
+        };
+        f.pad(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::Method;
+
+    fn measured(method: Method) -> Measurement {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let options = CompressOptions {
+            method,
+            ..CompressOptions::default()
+        };
+        measure("test-10kB.txt", &text, &options, 2).unwrap()
+    }
+
+    #[test]
+    fn measures_sizes_and_overhead() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed = Vec::new();
+        crate::compress(&text[..], &mut compressed).unwrap();
+
+        let m = measured(Method::Huffman);
+        assert_eq!(m.original_size, text.len() as u64);
+        assert_eq!(m.compressed_size, compressed.len() as u64);
+        let layout = Layout::read(&compressed, DecompressOptions::default()).unwrap();
+        assert_eq!(m.overhead, layout.overhead());
+        assert!(m.compress_secs.is_finite() && m.decompress_secs.is_finite());
+        // Tests run without the tracking allocator.
+        assert_eq!(m.peak_heap, None);
+    }
+
+    #[test]
+    fn report_round_trips_through_json() {
+        let report = Report {
+            settings: "--method lzh".to_string(),
+            files: vec![measured(Method::Lzh)],
+        };
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<Report>(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn compares_with_baseline() {
+        let baseline = Report {
+            settings: String::new(),
+            files: vec![measured(Method::Huffman)],
+        };
+        let report = Report {
+            settings: String::new(),
+            files: vec![measured(Method::Lzh)],
+        };
+        let shrunk = 100.0
+            * (report.files[0].compressed_size as f64 / baseline.files[0].compressed_size as f64
+                - 1.0);
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv, Some(&baseline)).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(",size_change,compress_speed_change,decompress_speed_change"));
+        assert!(lines[1].starts_with("test-10kB.txt,10000,"));
+        assert!(lines[1].contains(&format!(",{:+.1}%,", shrunk)));
+
+        let mut table = Vec::new();
+        report.write_table(&mut table, None).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert_eq!(table.lines().count(), 3);
+        assert!(table.lines().last().unwrap().starts_with("total"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Bit-level reading and writing, packing bits least significant first as
+//! `Code` does.
+
+use crate::huffman::{Code, HuffmanError};
+use bitvec::field::BitField;
+
+use std::io::BufRead;
+
+/// A bitstream that Huffman codes can be decoded from.
+pub(crate) trait BitSource {
+    /// The next `n` bits without consuming them, padded with zeros past the
+    /// end of the stream. `n` is at most 32.
+    fn peek(&mut self, n: usize) -> Result<u32, HuffmanError>;
+
+    /// Number of bits that can be consumed after a `peek`.
+    fn available(&self) -> usize;
+
+    fn consume(&mut self, n: usize);
+
+    fn read_bit(&mut self) -> Result<bool, HuffmanError>;
+}
+
+/// Accumulates bits into bytes.
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    nbits: usize,
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append the low `n` bits of `value`, lowest bit first. `n` is at most 32.
+    pub(crate) fn write_bits(&mut self, value: u32, n: usize) {
+        debug_assert!(n <= 32);
+        self.acc |= (value as u64 & ((1 << n) - 1)) << self.nbits;
+        self.nbits += n;
+        while self.nbits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    pub(crate) fn write_code(&mut self, code: &Code) {
+        for chunk in code.chunks(32) {
+            self.write_bits(chunk.load_le::<u32>(), chunk.len());
+        }
+    }
+
+    /// Pad to a byte boundary with zeros.
+    pub(crate) fn align(&mut self) {
+        if self.nbits > 0 {
+            self.write_bits(0, 8 - self.nbits);
+        }
+    }
+
+    /// Append whole bytes. The writer must be byte aligned.
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.nbits, 0);
+        self.out.extend_from_slice(bytes);
+    }
+
+    /// Take the complete bytes written so far, keeping any partial byte.
+    pub(crate) fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    /// Pad the last byte with zeros and return the bytes written.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+/// Reads bits from a byte slice.
+pub(crate) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Number of bits left to read.
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    /// The next `n` bits without consuming them, padded with zeros past the
+    /// end of the data. `n` is at most 32.
+    pub(crate) fn peek(&self, n: usize) -> u32 {
+        debug_assert!(n <= 32);
+        let byte = self.pos / 8;
+        let mut acc: u64 = 0;
+        for (i, b) in self.data.iter().skip(byte).take(5).enumerate() {
+            acc |= (*b as u64) << (8 * i);
+        }
+        ((acc >> (self.pos % 8)) & ((1 << n) - 1)) as u32
+    }
+
+    pub(crate) fn consume(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    pub(crate) fn read_bits(&mut self, n: usize) -> Result<u32, HuffmanError> {
+        if n > self.remaining() {
+            return Err(HuffmanError::BadBitstream("ended before EOT"));
+        }
+        let value = BitReader::peek(self, n);
+        self.consume(n);
+        Ok(value)
+    }
+
+    pub(crate) fn read_bit(&mut self) -> Result<bool, HuffmanError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+}
+
+impl BitSource for BitReader<'_> {
+    fn peek(&mut self, n: usize) -> Result<u32, HuffmanError> {
+        Ok(BitReader::peek(self, n))
+    }
+
+    fn available(&self) -> usize {
+        self.remaining()
+    }
+
+    fn consume(&mut self, n: usize) {
+        BitReader::consume(self, n)
+    }
+
+    fn read_bit(&mut self) -> Result<bool, HuffmanError> {
+        BitReader::read_bit(self)
+    }
+}
+
+/// Reads bits from a `BufRead` a byte at a time, only taking bytes from the
+/// reader as their bits are needed. After `align`, `read_bytes` picks up
+/// exactly where the bitstream left off.
+pub(crate) struct StreamBitReader<R: BufRead> {
+    input: R,
+    acc: u64,
+    nbits: usize,
+}
+
+impl<R: BufRead> StreamBitReader<R> {
+    pub(crate) fn new(input: R) -> Self {
+        StreamBitReader {
+            input,
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Load bytes until at least `n` bits are held or the input ends.
+    fn fill(&mut self, n: usize) -> Result<(), HuffmanError> {
+        while self.nbits < n {
+            let byte = match self.input.fill_buf()?.first() {
+                Some(byte) => *byte,
+                None => break,
+            };
+            self.input.consume(1);
+            self.acc |= (byte as u64) << self.nbits;
+            self.nbits += 8;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_bits(&mut self, n: usize) -> Result<u32, HuffmanError> {
+        let value = BitSource::peek(self, n)?;
+        if n > self.nbits {
+            return Err(HuffmanError::Truncated);
+        }
+        self.consume(n);
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary.
+    pub(crate) fn align(&mut self) {
+        let n = self.nbits % 8;
+        self.consume(n);
+    }
+
+    /// Read whole bytes. The reader must be byte aligned.
+    pub(crate) fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), HuffmanError> {
+        debug_assert_eq!(self.nbits % 8, 0);
+        let held = (self.nbits / 8).min(buf.len());
+        for b in buf[..held].iter_mut() {
+            *b = self.acc as u8;
+            self.consume(8);
+        }
+        self.input.read_exact(&mut buf[held..])?;
+        Ok(())
+    }
+
+    /// Whether the input has been read to its end. The reader must be byte
+    /// aligned.
+    pub(crate) fn at_end(&mut self) -> Result<bool, HuffmanError> {
+        Ok(self.nbits == 0 && self.input.fill_buf()?.is_empty())
+    }
+}
+
+impl<R: BufRead> BitSource for StreamBitReader<R> {
+    fn peek(&mut self, n: usize) -> Result<u32, HuffmanError> {
+        debug_assert!(n <= 32);
+        self.fill(n)?;
+        Ok((self.acc & ((1 << n) - 1)) as u32)
+    }
+
+    fn available(&self) -> usize {
+        self.nbits
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.acc >>= n;
+        self.nbits -= n;
+    }
+
+    fn read_bit(&mut self) -> Result<bool, HuffmanError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+}
@@ -0,0 +1,1771 @@
+//! The press file format, and the `Encoder` and `Decoder` adapters that
+//! stream data into and out of it.
+//!
+//! A file is laid out as:
+//!
+//! ```text
+//! MAGIC | version: u8 | flags: u8 | method: u8 [| dictionary_id: u32] [| transforms: u32] [| encryption]
+//! { original_len: u32, payload_len: u32, payload: [u8; payload_len] }*
+//! 0: u32 | original_size: u64 | crc32: u32
+//! [{ offset: u64, original_offset: u64, crc32: u32 }* | blocks: u32]
+//! ```
+//!
+//! With the Huffman and range methods each payload is
+//! `{ table_len: u32, table: [u8; table_len], stream }`, the table being the
+//! model saved by the `EntropyCoder` and the stream ending on its own, with
+//! an EOT symbol. The `lzh` and `adaptive` payloads are described in the
+//! `lz77` and `adaptive` modules. The size and checksum of the whole input go in the footer as
+//! they are only known once the input has been read.
+//!
+//! Version 1 files have no method byte and always use the Huffman method.
+//! Unversioned files, written by press before this format, are `MAGIC`
+//! followed directly by Huffman payloads up to the end of the file. Their
+//! tables hold the codes themselves rather than their lengths, as read by
+//! `HuffmanEncoding::restore_legacy_from`.
+//!
+//! The `FLAG_DICTIONARY` flag says the file was compressed with a
+//! `Dictionary`, whose ID follows the method. Huffman payloads with an empty
+//! table are then coded with the dictionary's table.
+//!
+//! The `FLAG_TRANSFORMS` flag says each block went through the transforms
+//! packed in the header, as described in the `transform` module, before it
+//! was coded. Payloads code the transformed data, while `original_len` and
+//! the footer and index describe the data before it was transformed.
+//!
+//! The `FLAG_INDEX` flag says the footer is followed by an index of the
+//! blocks, giving for each the offset of its `original_len` in the file, the
+//! offset of its data in the decompressed data, and the checksum of its
+//! data. The number of blocks comes last, so the index can be found from the
+//! end of the file and any block decoded without decoding the ones before.
+//!
+//! The `FLAG_ENCRYPTED` flag says everything after the header is encrypted,
+//! with the parameters that end the header, as described in the `crypto`
+//! module. Encrypted files have no index, as their blocks can only be
+//! authenticated in order.
+
+use crate::adaptive::{self, AdaptiveHuffman};
+use crate::crypto::{self, Decryptor, Encryptor};
+use crate::dictionary::Dictionary;
+use crate::entropy::EntropyCoder;
+use crate::huffman::{HuffmanEncoding, HuffmanError};
+use crate::range::RangeEncoding;
+use crate::transform::Transforms;
+use crate::workers::Workers;
+use crate::{lz77, CompressOptions, DecompressOptions, Method};
+
+use log::debug;
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+pub(crate) const MAGIC: [u8; 5] = [b'P', b'R', b'E', b'S', b'S'];
+
+/// Current container format version, written right after `MAGIC`.
+pub(crate) const VERSION: u8 = 2;
+
+/// Header flag of files compressed with a dictionary.
+const FLAG_DICTIONARY: u8 = 0x01;
+
+/// Header flag of files with a block index after the footer.
+const FLAG_INDEX: u8 = 0x02;
+
+/// Header flag of files whose blocks were transformed before coding.
+const FLAG_TRANSFORMS: u8 = 0x04;
+
+/// Header flag of files encrypted after the header.
+const FLAG_ENCRYPTED: u8 = 0x08;
+
+const FOOTER_LEN: u64 = 4 + 8 + 4;
+
+const INDEX_ENTRY_LEN: u64 = 8 + 8 + 4;
+
+/// Where a block is, in the file and in the decompressed data.
+pub(crate) struct IndexEntry {
+    pub(crate) offset: u64,
+    pub(crate) original_offset: u64,
+    pub(crate) crc: u32,
+}
+
+/// Compresses everything written to it into a press file written to an
+/// inner writer.
+///
+/// Input is buffered and encoded one block at a time, or with
+/// `CompressOptions::threads` above one, as many blocks at a time as there
+/// are threads. Call `finish` once all data has been written to write the
+/// footer and get the inner writer back; dropping the encoder finishes it
+/// too, but ignores any error.
+pub struct Encoder<W: Write> {
+    output: Option<W>,
+    options: CompressOptions,
+    block: Vec<u8>,
+    /// Full blocks waiting to be encoded together.
+    pending: Vec<Vec<u8>>,
+    /// Model of the adaptive method, carried from block to block.
+    model: Option<AdaptiveHuffman>,
+    dictionary: Option<Arc<Dictionary>>,
+    /// Threads encoding the blocks, once there are blocks to share out.
+    workers: Option<Workers>,
+    hasher: crc32fast::Hasher,
+    original_size: u64,
+    header_written: bool,
+    /// Bytes written to the output so far.
+    written: u64,
+    /// Blocks written so far, if the file gets an index.
+    index: Option<Vec<IndexEntry>>,
+    /// Encrypts what follows the header, once the header has been written.
+    encryptor: Option<Encryptor>,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(output: W) -> Self {
+        Encoder::with_options(output, CompressOptions::default())
+    }
+
+    pub fn with_options(output: W, options: CompressOptions) -> Self {
+        Encoder {
+            output: Some(output),
+            options,
+            block: Vec::with_capacity(options.block_size),
+            pending: Vec::new(),
+            model: (options.method == Method::Adaptive).then(AdaptiveHuffman::new),
+            dictionary: None,
+            workers: None,
+            hasher: crc32fast::Hasher::new(),
+            original_size: 0,
+            header_written: false,
+            written: 0,
+            // Blocks of the adaptive method, and encrypted blocks, can only be
+            // decoded in order.
+            index: (options.index
+                && options.method != Method::Adaptive
+                && options.encryption.is_none())
+            .then(Vec::new),
+            encryptor: None,
+        }
+    }
+
+    /// Code blocks with the table of `dictionary` wherever that is smaller
+    /// than storing a table of their own. Only the Huffman method uses a
+    /// dictionary; other methods ignore it.
+    pub fn with_dictionary(output: W, options: CompressOptions, dictionary: Dictionary) -> Self {
+        let mut encoder = Encoder::with_options(output, options);
+        if options.method == Method::Huffman {
+            encoder.dictionary = Some(Arc::new(dictionary));
+        }
+        encoder
+    }
+
+    pub fn finish(mut self) -> Result<W, HuffmanError> {
+        let result = self.try_finish();
+        let output = self.output.take().unwrap();
+        result?;
+        Ok(output)
+    }
+
+    fn output(&mut self) -> &mut W {
+        self.output.as_mut().expect("Encoder used after finish")
+    }
+
+    fn write_out(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let output = self.output.as_mut().expect("Encoder used after finish");
+        match self.encryptor.as_mut() {
+            Some(encryptor) => encryptor.write(output, buf)?,
+            None => output.write_all(buf)?,
+        }
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Blocks encoded at a time. The adaptive method codes each block with
+    /// the model left by the one before, so it only uses one.
+    fn threads(&self) -> usize {
+        match self.options.method {
+            Method::Adaptive => 1,
+            _ => self.options.threads.max(1),
+        }
+    }
+
+    /// Write the header if needed, then queue the buffered block if there is
+    /// one, writing the queue out once there is a block for every thread.
+    fn queue_block(&mut self) -> std::io::Result<()> {
+        if !self.header_written {
+            let method = self.options.method as u8;
+            let id = self.dictionary.as_deref().map(Dictionary::id);
+            let mut flags = if id.is_some() { FLAG_DICTIONARY } else { 0 };
+            if self.index.is_some() {
+                flags |= FLAG_INDEX;
+            }
+            let transforms = self.options.transforms;
+            if !transforms.is_empty() {
+                flags |= FLAG_TRANSFORMS;
+            }
+            if self.options.encryption.is_some() {
+                flags |= FLAG_ENCRYPTED;
+            }
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&[VERSION, flags, method]);
+            if let Some(id) = id {
+                header.extend(u32_to_u8s(id));
+            }
+            if !transforms.is_empty() {
+                header.extend(u32_to_u8s(transforms.to_u32()));
+            }
+            let encryptor = self
+                .options
+                .encryption
+                .map(|key| key.encryptor(&mut header));
+            self.write_out(&header)?;
+            self.encryptor = encryptor;
+            debug!(
+                "writing {} blocks of up to {} bytes, flags {:#04x}",
+                self.options.method, self.options.block_size, flags
+            );
+            self.header_written = true;
+        }
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        self.hasher.update(&self.block);
+        self.original_size += self.block.len() as u64;
+
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(self.options.block_size));
+        self.pending.push(block);
+        if self.pending.len() >= self.threads() {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Encode the queued blocks, each on a thread of the pool, and write
+    /// them in order.
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let transforms = self.options.transforms;
+        let encoded: Vec<(Vec<u8>, Vec<u8>)> = match self.model.as_mut() {
+            Some(model) => pending
+                .into_iter()
+                .map(|block| {
+                    let payload = adaptive::encode_block(model, &transforms.apply(&block));
+                    Ok((block, payload))
+                })
+                .collect::<Result<_, HuffmanError>>()?,
+            None => {
+                let (options, dictionary) = (self.options, self.dictionary.clone());
+                let threads = self.threads();
+                map_parallel(&mut self.workers, threads, pending, move |block| {
+                    let payload =
+                        encode_payload(&transforms.apply(&block), &options, dictionary.as_deref())?;
+                    Ok((block, payload))
+                })
+                .into_iter()
+                .collect::<Result<_, HuffmanError>>()?
+            }
+        };
+        debug!(
+            "encoded {} bytes in {} blocks to {} bytes in {:.2?}",
+            encoded.iter().map(|(block, _)| block.len()).sum::<usize>(),
+            encoded.len(),
+            encoded
+                .iter()
+                .map(|(_, payload)| payload.len())
+                .sum::<usize>(),
+            start.elapsed()
+        );
+
+        let start = Instant::now();
+        let blocks = encoded.len();
+        let mut original_offset = self.original_size
+            - encoded
+                .iter()
+                .map(|(block, _)| block.len() as u64)
+                .sum::<u64>();
+        for (block, payload) in encoded {
+            if let Some(index) = self.index.as_mut() {
+                index.push(IndexEntry {
+                    offset: self.written,
+                    original_offset,
+                    crc: crc32fast::hash(&block),
+                });
+            }
+            original_offset += block.len() as u64;
+
+            self.write_out(&u32_to_u8s(block.len() as u32))?;
+            self.write_out(&u32_to_u8s(payload.len() as u32))?;
+            self.write_out(&payload)?;
+        }
+        debug!("wrote {} blocks in {:.2?}", blocks, start.elapsed());
+        Ok(())
+    }
+
+    fn try_finish(&mut self) -> std::io::Result<()> {
+        self.queue_block()?;
+        self.write_pending()?;
+
+        let original_size = self.original_size;
+        let crc = self.hasher.clone().finalize();
+        self.write_out(&u32_to_u8s(0))?;
+        self.write_out(&original_size.to_be_bytes())?;
+        self.write_out(&u32_to_u8s(crc))?;
+
+        if let Some(index) = self.index.take() {
+            for entry in &index {
+                self.write_out(&entry.offset.to_be_bytes())?;
+                self.write_out(&entry.original_offset.to_be_bytes())?;
+                self.write_out(&u32_to_u8s(entry.crc))?;
+            }
+            self.write_out(&u32_to_u8s(index.len() as u32))?;
+        }
+        if let Some(encryptor) = self.encryptor.take() {
+            encryptor.finish(self.output())?;
+        }
+        debug!(
+            "compressed {} bytes to {}, checksum {:08x}",
+            original_size, self.written, crc
+        );
+        self.output().flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let room = self.options.block_size - self.block.len();
+        let n = room.min(buf.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.options.block_size {
+            self.queue_block()?;
+        }
+        Ok(n)
+    }
+
+    /// Writes out the buffered data as a block of its own, so frequent
+    /// flushes cost compression ratio. When encrypting, data short of a
+    /// whole chunk stays buffered until `finish`.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.queue_block()?;
+        self.write_pending()?;
+        self.output().flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        if self.output.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+enum DecoderState {
+    Header,
+    Unversioned,
+    Blocks,
+    /// The end of the blocks has been read, but blocks decoded before it
+    /// may still be waiting to be returned.
+    Footer,
+    Done,
+}
+
+/// Decompresses a press file read from an inner reader.
+///
+/// Blocks are decoded one at a time as they are read, or with
+/// `DecompressOptions::threads` above one, as many blocks at a time as there
+/// are threads. The size and checksum in the footer are checked when the end
+/// of the file is reached, and a mismatch is reported as an error from the
+/// final read.
+pub struct Decoder<R: BufRead> {
+    input: Source<R>,
+    state: DecoderState,
+    method: Method,
+    model: Option<AdaptiveHuffman>,
+    /// Dictionary for the empty tables of Huffman payloads, once the header
+    /// has said the file uses it.
+    dictionary: Option<Arc<Dictionary>>,
+    threads: usize,
+    /// Threads decoding the blocks, once there are blocks to share out.
+    workers: Option<Workers>,
+    /// Whether the footer is followed by a block index.
+    indexed: bool,
+    /// Transforms to undo on every block, once the header has been read.
+    transforms: Transforms,
+    /// Passphrase to decrypt the file with, if the header says it is
+    /// encrypted.
+    passphrase: Option<Vec<u8>>,
+    /// Blocks read so far.
+    blocks: u32,
+    /// Blocks decoded ahead of the one being read.
+    decoded: VecDeque<Result<Vec<u8>, HuffmanError>>,
+    block: Vec<u8>,
+    pos: usize,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(input: R) -> Self {
+        Decoder::with_options(input, DecompressOptions::default())
+    }
+
+    pub fn with_options(input: R, options: DecompressOptions) -> Self {
+        Decoder {
+            input: Source {
+                inner: input,
+                decryptor: None,
+            },
+            state: DecoderState::Header,
+            method: Method::Huffman,
+            model: None,
+            dictionary: options.dictionary.map(Arc::new),
+            threads: options.threads.max(1),
+            workers: None,
+            indexed: false,
+            transforms: Transforms::default(),
+            passphrase: options.passphrase,
+            blocks: 0,
+            decoded: VecDeque::new(),
+            block: Vec::new(),
+            pos: 0,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input.inner
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.input.inner
+    }
+
+    /// Method of the file, once the header has been read.
+    pub(crate) fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Whether the header said the file predates versioning, in which case
+    /// its blocks have no length prefix.
+    pub(crate) fn is_unversioned(&self) -> bool {
+        matches!(self.state, DecoderState::Unversioned)
+    }
+
+    /// The dictionary in use, once the header has said the file uses one.
+    pub(crate) fn dictionary(&self) -> Option<&Dictionary> {
+        self.dictionary.as_deref()
+    }
+
+    /// Transforms the blocks went through, once the header has been read.
+    pub(crate) fn transforms(&self) -> Transforms {
+        self.transforms
+    }
+
+    /// Whether the header says the file is encrypted, once it has been read.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.input.decryptor.is_some()
+    }
+
+    /// Decoded data of the block read by the last call to `next_block`.
+    pub(crate) fn block(&self) -> &[u8] {
+        &self.block
+    }
+
+    /// Decode the next block, returning `false` once the end of the file has
+    /// been reached and verified.
+    pub(crate) fn next_block(&mut self) -> Result<bool, HuffmanError> {
+        self.block.clear();
+        self.pos = 0;
+
+        loop {
+            if let Some(block) = self.decoded.pop_front() {
+                self.block = block?;
+                self.hasher.update(&self.block);
+                self.len += self.block.len() as u64;
+                return Ok(true);
+            }
+            match self.state {
+                DecoderState::Header => self.read_header()?,
+                DecoderState::Unversioned => {
+                    if self.input.fill_buf()?.is_empty() {
+                        self.state = DecoderState::Done;
+                        return Ok(false);
+                    }
+                    decode_legacy_block(&mut self.input, &mut self.block)?;
+                    return Ok(true);
+                }
+                DecoderState::Blocks => self.read_blocks(),
+                DecoderState::Footer => {
+                    self.read_footer()?;
+                    self.state = DecoderState::Done;
+                    return Ok(false);
+                }
+                DecoderState::Done => return Ok(false),
+            }
+        }
+    }
+
+    /// Read as many blocks as there are threads, or up to the end of the
+    /// blocks, and decode them together. A failure to read is queued after
+    /// the blocks read before it.
+    fn read_blocks(&mut self) {
+        let start = Instant::now();
+        let mut payloads = Vec::new();
+        let mut error = None;
+        while payloads.len() < self.threads {
+            match self.read_block_payload() {
+                Ok(Some(payload)) => payloads.push(payload),
+                Ok(None) => {
+                    self.state = DecoderState::Footer;
+                    break;
+                }
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let (read, blocks) = (
+            payloads.iter().map(|(_, raw)| raw.len()).sum::<usize>(),
+            payloads.len(),
+        );
+        let transforms = self.transforms;
+        let decoded: Vec<_> = match self.model.as_mut() {
+            Some(model) => payloads
+                .into_iter()
+                .map(|(len, raw)| {
+                    let mut out = Vec::new();
+                    let limit = transforms.max_output_len(len as usize);
+                    adaptive::decode_block(model, &raw, &mut out, limit)?;
+                    untransform(&transforms, out, len)
+                })
+                .collect(),
+            None => {
+                let (method, dictionary) = (self.method, self.dictionary.clone());
+                map_parallel(
+                    &mut self.workers,
+                    self.threads,
+                    payloads,
+                    move |(len, raw)| {
+                        let limit = transforms.max_output_len(len as usize);
+                        let block = decode_payload(method, dictionary.as_deref(), &raw, limit)?;
+                        untransform(&transforms, block, len)
+                    },
+                )
+            }
+        };
+        if blocks > 0 {
+            debug!(
+                "decoded {} bytes in {} blocks to {} bytes in {:.2?}",
+                read,
+                blocks,
+                decoded
+                    .iter()
+                    .map(|block| block.as_ref().map_or(0, Vec::len))
+                    .sum::<usize>(),
+                start.elapsed()
+            );
+        }
+        self.decoded.extend(decoded);
+        self.decoded.extend(error.map(Err));
+    }
+
+    /// Read the original length and payload of the next block, or `None` at
+    /// the end of the blocks.
+    pub(crate) fn read_block_payload(&mut self) -> Result<Option<(u64, Vec<u8>)>, HuffmanError> {
+        let original_len = read_u32(&mut self.input)? as u64;
+        if original_len == 0 {
+            return Ok(None);
+        }
+        let payload_len = read_u32(&mut self.input)?;
+        let raw = read_payload(&mut self.input.by_ref().take(payload_len as u64))?;
+        self.blocks += 1;
+        Ok(Some((original_len, raw)))
+    }
+
+    pub(crate) fn read_header(&mut self) -> Result<(), HuffmanError> {
+        let mut magic = [0u8; MAGIC.len()];
+        self.input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(HuffmanError::BadMagic);
+        }
+
+        // Unversioned files start with the table length of their first
+        // block, whose high byte is always zero.
+        let version = *self
+            .input
+            .fill_buf()?
+            .first()
+            .ok_or(HuffmanError::Truncated)?;
+        match version {
+            0 => {
+                self.state = DecoderState::Unversioned;
+                Ok(())
+            }
+            1 | VERSION => {
+                // The header as read, which encrypted data is bound to.
+                let mut raw = MAGIC.to_vec();
+                let mut header = [0u8; 2];
+                self.input.read_exact(&mut header)?;
+                raw.extend_from_slice(&header);
+                let flags = header[1];
+                let known = FLAG_DICTIONARY | FLAG_INDEX | FLAG_TRANSFORMS | FLAG_ENCRYPTED;
+                if flags & !known != 0 || (version < 2 && flags != 0) {
+                    return Err(HuffmanError::UnsupportedFlags(flags));
+                }
+                if version >= 2 {
+                    let method = read_u8(&mut self.input)?;
+                    raw.push(method);
+                    self.method =
+                        Method::from_u8(method).ok_or(HuffmanError::UnsupportedMethod(method))?;
+                    self.model = (self.method == Method::Adaptive).then(AdaptiveHuffman::new);
+                }
+                if flags & FLAG_DICTIONARY != 0 {
+                    let expected = read_u32(&mut self.input)?;
+                    raw.extend(u32_to_u8s(expected));
+                    let actual = self.dictionary.as_deref().map(Dictionary::id);
+                    if actual != Some(expected) {
+                        return Err(HuffmanError::WrongDictionary { expected, actual });
+                    }
+                } else {
+                    self.dictionary = None;
+                }
+                if flags & FLAG_TRANSFORMS != 0 {
+                    let transforms = read_u32(&mut self.input)?;
+                    raw.extend(u32_to_u8s(transforms));
+                    self.transforms = Transforms::from_u32(transforms)?;
+                }
+                if flags & FLAG_ENCRYPTED != 0 {
+                    let passphrase = self.passphrase.as_deref();
+                    let decryptor = crypto::decryptor(&mut self.input.inner, &raw, passphrase)?;
+                    self.input.decryptor = Some(decryptor);
+                }
+                self.indexed = flags & FLAG_INDEX != 0;
+                self.state = DecoderState::Blocks;
+                debug!(
+                    "reading version {} {} blocks, flags {:#04x}",
+                    version, self.method, flags
+                );
+                Ok(())
+            }
+            v => Err(HuffmanError::UnsupportedVersion(v)),
+        }
+    }
+
+    fn read_footer(&mut self) -> Result<(), HuffmanError> {
+        let mut original_size = [0u8; 8];
+        self.input.read_exact(&mut original_size)?;
+        let original_size = u64::from_be_bytes(original_size);
+        let crc = read_u32(&mut self.input)?;
+
+        if self.len != original_size {
+            return Err(HuffmanError::SizeMismatch {
+                expected: original_size,
+                actual: self.len,
+            });
+        }
+        let actual = self.hasher.clone().finalize();
+        if actual != crc {
+            return Err(HuffmanError::ChecksumMismatch {
+                expected: crc,
+                actual,
+            });
+        }
+
+        if self.indexed {
+            // Blocks have already been checked as they were decoded.
+            let len = self.blocks as u64 * INDEX_ENTRY_LEN;
+            let mut entries = self.input.by_ref().take(len);
+            if std::io::copy(&mut entries, &mut std::io::sink())? < len {
+                return Err(HuffmanError::Truncated);
+            }
+            if read_u32(&mut self.input)? != self.blocks {
+                return Err(HuffmanError::BadIndex("wrong number of blocks"));
+            }
+        }
+        if let Some(decryptor) = self.input.decryptor.as_mut() {
+            decryptor.finish(&mut self.input.inner)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the header says the file has a block index. Only known once
+    /// the header has been read.
+    pub(crate) fn is_indexed(&self) -> bool {
+        self.indexed
+    }
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Decode the block at `offset` in the file, after the header has been
+    /// read.
+    pub(crate) fn read_block_at(&mut self, offset: u64) -> Result<Vec<u8>, HuffmanError> {
+        self.input.seek(SeekFrom::Start(offset))?;
+        let (len, raw) = self
+            .read_block_payload()?
+            .ok_or(HuffmanError::BadIndex("offset of the end of the blocks"))?;
+        let limit = self.transforms.max_output_len(len as usize);
+        untransform(
+            &self.transforms,
+            decode_payload(self.method, self.dictionary.as_deref(), &raw, limit)?,
+            len,
+        )
+    }
+}
+
+/// The input of a `Decoder`, which decrypts what follows the header of an
+/// encrypted file.
+struct Source<R> {
+    inner: R,
+    decryptor: Option<Decryptor>,
+}
+
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.fill_buf()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Source<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor.fill_buf(&mut self.inner),
+            None => self.inner.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor.consume(amt),
+            None => self.inner.consume(amt),
+        }
+    }
+}
+
+impl<R: BufRead + Seek> Seek for Source<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        if self.decryptor.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek in encrypted data",
+            ));
+        }
+        self.inner.seek(pos)
+    }
+}
+
+/// Read the block index at the end of a file with `FLAG_INDEX` set, and the
+/// size of the decompressed data from the footer before it.
+pub(crate) fn read_index<R: Read + Seek>(
+    input: &mut R,
+) -> Result<(Vec<IndexEntry>, u64), HuffmanError> {
+    let end = input.seek(SeekFrom::End(-4))?;
+    let blocks = read_u32(input)? as u64;
+    let footer = end
+        .checked_sub(blocks * INDEX_ENTRY_LEN + FOOTER_LEN)
+        .ok_or(HuffmanError::BadIndex("more blocks than fit in the file"))?;
+
+    input.seek(SeekFrom::Start(footer))?;
+    if read_u32(input)? != 0 {
+        return Err(HuffmanError::BadIndex("no footer before the index"));
+    }
+    let mut size = [0u8; 8];
+    input.read_exact(&mut size)?;
+    let size = u64::from_be_bytes(size);
+    read_u32(input)?;
+
+    let mut index: Vec<IndexEntry> = Vec::new();
+    for _ in 0..blocks {
+        let mut offsets = [0u8; 16];
+        input.read_exact(&mut offsets)?;
+        let entry = IndexEntry {
+            offset: u64::from_be_bytes(offsets[..8].try_into().unwrap()),
+            original_offset: u64::from_be_bytes(offsets[8..].try_into().unwrap()),
+            crc: read_u32(input)?,
+        };
+        let in_order = match index.last() {
+            Some(prev) => {
+                entry.offset > prev.offset && entry.original_offset > prev.original_offset
+            }
+            None => entry.original_offset == 0,
+        };
+        if !in_order || entry.offset >= footer || entry.original_offset >= size {
+            return Err(HuffmanError::BadIndex("blocks out of order"));
+        }
+        index.push(entry);
+    }
+    Ok((index, size))
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Unversioned files can hold empty blocks, which are skipped.
+        while self.pos == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decode a payload written by `encode_payload`, failing as soon as it holds
+/// more than `limit` bytes.
+fn decode_payload(
+    method: Method,
+    dictionary: Option<&Dictionary>,
+    payload: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, HuffmanError> {
+    let mut out = Vec::new();
+    let mut rest = payload;
+    match method {
+        Method::Huffman => decode_block(
+            &mut rest,
+            &mut out,
+            dictionary.map(Dictionary::encoding),
+            limit,
+        )?,
+        Method::Range => decode_block::<RangeEncoding, _, _>(&mut rest, &mut out, None, limit)?,
+        Method::Lzh => {
+            lz77::decode_block(payload, &mut out, limit)?;
+            rest = &[];
+        }
+        Method::Stored => {
+            if payload.len() > limit {
+                return Err(HuffmanError::BadBitstream("more data than the block holds"));
+            }
+            out.extend_from_slice(payload);
+            rest = &[];
+        }
+        Method::Adaptive => unreachable!("adaptive blocks depend on the blocks before"),
+    }
+    if !rest.is_empty() {
+        return Err(HuffmanError::BadBitstream("data after EOT"));
+    }
+    Ok(out)
+}
+
+/// Undo the transforms of a decoded block and check it has the length the
+/// file says it should.
+fn untransform(
+    transforms: &Transforms,
+    block: Vec<u8>,
+    expected: u64,
+) -> Result<Vec<u8>, HuffmanError> {
+    if transforms.is_empty() {
+        return check_size(block, expected);
+    }
+    check_size(transforms.invert(block, expected as usize)?, expected)
+}
+
+fn check_size(block: Vec<u8>, expected: u64) -> Result<Vec<u8>, HuffmanError> {
+    if block.len() as u64 != expected {
+        return Err(HuffmanError::SizeMismatch {
+            expected,
+            actual: block.len() as u64,
+        });
+    }
+    Ok(block)
+}
+
+/// Encode a block with any method but the adaptive one, whose model is
+/// carried between blocks.
+fn encode_payload(
+    block: &[u8],
+    options: &CompressOptions,
+    dictionary: Option<&Dictionary>,
+) -> Result<Vec<u8>, HuffmanError> {
+    match options.method {
+        Method::Huffman => match dictionary {
+            Some(dictionary) => encode_with_dictionary(block, options, dictionary.encoding()),
+            None => encode_block::<HuffmanEncoding>(block, options),
+        },
+        Method::Range => encode_block::<RangeEncoding>(block, options),
+        Method::Lzh => lz77::encode_block(block, options.max_code_length, options.max_chain),
+        Method::Stored => Ok(block.to_vec()),
+        Method::Adaptive => unreachable!("adaptive blocks depend on the blocks before"),
+    }
+}
+
+/// Apply `f` to every item, on `workers` if there are several, and return
+/// the results in order. The pool of `threads` threads is started the first
+/// time it is needed and kept for the next batch.
+fn map_parallel<T, U, F>(
+    workers: &mut Option<Workers>,
+    threads: usize,
+    items: Vec<T>,
+    f: F,
+) -> Vec<U>
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: Fn(T) -> U + Send + Sync + 'static,
+{
+    if items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+    workers
+        .get_or_insert_with(|| Workers::new(threads))
+        .map(items, f)
+}
+
+fn encode_block<C: EntropyCoder>(
+    block: &[u8],
+    options: &CompressOptions,
+) -> Result<Vec<u8>, HuffmanError> {
+    Ok(coded_block(&C::for_block(block, options)?, block))
+}
+
+fn coded_block<C: EntropyCoder>(encoding: &C, block: &[u8]) -> Vec<u8> {
+    let mut code_table = encoding.save();
+
+    let mut out = u32_to_u8s(code_table.len() as u32);
+    out.append(&mut code_table);
+    out.append(&mut encoding.encode(block));
+    out
+}
+
+/// Code `block` with the dictionary's table, written as an empty table, if
+/// that takes fewer bits than a table of its own and the codes it gives.
+fn encode_with_dictionary(
+    block: &[u8],
+    options: &CompressOptions,
+    dictionary: &HuffmanEncoding,
+) -> Result<Vec<u8>, HuffmanError> {
+    let own = HuffmanEncoding::for_block(block, options)?;
+    let own_bits = 8 * own.save().len() as u64 + own.encoded_bits(block).unwrap();
+    match dictionary.encoded_bits(block) {
+        Some(bits) if bits <= own_bits => {
+            let mut out = u32_to_u8s(0);
+            out.append(&mut dictionary.encode(block));
+            Ok(out)
+        }
+        _ => Ok(coded_block(&own, block)),
+    }
+}
+
+/// Decode a payload written by `encode_block`, or by
+/// `encode_with_dictionary` if a `dictionary` is given. At most `limit`
+/// bytes are decoded.
+fn decode_block<C: EntropyCoder, R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    dictionary: Option<&C>,
+    limit: usize,
+) -> Result<(), HuffmanError> {
+    let table_len = read_u32(input)?;
+    if let (0, Some(dictionary)) = (table_len, dictionary) {
+        return dictionary.decode_from(input, output, limit);
+    }
+    let mut table_raw: Vec<u8> = Vec::new();
+    input.take(table_len as u64).read_to_end(&mut table_raw)?;
+    if table_raw.len() < table_len as usize {
+        return Err(HuffmanError::Truncated);
+    }
+
+    let encoding = C::restore_from(&table_raw)?;
+    encoding.decode_from(input, output, limit)
+}
+
+/// Decode a payload of an unversioned file, which is laid out as those
+/// written by `encode_block` but with a legacy code table.
+fn decode_legacy_block<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+) -> Result<(), HuffmanError> {
+    let table_len = read_u32(input)?;
+    let table = read_payload(&mut input.by_ref().take(table_len as u64))?;
+    HuffmanEncoding::restore_legacy_from(&table)?.decode_from(input, output)
+}
+
+/// Read the whole of a payload limited with `take`.
+fn read_payload<R: Read>(payload: &mut std::io::Take<R>) -> Result<Vec<u8>, HuffmanError> {
+    let len = payload.limit() as usize;
+    let mut raw = Vec::new();
+    payload.read_to_end(&mut raw)?;
+    if raw.len() < len {
+        return Err(HuffmanError::Truncated);
+    }
+    Ok(raw)
+}
+
+fn read_u8<R: Read>(input: &mut R) -> Result<u8, HuffmanError> {
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32, HuffmanError> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u8s_to_u32(&bytes))
+}
+
+fn u32_to_u8s(i: u32) -> Vec<u8> {
+    // return i.to_be_bytes().to_vec();
+    Vec::from([
+        ((i >> 24) & 0xff) as u8,
+        ((i >> 16) & 0xff) as u8,
+        ((i >> 8) & 0xff) as u8,
+        (i & 0xff) as u8,
+    ])
+}
+
+fn u8s_to_u32(us: &[u8]) -> u32 {
+    // return u32::from_be_bytes(us);
+    let mut out: u32 = 0;
+    out |= (us[0] as u32) << 24;
+    out |= (us[1] as u32) << 16;
+    out |= (us[2] as u32) << 8;
+    out |= us[3] as u32;
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{compress, compress_with, decompress};
+    use std::io::BufReader;
+
+    fn _get_tlen(input: &[u8]) -> u32 {
+        if input[0..MAGIC.len()] != MAGIC {
+            panic!("File is not in press format.");
+        }
+        // version, flags, method, then the first block's original and
+        // payload lengths
+        let start = MAGIC.len() + 3 + 4 + 4;
+        u8s_to_u32(&input[start..start + 4])
+    }
+
+    fn small_blocks(block_size: usize) -> CompressOptions {
+        CompressOptions {
+            block_size,
+            ..CompressOptions::default()
+        }
+    }
+
+    #[test]
+    fn can_encode_decode_u32() {
+        for i in 0..400 {
+            let xs = u32_to_u8s(i);
+            let y = u8s_to_u32(&xs);
+            assert_eq!(i, y);
+        }
+    }
+
+    #[test]
+    fn can_get_encoding_table_length() {
+        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        let encoding_original = HuffmanEncoding::from_data_vec(&in_buf);
+        let saved = encoding_original.save();
+        let expected_length = saved.len() as u32;
+
+        compress(&in_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(_get_tlen(&out_buf), expected_length)
+    }
+
+    #[test]
+    fn can_compress_decompress_multiple_blocks() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress_with(&in_buf[..], &mut compressed_buf, &small_blocks(1000)).unwrap();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn can_decompress_across_small_read_buffers() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-3kB.txt").unwrap();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress_with(&in_buf[..], &mut compressed_buf, &small_blocks(500)).unwrap();
+        let reader = BufReader::with_capacity(3, &compressed_buf[..]);
+        decompress(reader, &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn compressed_output_is_reproducible() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut first: Vec<u8> = Vec::new();
+        let mut second: Vec<u8> = Vec::new();
+
+        compress(&in_buf[..], &mut first).unwrap();
+        compress(&in_buf[..], &mut second).unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    fn compressed_sample() -> Vec<u8> {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-2kB.txt").unwrap();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        compress_with(&in_buf[..], &mut compressed_buf, &small_blocks(500)).unwrap();
+        compressed_buf
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut compressed_buf = compressed_sample();
+        compressed_buf[0] = b'X';
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::BadMagic)));
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut compressed_buf = compressed_sample();
+        compressed_buf[MAGIC.len()] = VERSION + 1;
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::UnsupportedVersion(v)) if v == VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let compressed_buf = compressed_sample();
+
+        for len in [MAGIC.len(), MAGIC.len() + 3, compressed_buf.len() - 1] {
+            let result = decompress(&compressed_buf[..len], &mut Vec::new());
+            assert!(matches!(result, Err(HuffmanError::Truncated)), "{}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut compressed_buf = compressed_sample();
+        let last = compressed_buf.len() - 1;
+        compressed_buf[last] ^= 0x01;
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_size_mismatch() {
+        let mut compressed_buf = compressed_sample();
+        // Low byte of the original size in the footer.
+        let size_end = compressed_buf.len() - 4;
+        compressed_buf[size_end - 1] ^= 0x01;
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::SizeMismatch { .. })));
+    }
+
+    #[test]
+    fn can_decompress_legacy_file() {
+        // Compressed with `press -c` as it was before the container format.
+        let legacy = std::fs::read("fixtures/legacy/test-1kB.txt.press").unwrap();
+        let mut out_buf: Vec<u8> = Vec::new();
+        decompress(&legacy[..], &mut out_buf).unwrap();
+
+        assert_eq!(out_buf, std::fs::read("tests/test-1kB.txt").unwrap());
+    }
+
+    #[test]
+    fn can_decompress_version_1() {
+        let mut compressed_buf = compressed_sample();
+        compressed_buf[MAGIC.len()] = 1;
+        compressed_buf.remove(MAGIC.len() + 2);
+
+        let mut out_buf: Vec<u8> = Vec::new();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(out_buf, std::fs::read("tests/test-2kB.txt").unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_method() {
+        let mut compressed_buf = compressed_sample();
+        compressed_buf[MAGIC.len() + 2] = 0x7F;
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::UnsupportedMethod(0x7F))));
+    }
+
+    #[test]
+    fn can_compress_decompress_lzh() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-100kB.txt").unwrap();
+        let options = CompressOptions {
+            block_size: 30_000,
+            method: Method::Lzh,
+            ..CompressOptions::default()
+        };
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+        assert_eq!(compressed_buf[MAGIC.len() + 2], Method::Lzh as u8);
+        let reader = BufReader::with_capacity(7, &compressed_buf[..]);
+        decompress(reader, &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn can_compress_decompress_adaptive() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-100kB.txt").unwrap();
+        let options = CompressOptions {
+            block_size: 7_000,
+            method: Method::Adaptive,
+            ..CompressOptions::default()
+        };
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+        assert_eq!(compressed_buf[MAGIC.len() + 2], Method::Adaptive as u8);
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn adaptive_blocks_share_their_model() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut encoder = Encoder::with_options(
+            Vec::new(),
+            CompressOptions {
+                method: Method::Adaptive,
+                ..CompressOptions::default()
+            },
+        );
+        for line in in_buf.split_inclusive(|c| *c == b'\n') {
+            encoder.write_all(line).unwrap();
+            encoder.flush().unwrap();
+        }
+        let compressed_buf = encoder.finish().unwrap();
+
+        // Flushing every line costs framing and padding, but no code tables.
+        let lines = in_buf.split_inclusive(|c| *c == b'\n').count();
+        let mut whole: Vec<u8> = Vec::new();
+        compress_with(&in_buf[..], &mut whole, &CompressOptions::default()).unwrap();
+        assert!(compressed_buf.len() < whole.len() + 10 * lines);
+
+        let mut out_buf: Vec<u8> = Vec::new();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn every_method_round_trips_test_files() {
+        let methods = [
+            (Method::Huffman, 0),
+            (Method::Lzh, 0),
+            (Method::Adaptive, 0),
+            (Method::Range, 0),
+            (Method::Range, 1),
+        ];
+        for entry in std::fs::read_dir("tests").unwrap() {
+            let path = entry.unwrap().path();
+            let in_buf = std::fs::read(&path).unwrap();
+            // The large files take minutes in debug builds; the methods bench
+            // checks their round trips.
+            if in_buf.len() > 200_000 {
+                continue;
+            }
+            for (method, context_order) in methods {
+                let options = CompressOptions {
+                    method,
+                    context_order,
+                    ..CompressOptions::default()
+                };
+                let mut compressed_buf: Vec<u8> = Vec::new();
+                let mut out_buf: Vec<u8> = Vec::new();
+
+                compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+                decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+                assert!(
+                    in_buf == out_buf,
+                    "{:?} order {} round trip of {}",
+                    method,
+                    context_order,
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_blocks_longer_than_their_original_length() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-1kB.txt").unwrap();
+        let methods = [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ];
+        for method in methods {
+            let options = CompressOptions {
+                method,
+                ..CompressOptions::default()
+            };
+            let mut compressed_buf: Vec<u8> = Vec::new();
+            compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+            // Original length of the first block.
+            let start = MAGIC.len() + 3;
+            compressed_buf[start..start + 4].copy_from_slice(&u32_to_u8s(100));
+
+            let result = decompress(&compressed_buf[..], &mut Vec::new());
+            assert!(
+                matches!(result, Err(HuffmanError::BadBitstream(_))),
+                "{:?}: {:?}",
+                method,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_lzh_file() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-2kB.txt").unwrap();
+        let options = CompressOptions {
+            method: Method::Lzh,
+            ..CompressOptions::default()
+        };
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+        compressed_buf.truncate(compressed_buf.len() / 2);
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+    }
+
+    #[test]
+    fn encoder_output_matches_compress() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-3kB.txt").unwrap();
+        let mut expected: Vec<u8> = Vec::new();
+        compress_with(&in_buf[..], &mut expected, &small_blocks(1000)).unwrap();
+
+        let mut encoder = Encoder::with_options(Vec::new(), small_blocks(1000));
+        for chunk in in_buf.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(encoder.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn dropping_encoder_finishes_file() {
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut compressed_buf);
+            encoder.write_all(b"Hello World").unwrap();
+        }
+
+        let mut out_buf: Vec<u8> = Vec::new();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+        assert_eq!(out_buf, b"Hello World");
+    }
+
+    #[test]
+    fn decoder_reads_in_small_chunks() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-2kB.txt").unwrap();
+        let compressed_buf = compressed_sample();
+        let mut decoder = Decoder::new(&compressed_buf[..]);
+
+        let mut out_buf: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 5];
+        loop {
+            let n = decoder.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            out_buf.extend_from_slice(&chunk[..n]);
+        }
+
+        assert_eq!(out_buf, in_buf);
+    }
+
+    #[test]
+    fn decoder_read_error_converts_back() {
+        let mut compressed_buf = compressed_sample();
+        let last = compressed_buf.len() - 1;
+        compressed_buf[last] ^= 0x01;
+
+        let e = Decoder::new(&compressed_buf[..])
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            HuffmanError::from(e),
+            HuffmanError::ChecksumMismatch { .. }
+        ));
+    }
+
+    fn with_dictionary(data: &[u8], dictionary: &Dictionary) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        crate::compress_with_dictionary(
+            data,
+            &mut compressed,
+            &CompressOptions::default(),
+            dictionary,
+        )
+        .unwrap();
+        compressed
+    }
+
+    fn dictionary_options(dictionary: &Dictionary) -> DecompressOptions {
+        DecompressOptions {
+            dictionary: Some(dictionary.clone()),
+            ..DecompressOptions::default()
+        }
+    }
+
+    #[test]
+    fn dictionary_saves_table_of_small_file() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let dictionary = Dictionary::train(text.chunks(1000).skip(1), 15).unwrap();
+        let message = &text[..1000];
+
+        let mut plain = Vec::new();
+        compress(message, &mut plain).unwrap();
+        let compressed = with_dictionary(message, &dictionary);
+        assert!(compressed.len() + 50 < plain.len());
+
+        let mut out = Vec::new();
+        crate::decompress_with(&compressed[..], &mut out, &dictionary_options(&dictionary))
+            .unwrap();
+        assert_eq!(out, message);
+    }
+
+    #[test]
+    fn dictionary_falls_back_to_own_table() {
+        let dictionary = Dictionary::train([&b"the quick brown fox"[..]], 15).unwrap();
+        let data = vec![0xAB; 10_000];
+
+        let compressed = with_dictionary(&data, &dictionary);
+        let table_len_at = MAGIC.len() + 3 + 4 + 4 + 4;
+        assert_ne!(u8s_to_u32(&compressed[table_len_at..]), 0);
+
+        let mut out = Vec::new();
+        crate::decompress_with(&compressed[..], &mut out, &dictionary_options(&dictionary))
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_dictionary() {
+        let dictionary = Dictionary::train([&b"some sample"[..]], 15).unwrap();
+        let other = Dictionary::train([&b"another sample"[..]], 15).unwrap();
+        let compressed = with_dictionary(b"sample", &dictionary);
+
+        let result = decompress(&compressed[..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(HuffmanError::WrongDictionary { actual: None, .. })
+        ));
+        let result = crate::decompress_with(
+            &compressed[..],
+            &mut Vec::new(),
+            &dictionary_options(&other),
+        );
+        assert!(matches!(
+            result,
+            Err(HuffmanError::WrongDictionary {
+                actual: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn threads_do_not_change_output() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        for method in [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ] {
+            let compress_on = |threads| {
+                let options = CompressOptions {
+                    block_size: 8192,
+                    method,
+                    threads,
+                    ..CompressOptions::default()
+                };
+                let mut compressed = Vec::new();
+                compress_with(&text[..], &mut compressed, &options).unwrap();
+                compressed
+            };
+            let compressed = compress_on(1);
+            assert_eq!(compress_on(4), compressed, "{:?}", method);
+
+            let options = DecompressOptions {
+                threads: 4,
+                ..DecompressOptions::default()
+            };
+            let mut out = Vec::new();
+            crate::decompress_with(&compressed[..], &mut out, &options).unwrap();
+            assert_eq!(out, text, "{:?}", method);
+        }
+    }
+
+    /// Fixed-width records that change a little from one to the next.
+    fn telemetry() -> Vec<u8> {
+        (0..1000)
+            .flat_map(|i| {
+                format!(
+                    "{:08} sensor={:04} temp={:+06.1} status=OK   \n",
+                    1_700_000_000 + i * 10,
+                    i % 16,
+                    20.0 + (i / 50) as f64 * 0.1
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transforms_round_trip_with_every_method() {
+        let data = telemetry();
+        for method in [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ] {
+            for transforms in ["rle", "bwt", "mtf", "bwt,mtf,rle", "rle,bwt,mtf,rle"] {
+                let options = CompressOptions {
+                    block_size: 20_000,
+                    method,
+                    transforms: transforms.parse().unwrap(),
+                    threads: 2,
+                    index: true,
+                    ..CompressOptions::default()
+                };
+                let mut compressed = Vec::new();
+                compress_with(&data[..], &mut compressed, &options).unwrap();
+
+                let mut out = Vec::new();
+                decompress(&compressed[..], &mut out).unwrap();
+                assert!(out == data, "{:?} {}", method, transforms);
+            }
+        }
+    }
+
+    #[test]
+    fn transforms_shrink_repetitive_data() {
+        let data = telemetry();
+        let compressed_with = |transforms: &str| {
+            let options = CompressOptions {
+                transforms: transforms.parse().unwrap(),
+                ..CompressOptions::default()
+            };
+            let mut compressed = Vec::new();
+            compress_with(&data[..], &mut compressed, &options).unwrap();
+            compressed.len()
+        };
+
+        assert!(compressed_with("bwt,mtf,rle") * 3 < compressed_with(""));
+    }
+
+    #[test]
+    fn rejects_unknown_transform() {
+        let options = CompressOptions {
+            transforms: "mtf".parse().unwrap(),
+            ..small_blocks(500)
+        };
+        let mut compressed_buf = Vec::new();
+        compress_with(&b"Hello World"[..], &mut compressed_buf, &options).unwrap();
+        compressed_buf[MAGIC.len() + 3] = 0xF0;
+
+        let result = decompress(&compressed_buf[..], &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(HuffmanError::UnsupportedTransform(0xF))
+        ));
+    }
+
+    #[test]
+    fn parallel_decoder_returns_blocks_before_error() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed = Vec::new();
+        compress_with(&text[..], &mut compressed, &small_blocks(1000)).unwrap();
+        compressed.truncate(compressed.len() / 2);
+
+        let options = DecompressOptions {
+            threads: 4,
+            ..DecompressOptions::default()
+        };
+        let mut decoder = Decoder::with_options(&compressed[..], options);
+        let mut out = Vec::new();
+        let result = loop {
+            match decoder.next_block() {
+                Ok(true) => out.extend_from_slice(decoder.block()),
+                result => break result,
+            }
+        };
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+        assert!(out.len() >= 4000);
+        assert_eq!(out, text[..out.len()]);
+    }
+
+    fn encrypted(data: &[u8], options: CompressOptions) -> Vec<u8> {
+        let options = CompressOptions {
+            encryption: Some(crate::Key::with_cost(b"secret", crypto::TEST_COST).unwrap()),
+            ..options
+        };
+        let mut compressed = Vec::new();
+        compress_with(data, &mut compressed, &options).unwrap();
+        compressed
+    }
+
+    fn decrypted(compressed: &[u8], passphrase: Option<&[u8]>) -> Result<Vec<u8>, HuffmanError> {
+        let options = DecompressOptions {
+            passphrase: passphrase.map(<[u8]>::to_vec),
+            ..DecompressOptions::default()
+        };
+        let mut out = Vec::new();
+        crate::decompress_with(compressed, &mut out, &options)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn encryption_round_trips_with_every_method() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        for method in [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ] {
+            let options = CompressOptions {
+                method,
+                ..small_blocks(30_000)
+            };
+            let compressed = encrypted(&text, options);
+            assert_eq!(compressed[MAGIC.len() + 1] & FLAG_ENCRYPTED, FLAG_ENCRYPTED);
+            assert_eq!(
+                decrypted(&compressed, Some(b"secret")).unwrap(),
+                text,
+                "{}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn encrypted_files_differ_and_hide_their_data() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let stored = CompressOptions {
+            method: Method::Stored,
+            ..CompressOptions::default()
+        };
+        let first = encrypted(&text, stored);
+        let second = encrypted(&text, stored);
+        assert_ne!(first, second);
+        assert!(!first.windows(20).any(|window| window == &text[..20]));
+    }
+
+    #[test]
+    fn encrypted_files_get_no_index() {
+        let options = CompressOptions {
+            index: true,
+            ..CompressOptions::default()
+        };
+        let compressed = encrypted(b"some data", options);
+        assert_eq!(compressed[MAGIC.len() + 1] & FLAG_INDEX, 0);
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_passphrase() {
+        let compressed = encrypted(b"some data", CompressOptions::default());
+        assert!(matches!(
+            decrypted(&compressed, None),
+            Err(HuffmanError::PassphraseRequired)
+        ));
+        assert!(matches!(
+            decrypted(&compressed, Some(b"guess")),
+            Err(HuffmanError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampering_before_decoding() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let compressed = encrypted(&text, small_blocks(1000));
+
+        // A modified byte anywhere in the chunk fails the first block,
+        // before anything is decoded.
+        let mut flipped = compressed.clone();
+        let last = flipped.len() - 1;
+        flipped[last - 5000] ^= 0x40;
+        let options = DecompressOptions {
+            passphrase: Some(b"secret".to_vec()),
+            ..DecompressOptions::default()
+        };
+        let mut decoder = Decoder::with_options(&flipped[..], options);
+        assert!(matches!(decoder.next_block(), Err(HuffmanError::Tampered)));
+
+        for len in [compressed.len() - 1, compressed.len() - 16] {
+            assert!(matches!(
+                decrypted(&compressed[..len], Some(b"secret")),
+                Err(HuffmanError::Tampered)
+            ));
+        }
+        let mut extended = compressed.clone();
+        extended.push(0);
+        assert!(matches!(
+            decrypted(&extended, Some(b"secret")),
+            Err(HuffmanError::Tampered)
+        ));
+
+        // The header is bound to the passphrase check.
+        let mut method = compressed.clone();
+        method[MAGIC.len() + 2] = Method::Range as u8;
+        assert!(matches!(
+            decrypted(&method, Some(b"secret")),
+            Err(HuffmanError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn writes_nothing_when_the_last_chunk_is_tampered_with() {
+        let text = std::fs::read("tests/test-1mB.txt").unwrap();
+        let stored = CompressOptions {
+            method: Method::Stored,
+            ..small_blocks(10_000)
+        };
+        let mut compressed = encrypted(&text, stored);
+        assert!(compressed.len() > 10 * crypto::CHUNK_SIZE);
+        let last = compressed.len() - 1;
+        compressed[last - 20] ^= 0x01;
+
+        let options = DecompressOptions {
+            passphrase: Some(b"secret".to_vec()),
+            ..DecompressOptions::default()
+        };
+        let mut out = Vec::new();
+        let result = crate::decompress_with(&compressed[..], &mut out, &options);
+        assert!(matches!(result, Err(HuffmanError::Tampered)));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,528 @@
+//! Authenticated encryption of press files with a key derived from a
+//! passphrase.
+//!
+//! The key is derived from the passphrase with Argon2id and a random salt.
+//! An encrypted file has `FLAG_ENCRYPTED` set and the encryption parameters
+//! after the rest of the header:
+//!
+//! ```text
+//! salt: [u8; 16] | memory_kib: u32 | iterations: u32 | lanes: u32 | nonce_prefix: [u8; 16] | check: [u8; 16]
+//! ```
+//!
+//! Everything after the header, from the first block to the end of the
+//! file, is XChaCha20-Poly1305 ciphertext in chunks of `CHUNK_SIZE` bytes of
+//! data and a 16 byte tag. The last chunk holds less than `CHUNK_SIZE`
+//! bytes, possibly none. The nonce of each chunk is the random prefix, the
+//! number of the chunk and whether it is the last, so chunks cannot be
+//! reordered, dropped or cut off without failing authentication, and the
+//! header up to the check is the associated data of every chunk. `check` is
+//! the tag of an empty message under a nonce no chunk uses, which tells a
+//! wrong passphrase apart from a modified file.
+//!
+//! The decoder reads and authenticates every chunk, up to the last, before
+//! handing any byte on, so nothing of a modified file is decoded or written
+//! out, wherever the modification is. The chunks after the first are
+//! spooled to a temporary file, still encrypted, and decrypted again as the
+//! data is read, so memory use does not grow with the file.
+
+use crate::huffman::HuffmanError;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes of data in every chunk but the last.
+pub(crate) const CHUNK_SIZE: usize = 1 << 16;
+
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 16;
+
+/// Bytes of the encryption parameters in the header, before the check.
+const PARAMETERS_LEN: usize = SALT_LEN + 3 * 4 + NONCE_PREFIX_LEN;
+
+/// Most Argon2 memory, in KiB, and iterations a file may ask for, so that a
+/// modified header cannot make the decoder run out of memory or time.
+const MAX_MEMORY_KIB: u32 = 1 << 20;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_LANES: u32 = 16;
+
+/// Argon2 cost cheap enough for tests in debug builds.
+#[cfg(test)]
+pub(crate) const TEST_COST: KeyCost = KeyCost {
+    memory_kib: 64,
+    iterations: 1,
+    lanes: 1,
+};
+
+/// Argon2id cost of deriving a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyCost {
+    pub(crate) memory_kib: u32,
+    pub(crate) iterations: u32,
+    pub(crate) lanes: u32,
+}
+
+impl Default for KeyCost {
+    /// The cost recommended by the argon2 crate: 19MiB and 2 iterations.
+    fn default() -> Self {
+        KeyCost {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            lanes: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// A key derived from a passphrase, for `CompressOptions::encryption`.
+///
+/// Deriving the key is slow on purpose, so one key can be made once and
+/// used for many files: every file gets a random nonce prefix of its own.
+#[derive(Clone, Copy)]
+pub struct Key {
+    key: [u8; 32],
+    salt: [u8; SALT_LEN],
+    cost: KeyCost,
+}
+
+impl Key {
+    /// Derive a key from `passphrase` with a random salt.
+    pub fn from_passphrase(passphrase: &[u8]) -> Result<Key, HuffmanError> {
+        Key::with_cost(passphrase, KeyCost::default())
+    }
+
+    pub(crate) fn with_cost(passphrase: &[u8], cost: KeyCost) -> Result<Key, HuffmanError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Key::derive(passphrase, salt, cost)
+    }
+
+    fn derive(passphrase: &[u8], salt: [u8; SALT_LEN], cost: KeyCost) -> Result<Key, HuffmanError> {
+        if cost.memory_kib > MAX_MEMORY_KIB
+            || cost.iterations > MAX_ITERATIONS
+            || !(1..=MAX_LANES).contains(&cost.lanes)
+        {
+            return Err(HuffmanError::Tampered);
+        }
+        let params = Params::new(cost.memory_kib, cost.iterations, cost.lanes, Some(32))
+            .map_err(|_| HuffmanError::Tampered)?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, &salt, &mut key)
+            .map_err(|_| HuffmanError::Tampered)?;
+        Ok(Key { key, salt, cost })
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.key.into())
+    }
+
+    /// Append the encryption parameters and check to `header`, the header
+    /// of a file so far, and return the encryptor of the rest of the file.
+    pub(crate) fn encryptor(&self, header: &mut Vec<u8>) -> Encryptor {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        header.extend_from_slice(&self.salt);
+        for n in [self.cost.memory_kib, self.cost.iterations, self.cost.lanes] {
+            header.extend_from_slice(&n.to_be_bytes());
+        }
+        header.extend_from_slice(&prefix);
+
+        let cipher = self.cipher();
+        let aad = header.clone();
+        let check = cipher
+            .encrypt(
+                &check_nonce(&prefix),
+                Payload {
+                    msg: &[],
+                    aad: &aad,
+                },
+            )
+            .expect("encrypting into memory cannot fail");
+        header.extend_from_slice(&check);
+        Encryptor {
+            cipher,
+            prefix,
+            aad,
+            chunks: 0,
+            data: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+}
+
+/// Leaves the key itself out.
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("salt", &self.salt)
+            .field("cost", &self.cost)
+            .finish_non_exhaustive()
+    }
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], chunk: u64, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..23].copy_from_slice(&chunk.to_be_bytes()[1..]);
+    nonce[23] = last as u8;
+    nonce.into()
+}
+
+/// Nonce of the check, whose last byte no chunk's nonce has.
+fn check_nonce(prefix: &[u8; NONCE_PREFIX_LEN]) -> XNonce {
+    let mut nonce = [0xff; 24];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce.into()
+}
+
+/// Encrypts the part of a file after its header, a chunk at a time.
+pub(crate) struct Encryptor {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    chunks: u64,
+    data: Vec<u8>,
+}
+
+impl Encryptor {
+    /// Encrypt `buf` to `output`, holding back the bytes that do not fill a
+    /// chunk.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        output: &mut W,
+        mut buf: &[u8],
+    ) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            let n = (CHUNK_SIZE - self.data.len()).min(buf.len());
+            self.data.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.data.len() == CHUNK_SIZE {
+                self.seal(output, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt the bytes held back as the last chunk.
+    pub(crate) fn finish<W: Write>(mut self, output: &mut W) -> std::io::Result<()> {
+        self.seal(output, true)
+    }
+
+    fn seal<W: Write>(&mut self, output: &mut W, last: bool) -> std::io::Result<()> {
+        let payload = Payload {
+            msg: &self.data,
+            aad: &self.aad,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(&self.prefix, self.chunks, last), payload)
+            .expect("encrypting into memory cannot fail");
+        output.write_all(&sealed)?;
+        self.data.clear();
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+/// Bytes of the tags in `sealed_len` bytes of encrypted chunks.
+pub(crate) fn tags_len(sealed_len: u64) -> u64 {
+    let chunks = sealed_len / (CHUNK_SIZE + TAG_LEN) as u64 + 1;
+    chunks * TAG_LEN as u64
+}
+
+/// Read the encryption parameters and check that follow `header`, the
+/// header of a file so far, from `input`, and derive the key from
+/// `passphrase`. Returns the decryptor of the rest of the file.
+pub(crate) fn decryptor<R: Read>(
+    input: &mut R,
+    header: &[u8],
+    passphrase: Option<&[u8]>,
+) -> Result<Decryptor, HuffmanError> {
+    let mut parameters = [0u8; PARAMETERS_LEN];
+    input.read_exact(&mut parameters)?;
+    let mut check = [0u8; TAG_LEN];
+    input.read_exact(&mut check)?;
+    let passphrase = passphrase.ok_or(HuffmanError::PassphraseRequired)?;
+
+    let (salt, rest) = parameters.split_at(SALT_LEN);
+    let (cost, prefix) = rest.split_at(3 * 4);
+    let n = |i: usize| u32::from_be_bytes(cost[4 * i..4 * i + 4].try_into().unwrap());
+    let cost = KeyCost {
+        memory_kib: n(0),
+        iterations: n(1),
+        lanes: n(2),
+    };
+    let key = Key::derive(passphrase, salt.try_into().unwrap(), cost)?;
+    let prefix: [u8; NONCE_PREFIX_LEN] = prefix.try_into().unwrap();
+
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&parameters);
+    let cipher = key.cipher();
+    let payload = Payload {
+        msg: &check,
+        aad: &aad,
+    };
+    cipher
+        .decrypt(&check_nonce(&prefix), payload)
+        .map_err(|_| HuffmanError::WrongPassphrase)?;
+    Ok(Decryptor {
+        cipher,
+        prefix,
+        aad,
+        chunks: 0,
+        last: false,
+        data: Vec::new(),
+        pos: 0,
+        spool: None,
+        current: 0,
+    })
+}
+
+/// Decrypts the part of a file after its header.
+///
+/// The first `fill_buf` reads and authenticates every chunk, keeping the
+/// data of the first and spooling the rest, still encrypted, to a temporary
+/// file. The spooled chunks are then decrypted again one at a time as the
+/// data is consumed, so only a chunk is held in memory.
+pub(crate) struct Decryptor {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    aad: Vec<u8>,
+    /// Number of chunks read from the input.
+    chunks: u64,
+    /// Whether the last chunk has been read from the input.
+    last: bool,
+    /// The decrypted chunk being consumed.
+    data: Vec<u8>,
+    pos: usize,
+    /// The chunks after the first, as read from the input.
+    spool: Option<BufReader<Spool>>,
+    /// Number of the chunk in `data`.
+    current: u64,
+}
+
+impl Decryptor {
+    /// The decrypted bytes not yet consumed, reading and authenticating
+    /// every chunk from `input` the first time. Empty at the end.
+    pub(crate) fn fill_buf<R: Read>(&mut self, input: &mut R) -> std::io::Result<&[u8]> {
+        self.finish(input)?;
+        if self.pos == self.data.len() && self.current + 1 < self.chunks {
+            self.current += 1;
+            let last = self.current + 1 == self.chunks;
+            let spool = self
+                .spool
+                .as_mut()
+                .expect("chunks after the first are spooled");
+            let sealed = read_chunk(spool)?;
+            self.data = self.open(&sealed, self.current, last)?;
+            self.pos = 0;
+        }
+        Ok(&self.data[self.pos..])
+    }
+
+    pub(crate) fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.data.len());
+    }
+
+    /// Read up to the end of the last chunk, authenticating every chunk on
+    /// the way.
+    pub(crate) fn finish<R: Read>(&mut self, input: &mut R) -> std::io::Result<()> {
+        if self.last {
+            return Ok(());
+        }
+        let mut spool = None;
+        while !self.last {
+            let sealed = read_chunk(input)?;
+            let last = (sealed.len() as u64) < (CHUNK_SIZE + TAG_LEN) as u64;
+            let data = self.open(&sealed, self.chunks, last)?;
+            if self.chunks == 0 {
+                self.data = data;
+            } else {
+                let spool = match spool.as_mut() {
+                    Some(spool) => spool,
+                    None => spool.insert(BufWriter::new(Spool::create()?)),
+                };
+                spool.write_all(&sealed)?;
+            }
+            self.last = last;
+            self.chunks += 1;
+        }
+        if let Some(spool) = spool {
+            let mut spool = spool.into_inner().map_err(|e| e.into_error())?;
+            spool.file.seek(SeekFrom::Start(0))?;
+            self.spool = Some(BufReader::new(spool));
+        }
+        Ok(())
+    }
+
+    /// Decrypt and authenticate chunk number `chunk`.
+    fn open(&self, sealed: &[u8], chunk: u64, last: bool) -> std::io::Result<Vec<u8>> {
+        let payload = Payload {
+            msg: sealed,
+            aad: &self.aad,
+        };
+        let data = self
+            .cipher
+            .decrypt(&nonce(&self.prefix, chunk, last), payload)
+            .map_err(|_| HuffmanError::Tampered)?;
+        Ok(data)
+    }
+}
+
+/// Read the next chunk from `input`. Only the last chunk is shorter than a
+/// full one, so a short read marks it.
+fn read_chunk<R: Read>(input: &mut R) -> std::io::Result<Vec<u8>> {
+    let full = (CHUNK_SIZE + TAG_LEN) as u64;
+    let mut sealed = Vec::with_capacity(full as usize);
+    input.take(full).read_to_end(&mut sealed)?;
+    Ok(sealed)
+}
+
+/// A temporary file, removed when dropped.
+struct Spool {
+    file: File,
+    path: PathBuf,
+}
+
+impl Spool {
+    fn create() -> std::io::Result<Spool> {
+        static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+        let n = SPOOLS.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("press-{}-{}.spool", std::process::id(), n));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Spool { file, path })
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn encrypt(key: &Key, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut header = b"header".to_vec();
+        let mut encryptor = key.encryptor(&mut header);
+        let mut sealed = Vec::new();
+        encryptor.write(&mut sealed, data).unwrap();
+        encryptor.finish(&mut sealed).unwrap();
+        (header, sealed)
+    }
+
+    fn decrypt(header: &[u8], sealed: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, HuffmanError> {
+        let (header, parameters) = header.split_at(b"header".len());
+        let input = [parameters, sealed].concat();
+        let mut input = &input[..];
+        let mut decryptor = decryptor(&mut input, header, Some(passphrase))?;
+        let mut data = Vec::new();
+        loop {
+            let buf = decryptor.fill_buf(&mut input)?;
+            if buf.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(buf);
+            let n = buf.len();
+            decryptor.consume(n);
+        }
+    }
+
+    #[test]
+    fn round_trips_whole_and_partial_chunks() {
+        let key = Key::with_cost(b"secret", TEST_COST).unwrap();
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 5] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let (header, sealed) = encrypt(&key, &data);
+            assert_eq!(sealed.len(), len + (len / CHUNK_SIZE + 1) * TAG_LEN);
+            assert_eq!(decrypt(&header, &sealed, b"secret").unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn removes_its_spool() {
+        let key = Key::with_cost(b"secret", TEST_COST).unwrap();
+        let (header, sealed) = encrypt(&key, &[7; 3 * CHUNK_SIZE]);
+        let (header, parameters) = header.split_at(b"header".len());
+        let input = [parameters, &sealed].concat();
+        let mut input = &input[..];
+        let mut decryptor = decryptor(&mut input, header, Some(b"secret")).unwrap();
+        decryptor.fill_buf(&mut input).unwrap();
+
+        let path = decryptor.spool.as_ref().unwrap().get_ref().path.clone();
+        assert!(path.exists());
+        drop(decryptor);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn files_get_nonces_of_their_own() {
+        let key = Key::with_cost(b"secret", TEST_COST).unwrap();
+        let (_, first) = encrypt(&key, b"same data");
+        let (_, second) = encrypt(&key, b"same data");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let key = Key::with_cost(b"secret", TEST_COST).unwrap();
+        let (header, sealed) = encrypt(&key, b"data");
+        assert!(matches!(
+            decrypt(&header, &sealed, b"guess"),
+            Err(HuffmanError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn rejects_modified_reordered_and_cut_chunks() {
+        let key = Key::with_cost(b"secret", TEST_COST).unwrap();
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 7) as u8).collect();
+        let (header, sealed) = encrypt(&key, &data);
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        let tampered = |sealed: Vec<u8>| {
+            let result = decrypt(&header, &sealed, b"secret");
+            matches!(result, Err(HuffmanError::Tampered))
+        };
+
+        let mut flipped = sealed.clone();
+        flipped[CHUNK_SIZE + 100] ^= 1;
+        assert!(tampered(flipped));
+
+        let mut swapped = sealed.clone();
+        swapped[..2 * chunk].rotate_left(chunk);
+        assert!(tampered(swapped));
+
+        assert!(tampered(sealed[..3 * chunk].to_vec()));
+        assert!(tampered(sealed[..2 * chunk].to_vec()));
+        assert!(tampered([&sealed[..], b"more"].concat()));
+    }
+}
@@ -0,0 +1,453 @@
+//! DEFLATE blocks, as described in RFC 1951.
+//!
+//! Each block of input is matched with the `lz77` module and written as
+//! whichever of a stored, fixed Huffman or dynamic Huffman block is smallest.
+//! DEFLATE's literal/length alphabet is laid out as `Symbol::index` orders
+//! symbols, and its canonical codes are the ones `HuffmanEncoding` builds,
+//! so code tables carry over directly.
+
+use crate::bits::{BitWriter, StreamBitReader};
+use crate::huffman::{HuffmanDecoder, HuffmanEncoding, HuffmanError, HuffmanTree, Symbol};
+use crate::lz77::{self, Token};
+use crate::lz77::{DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Longest code allowed in the literal/length and distance tables.
+pub(crate) const MAX_CODE_LENGTH: usize = 15;
+
+/// Longest code allowed in the table of code length codes.
+const MAX_CLEN_CODE_LENGTH: usize = 7;
+
+/// Order in which the lengths of the code length codes are stored.
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Number of literal/length symbols that can be coded: bytes, end of block
+/// and the length codes.
+const LITLEN_CODES: usize = 257 + crate::huffman::LENGTH_CODES;
+
+const STORED: u32 = 0;
+const FIXED: u32 = 1;
+const DYNAMIC: u32 = 2;
+
+/// Longest stored block.
+const MAX_STORED: usize = 0xFFFF;
+
+/// A symbol together with the extra bits that follow it.
+type Coded = (Symbol, u32, usize);
+
+/// The literal/length symbol of each token, and the distance symbol of each
+/// match.
+fn code_tokens(tokens: &[Token]) -> Vec<(Coded, Option<Coded>)> {
+    tokens
+        .iter()
+        .map(|token| match *token {
+            Token::Literal(c) => ((Symbol::Char(c), 0, 0), None),
+            Token::Match { length, distance } => {
+                let (l, l_extra, l_bits) = lz77::split(length, &LENGTH_BASE, &LENGTH_EXTRA);
+                let (d, d_extra, d_bits) = lz77::split(distance, &DISTANCE_BASE, &DISTANCE_EXTRA);
+                (
+                    (Symbol::Length(l), l_extra, l_bits),
+                    Some((Symbol::Distance(d), d_extra, d_bits)),
+                )
+            }
+        })
+        .collect()
+}
+
+fn fixed_litlen() -> HuffmanEncoding {
+    let lengths = (0..LITLEN_CODES)
+        .map(|i| {
+            let len = match i {
+                0..=143 => 8,
+                144..=255 => 9,
+                256..=279 => 7,
+                _ => 8,
+            };
+            (Symbol::from_index(i), len)
+        })
+        .collect();
+    HuffmanEncoding::from_lengths(&lengths)
+}
+
+fn fixed_distance() -> HuffmanEncoding {
+    let lengths = (0..DISTANCE_BASE.len())
+        .map(|d| (Symbol::Distance(d as u8), 5))
+        .collect();
+    HuffmanEncoding::from_lengths(&lengths)
+}
+
+/// Build a dynamic code table. Decoders may reject a code with a single
+/// symbol, so `fillers` are added until there are at least two.
+fn dynamic_table(
+    mut frequencies: HashMap<Symbol, u64>,
+    fillers: [Symbol; 2],
+    max_len: usize,
+) -> Result<HuffmanEncoding, HuffmanError> {
+    for filler in fillers {
+        if frequencies.len() >= 2 {
+            break;
+        }
+        frequencies.entry(filler).or_insert(1);
+    }
+    let tree = HuffmanTree::from_frequencies_limited(&frequencies, max_len)?;
+    Ok(HuffmanEncoding::from_tree(&tree))
+}
+
+fn code_len(encoding: &HuffmanEncoding, symbol: &Symbol) -> usize {
+    encoding.encoding.get(symbol).map_or(0, |code| code.len())
+}
+
+/// Run-length code a sequence of code lengths with the code length
+/// alphabet: 0-15 are lengths, 16 repeats the previous length 3-6 times, 17
+/// and 18 give runs of 3-10 and 11-138 zeros.
+fn rle_lengths(lengths: &[usize]) -> Vec<Coded> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i..].iter().take_while(|l| **l == len).count();
+        if len == 0 && run >= 11 {
+            let n = run.min(138);
+            out.push((Symbol::Char(18), (n - 11) as u32, 7));
+            i += n;
+        } else if len == 0 && run >= 3 {
+            let n = run.min(10);
+            out.push((Symbol::Char(17), (n - 3) as u32, 3));
+            i += n;
+        } else if len != 0 && run >= 4 {
+            let n = (run - 1).min(6);
+            out.push((Symbol::Char(len as u8), 0, 0));
+            out.push((Symbol::Char(16), (n - 3) as u32, 2));
+            i += n + 1;
+        } else {
+            out.push((Symbol::Char(len as u8), 0, 0));
+            i += 1;
+        }
+    }
+    out
+}
+
+/// The header of a dynamic block: how many literal/length, distance and code
+/// length code lengths are stored, the code length table, and the stored
+/// lengths themselves.
+struct DynamicHeader {
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+    clen: HuffmanEncoding,
+    lengths: Vec<Coded>,
+}
+
+impl DynamicHeader {
+    fn new(litlen: &HuffmanEncoding, distance: &HuffmanEncoding) -> DynamicHeader {
+        let litlen_lengths: Vec<usize> = (0..LITLEN_CODES)
+            .map(|i| code_len(litlen, &Symbol::from_index(i)))
+            .collect();
+        let distance_lengths: Vec<usize> = (0..DISTANCE_BASE.len())
+            .map(|d| code_len(distance, &Symbol::Distance(d as u8)))
+            .collect();
+        let hlit = 257.max(litlen_lengths.iter().rposition(|l| *l > 0).unwrap_or(0) + 1);
+        let hdist = 1.max(distance_lengths.iter().rposition(|l| *l > 0).unwrap_or(0) + 1);
+
+        let mut all = litlen_lengths[..hlit].to_vec();
+        all.extend_from_slice(&distance_lengths[..hdist]);
+        let lengths = rle_lengths(&all);
+
+        let mut frequencies: HashMap<Symbol, u64> = HashMap::new();
+        for (symbol, _, _) in &lengths {
+            *frequencies.entry(*symbol).or_insert(0) += 1;
+        }
+        let clen = dynamic_table(
+            frequencies,
+            [Symbol::Char(0), Symbol::Char(1)],
+            MAX_CLEN_CODE_LENGTH,
+        )
+        .expect("the 19 code length codes fit");
+        let hclen = 4.max(
+            CLEN_ORDER
+                .iter()
+                .rposition(|c| code_len(&clen, &Symbol::Char(*c as u8)) > 0)
+                .unwrap_or(0)
+                + 1,
+        );
+
+        DynamicHeader {
+            hlit,
+            hdist,
+            hclen,
+            clen,
+            lengths,
+        }
+    }
+
+    fn cost(&self) -> usize {
+        14 + 3 * self.hclen
+            + self
+                .lengths
+                .iter()
+                .map(|(s, _, n)| code_len(&self.clen, s) + n)
+                .sum::<usize>()
+    }
+
+    fn write(&self, bits: &mut BitWriter) {
+        bits.write_bits((self.hlit - 257) as u32, 5);
+        bits.write_bits((self.hdist - 1) as u32, 5);
+        bits.write_bits((self.hclen - 4) as u32, 4);
+        for c in &CLEN_ORDER[..self.hclen] {
+            bits.write_bits(code_len(&self.clen, &Symbol::Char(*c as u8)) as u32, 3);
+        }
+        for (symbol, extra, n) in &self.lengths {
+            bits.write_code(&self.clen.encoding[symbol]);
+            bits.write_bits(*extra, *n);
+        }
+    }
+}
+
+/// Number of bits taken by the tokens and end of block with the given
+/// tables.
+fn tokens_cost(
+    coded: &[(Coded, Option<Coded>)],
+    litlen: &HuffmanEncoding,
+    distance: &HuffmanEncoding,
+) -> usize {
+    let tokens: usize = coded
+        .iter()
+        .map(|((l, _, l_bits), d)| {
+            code_len(litlen, l)
+                + l_bits
+                + d.map_or(0, |(d, _, d_bits)| code_len(distance, &d) + d_bits)
+        })
+        .sum();
+    tokens + code_len(litlen, &Symbol::EOT)
+}
+
+fn write_tokens(
+    bits: &mut BitWriter,
+    coded: &[(Coded, Option<Coded>)],
+    litlen: &HuffmanEncoding,
+    distance: &HuffmanEncoding,
+) {
+    for ((l, l_extra, l_bits), d) in coded {
+        bits.write_code(&litlen.encoding[l]);
+        bits.write_bits(*l_extra, *l_bits);
+        if let Some((d, d_extra, d_bits)) = d {
+            bits.write_code(&distance.encoding[d]);
+            bits.write_bits(*d_extra, *d_bits);
+        }
+    }
+    bits.write_code(&litlen.encoding[&Symbol::EOT]);
+}
+
+fn write_stored(bits: &mut BitWriter, block: &[u8], last: bool) {
+    let mut chunks: Vec<&[u8]> = block.chunks(MAX_STORED).collect();
+    // An empty block is still written, as a single empty stored block.
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let n = chunks.len();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        bits.write_bits((last && i == n - 1) as u32, 1);
+        bits.write_bits(STORED, 2);
+        bits.align();
+        let len = chunk.len() as u16;
+        bits.write_bytes(&len.to_le_bytes());
+        bits.write_bytes(&(!len).to_le_bytes());
+        bits.write_bytes(chunk);
+    }
+}
+
+/// Compress `block` as one or more DEFLATE blocks, the last of them marked
+/// final if `last` is set. Matches do not reach back into earlier blocks.
+pub(crate) fn write_block(
+    bits: &mut BitWriter,
+    block: &[u8],
+    last: bool,
+    max_code_length: usize,
+    max_chain: usize,
+) -> Result<(), HuffmanError> {
+    let coded = code_tokens(&lz77::parse(block, max_chain));
+
+    let mut litlen_freqs: HashMap<Symbol, u64> = HashMap::new();
+    let mut distance_freqs: HashMap<Symbol, u64> = HashMap::new();
+    for ((l, _, _), d) in &coded {
+        *litlen_freqs.entry(*l).or_insert(0) += 1;
+        if let Some((d, _, _)) = d {
+            *distance_freqs.entry(*d).or_insert(0) += 1;
+        }
+    }
+    litlen_freqs.insert(Symbol::EOT, 1);
+
+    let max_len = max_code_length.min(MAX_CODE_LENGTH);
+    let litlen = dynamic_table(litlen_freqs, [Symbol::Char(0), Symbol::Char(1)], max_len)?;
+    let distance = dynamic_table(
+        distance_freqs,
+        [Symbol::Distance(0), Symbol::Distance(1)],
+        max_len,
+    )?;
+    let header = DynamicHeader::new(&litlen, &distance);
+
+    let (fixed_litlen, fixed_distance) = (fixed_litlen(), fixed_distance());
+    let dynamic_cost = header.cost() + tokens_cost(&coded, &litlen, &distance);
+    let fixed_cost = tokens_cost(&coded, &fixed_litlen, &fixed_distance);
+    let stored_cost = 8 * block.len() + 40 * block.len().div_ceil(MAX_STORED).max(1);
+
+    if stored_cost < dynamic_cost.min(fixed_cost) {
+        write_stored(bits, block, last);
+    } else if fixed_cost <= dynamic_cost {
+        bits.write_bits(last as u32, 1);
+        bits.write_bits(FIXED, 2);
+        write_tokens(bits, &coded, &fixed_litlen, &fixed_distance);
+    } else {
+        bits.write_bits(last as u32, 1);
+        bits.write_bits(DYNAMIC, 2);
+        header.write(bits);
+        write_tokens(bits, &coded, &litlen, &distance);
+    }
+    Ok(())
+}
+
+fn read_stored<R: BufRead>(
+    bits: &mut StreamBitReader<R>,
+    out: &mut Vec<u8>,
+) -> Result<(), HuffmanError> {
+    bits.align();
+    let mut header = [0u8; 4];
+    bits.read_bytes(&mut header)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != !nlen {
+        return Err(HuffmanError::BadBitstream(
+            "stored block length check failed",
+        ));
+    }
+
+    let start = out.len();
+    out.resize(start + len as usize, 0);
+    bits.read_bytes(&mut out[start..])
+}
+
+fn read_dynamic_tables<R: BufRead>(
+    bits: &mut StreamBitReader<R>,
+) -> Result<(HuffmanDecoder, HuffmanDecoder), HuffmanError> {
+    let hlit = bits.read_bits(5)? as usize + 257;
+    let hdist = bits.read_bits(5)? as usize + 1;
+    let hclen = bits.read_bits(4)? as usize + 4;
+
+    let mut clen_lengths: HashMap<Symbol, usize> = HashMap::new();
+    for c in &CLEN_ORDER[..hclen] {
+        clen_lengths.insert(Symbol::Char(*c as u8), bits.read_bits(3)? as usize);
+    }
+    HuffmanEncoding::check_lengths(&clen_lengths)?;
+    let clen = HuffmanDecoder::new(&HuffmanEncoding::from_lengths(&clen_lengths));
+
+    let mut lengths: Vec<usize> = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let (len, repeat) = match clen.decode_symbol(bits)? {
+            Symbol::Char(16) => {
+                let prev = *lengths
+                    .last()
+                    .ok_or(HuffmanError::BadTable("repeat of no previous length"))?;
+                (prev, 3 + bits.read_bits(2)?)
+            }
+            Symbol::Char(17) => (0, 3 + bits.read_bits(3)?),
+            Symbol::Char(18) => (0, 11 + bits.read_bits(7)?),
+            Symbol::Char(len) => (len as usize, 1),
+            _ => return Err(HuffmanError::BadTable("unknown code length code")),
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() > hlit + hdist {
+        return Err(HuffmanError::BadTable("code lengths overrun the table"));
+    }
+
+    // Literal/length codes 286 and 287 and distance codes 30 and 31 can
+    // appear in the table but never in valid data, so they are left out.
+    let litlen_lengths: HashMap<Symbol, usize> = lengths[..hlit.min(LITLEN_CODES)]
+        .iter()
+        .enumerate()
+        .filter(|(_, len)| **len > 0)
+        .map(|(i, len)| (Symbol::from_index(i), *len))
+        .collect();
+    let distance_lengths: HashMap<Symbol, usize> = lengths[hlit..]
+        .iter()
+        .take(DISTANCE_BASE.len())
+        .enumerate()
+        .filter(|(_, len)| **len > 0)
+        .map(|(d, len)| (Symbol::Distance(d as u8), *len))
+        .collect();
+    if !litlen_lengths.contains_key(&Symbol::EOT) {
+        return Err(HuffmanError::BadTable("no code for end of block"));
+    }
+    HuffmanEncoding::check_lengths(&litlen_lengths)?;
+    HuffmanEncoding::check_lengths(&distance_lengths)?;
+
+    Ok((
+        HuffmanDecoder::new(&HuffmanEncoding::from_lengths(&litlen_lengths)),
+        HuffmanDecoder::new(&HuffmanEncoding::from_lengths(&distance_lengths)),
+    ))
+}
+
+fn read_compressed<R: BufRead>(
+    bits: &mut StreamBitReader<R>,
+    out: &mut Vec<u8>,
+    litlen: &HuffmanDecoder,
+    distance: &HuffmanDecoder,
+) -> Result<(), HuffmanError> {
+    loop {
+        match litlen.decode_symbol(bits)? {
+            Symbol::Char(c) => out.push(c),
+            Symbol::EOT => return Ok(()),
+            Symbol::Length(l) => {
+                let l = l as usize;
+                let length =
+                    LENGTH_BASE[l] as usize + bits.read_bits(LENGTH_EXTRA[l] as usize)? as usize;
+                let d = match distance.decode_symbol(bits)? {
+                    Symbol::Distance(d) => d as usize,
+                    _ => return Err(HuffmanError::BadBitstream("expected a distance code")),
+                };
+                let distance = DISTANCE_BASE[d] as usize
+                    + bits.read_bits(DISTANCE_EXTRA[d] as usize)? as usize;
+                if distance > out.len() {
+                    return Err(HuffmanError::BadBitstream("match before start of stream"));
+                }
+                let from = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[from + i]);
+                }
+            }
+            Symbol::Distance(_) | Symbol::Word(_) => {
+                return Err(HuffmanError::BadBitstream("unexpected symbol"))
+            }
+        }
+    }
+}
+
+/// Decode one DEFLATE block, appending its data to `out`, which must hold
+/// the last `lz77::WINDOW_SIZE` bytes decoded before it. Returns whether the
+/// block was marked final.
+pub(crate) fn read_block<R: BufRead>(
+    bits: &mut StreamBitReader<R>,
+    out: &mut Vec<u8>,
+) -> Result<bool, HuffmanError> {
+    let last = bits.read_bits(1)? == 1;
+    match bits.read_bits(2)? {
+        STORED => read_stored(bits, out)?,
+        FIXED => read_compressed(
+            bits,
+            out,
+            &HuffmanDecoder::new(&fixed_litlen()),
+            &HuffmanDecoder::new(&fixed_distance()),
+        )?,
+        DYNAMIC => {
+            let (litlen, distance) = read_dynamic_tables(bits)?;
+            read_compressed(bits, out, &litlen, &distance)?
+        }
+        _ => return Err(HuffmanError::BadBitstream("reserved block type")),
+    }
+    Ok(last)
+}
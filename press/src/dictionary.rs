@@ -0,0 +1,141 @@
+//! Code tables trained on sample data and shared between files.
+//!
+//! A small file spends much of its size on its code table. When many such
+//! files look alike, a table built once from samples of them can be kept in
+//! a dictionary file instead, and each compressed file refers to it by its
+//! ID. The dictionary has a code for every byte, so it can code any block,
+//! although blocks unlike the samples are better off with their own table;
+//! the encoder picks whichever makes the block smaller.
+//!
+//! A dictionary file is laid out as:
+//!
+//! ```text
+//! MAGIC | version: u8 | table
+//! ```
+//!
+//! where the table is saved with `HuffmanEncoding::save`. The ID is the
+//! CRC32 of the table.
+
+use crate::huffman::{HuffmanEncoding, HuffmanError, Symbol};
+
+use std::collections::HashMap;
+
+const MAGIC: [u8; 5] = [b'P', b'D', b'I', b'C', b'T'];
+
+const VERSION: u8 = 1;
+
+/// A code table shared by the files compressed with it.
+#[derive(Clone)]
+pub struct Dictionary {
+    encoding: HuffmanEncoding,
+    id: u32,
+}
+
+impl Dictionary {
+    /// Build a dictionary from the byte frequencies of `samples`. Every byte
+    /// is counted once more than it occurs, so that all have a code, which
+    /// takes a `max_code_length` of at least 9 bits.
+    pub fn train<'a, I>(samples: I, max_code_length: usize) -> Result<Dictionary, HuffmanError>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut counts = [1u64; 256];
+        for sample in samples {
+            for c in sample {
+                counts[*c as usize] += 1;
+            }
+        }
+
+        let mut frequencies: HashMap<Symbol, u64> = (0..=255u8)
+            .map(|c| (Symbol::Char(c), counts[c as usize]))
+            .collect();
+        frequencies.insert(Symbol::EOT, 1);
+        let encoding = HuffmanEncoding::from_frequencies_limited(frequencies, max_code_length)?;
+        Ok(Dictionary::from_encoding(encoding))
+    }
+
+    fn from_encoding(encoding: HuffmanEncoding) -> Dictionary {
+        let id = crc32fast::hash(&encoding.save());
+        Dictionary { encoding, id }
+    }
+
+    /// Identifies the dictionary in the files compressed with it.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn encoding(&self) -> &HuffmanEncoding {
+        &self.encoding
+    }
+
+    /// Serialise the dictionary as a dictionary file.
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(self.encoding.save());
+        out
+    }
+
+    /// Read a dictionary file written by `save`.
+    pub fn restore_from(d: &[u8]) -> Result<Dictionary, HuffmanError> {
+        let table = d.strip_prefix(&MAGIC).ok_or(HuffmanError::BadMagic)?;
+        let (&version, table) = table.split_first().ok_or(HuffmanError::Truncated)?;
+        if version != VERSION {
+            return Err(HuffmanError::UnsupportedVersion(version));
+        }
+
+        let encoding = HuffmanEncoding::restore_from(table)?;
+        if (0..=255u8).any(|c| !encoding.encoding.contains_key(&Symbol::Char(c))) {
+            return Err(HuffmanError::BadTable(
+                "dictionary lacks codes for some bytes",
+            ));
+        }
+        Ok(Dictionary::from_encoding(encoding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn can_save_and_restore() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let dictionary = Dictionary::train([&text[..]], 15).unwrap();
+
+        let restored = Dictionary::restore_from(&dictionary.save()).unwrap();
+        assert_eq!(restored.id(), dictionary.id());
+        assert!(restored.encoding() == dictionary.encoding());
+    }
+
+    #[test]
+    fn codes_every_byte() {
+        let dictionary = Dictionary::train([&b"aaaa"[..], b"abab"], 15).unwrap();
+        let all: Vec<u8> = (0..=255).collect();
+
+        assert!(dictionary.encoding().encoded_bits(&all).is_some());
+    }
+
+    #[test]
+    fn rejects_code_lengths_too_short_for_every_byte() {
+        let result = Dictionary::train([&b"abc"[..]], 8);
+        assert!(matches!(
+            result,
+            Err(HuffmanError::BadCodeLength {
+                max_len: 8,
+                symbols: 257
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_partial_table() {
+        let mut file = MAGIC.to_vec();
+        file.push(VERSION);
+        file.extend(HuffmanEncoding::from_data_vec(b"abc").save());
+
+        let result = Dictionary::restore_from(&file);
+        assert!(matches!(result, Err(HuffmanError::BadTable(_))));
+    }
+}
@@ -0,0 +1,62 @@
+//! The interface shared by the entropy coders a block can be compressed
+//! with.
+
+use crate::huffman::{HuffmanDecoder, HuffmanEncoding, HuffmanError};
+use crate::CompressOptions;
+
+use std::io::{BufRead, Write};
+
+/// Codes blocks of bytes with a model that is saved alongside the coded data.
+///
+/// The container writes a block as the saved model, prefixed by its length,
+/// followed by the coded data, which must end on its own so that decoding
+/// stops where it does.
+pub trait EntropyCoder: Sized {
+    /// Build a coder for `block`, failing if `options` ask for one that
+    /// cannot be built.
+    fn for_block(block: &[u8], options: &CompressOptions) -> Result<Self, HuffmanError>;
+
+    /// Serialise what the decoder needs to rebuild the coder.
+    fn save(&self) -> Vec<u8>;
+
+    fn restore_from(d: &[u8]) -> Result<Self, HuffmanError>;
+
+    fn encode(&self, input: &[u8]) -> Vec<u8>;
+
+    /// Decode data coded by `encode` from `input`, consuming only the bytes
+    /// it occupies. Fails with `HuffmanError::BadBitstream` as soon as the
+    /// data runs past `limit` bytes.
+    fn decode_from<R: BufRead, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        limit: usize,
+    ) -> Result<(), HuffmanError>;
+}
+
+impl EntropyCoder for HuffmanEncoding {
+    fn for_block(block: &[u8], options: &CompressOptions) -> Result<Self, HuffmanError> {
+        HuffmanEncoding::from_data_vec_with(block, options.alphabet, options.max_code_length)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        HuffmanEncoding::save(self)
+    }
+
+    fn restore_from(d: &[u8]) -> Result<Self, HuffmanError> {
+        HuffmanEncoding::restore_from(d)
+    }
+
+    fn encode(&self, input: &[u8]) -> Vec<u8> {
+        HuffmanEncoding::encode(self, input)
+    }
+
+    fn decode_from<R: BufRead, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        limit: usize,
+    ) -> Result<(), HuffmanError> {
+        HuffmanDecoder::new(self).decode_from(input, output, limit)
+    }
+}
@@ -0,0 +1,451 @@
+//! Reading and writing gzip files (RFC 1952), for exchanging data with
+//! `gzip` and `gunzip`.
+//!
+//! A gzip member is a header, a DEFLATE stream and a trailer holding the
+//! CRC32 and size of the data:
+//!
+//! ```text
+//! 1f 8b | 08 | flags: u8 | mtime: u32 | xfl: u8 | os: u8 | [optional fields]
+//! deflate stream
+//! crc32: u32 | size mod 2^32: u32
+//! ```
+//!
+//! Integers are little-endian. Files holding several members, as made by
+//! concatenating gzip files, decompress to the concatenation of their data.
+
+use crate::bits::{BitWriter, StreamBitReader};
+use crate::huffman::HuffmanError;
+use crate::{deflate, lz77, CompressOptions};
+
+use std::io::{BufRead, Read, Write};
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The only compression method gzip defines, DEFLATE.
+const CM_DEFLATE: u8 = 8;
+
+const FTEXT: u8 = 0x01;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// Operating system field for an unknown system.
+const OS_UNKNOWN: u8 = 255;
+
+/// Compresses everything written to it into a gzip file written to an inner
+/// writer.
+///
+/// Input is buffered and compressed `block_size` bytes at a time; the
+/// `method` option is ignored as gzip always uses DEFLATE. Call `finish`
+/// once all data has been written to end the stream and get the inner writer
+/// back; dropping the encoder finishes it too, but ignores any error.
+pub struct Encoder<W: Write> {
+    output: Option<W>,
+    options: CompressOptions,
+    block: Vec<u8>,
+    bits: BitWriter,
+    hasher: crc32fast::Hasher,
+    size: u32,
+    header_written: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(output: W) -> Self {
+        Encoder::with_options(output, CompressOptions::default())
+    }
+
+    pub fn with_options(output: W, options: CompressOptions) -> Self {
+        Encoder {
+            output: Some(output),
+            options,
+            block: Vec::with_capacity(options.block_size),
+            bits: BitWriter::new(),
+            hasher: crc32fast::Hasher::new(),
+            size: 0,
+            header_written: false,
+        }
+    }
+
+    pub fn finish(mut self) -> Result<W, HuffmanError> {
+        let result = self.try_finish();
+        let output = self.output.take().unwrap();
+        result?;
+        Ok(output)
+    }
+
+    fn output(&mut self) -> &mut W {
+        self.output.as_mut().expect("Encoder used after finish")
+    }
+
+    /// Write the header if needed, then the buffered block. The final block
+    /// is written even when empty, as the stream must end with one.
+    fn write_block(&mut self, last: bool) -> std::io::Result<()> {
+        if !self.header_written {
+            let mut header = Vec::from(MAGIC);
+            header.extend_from_slice(&[CM_DEFLATE, 0, 0, 0, 0, 0, 0, OS_UNKNOWN]);
+            self.output().write_all(&header)?;
+            self.header_written = true;
+        }
+
+        self.hasher.update(&self.block);
+        self.size = self.size.wrapping_add(self.block.len() as u32);
+        deflate::write_block(
+            &mut self.bits,
+            &self.block,
+            last,
+            self.options.max_code_length,
+            self.options.max_chain,
+        )?;
+        self.block.clear();
+
+        let bytes = self.bits.take_bytes();
+        self.output().write_all(&bytes)
+    }
+
+    fn try_finish(&mut self) -> std::io::Result<()> {
+        self.write_block(true)?;
+
+        let mut trailer = std::mem::take(&mut self.bits).finish();
+        trailer.extend_from_slice(&self.hasher.clone().finalize().to_le_bytes());
+        trailer.extend_from_slice(&self.size.to_le_bytes());
+        let output = self.output();
+        output.write_all(&trailer)?;
+        output.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // A full block is only written once more data arrives, so that the
+        // last block can be marked final.
+        if self.block.len() == self.options.block_size && !buf.is_empty() {
+            self.write_block(false)?;
+        }
+        let room = self.options.block_size - self.block.len();
+        let n = room.min(buf.len());
+        self.block.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Flushes the inner writer. Buffered data is only compressed once a
+    /// block fills up or the encoder is finished.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output().flush()
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        if self.output.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+enum DecoderState {
+    Header,
+    Blocks,
+    Trailer,
+    Done,
+}
+
+/// Decompresses a gzip file read from an inner reader.
+///
+/// DEFLATE blocks are decoded one at a time as they are read. The checksum
+/// and size in each member's trailer are checked when it is reached, and a
+/// mismatch is reported as an error from the read that reaches it.
+pub struct Decoder<R: BufRead> {
+    bits: StreamBitReader<R>,
+    state: DecoderState,
+    /// The last `lz77::WINDOW_SIZE` bytes already read, for matches to copy
+    /// from, followed by the data of the current block.
+    window: Vec<u8>,
+    pos: usize,
+    hasher: crc32fast::Hasher,
+    size: u32,
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(input: R) -> Self {
+        Decoder {
+            bits: StreamBitReader::new(input),
+            state: DecoderState::Header,
+            window: Vec::new(),
+            pos: 0,
+            hasher: crc32fast::Hasher::new(),
+            size: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.bits.into_inner()
+    }
+
+    /// Decoded data of the block read by the last call to `next_block`.
+    pub(crate) fn block(&self) -> &[u8] {
+        &self.window[self.pos..]
+    }
+
+    /// Decode the next block, returning `false` once the end of the file has
+    /// been reached and verified.
+    pub(crate) fn next_block(&mut self) -> Result<bool, HuffmanError> {
+        if self.window.len() > lz77::WINDOW_SIZE {
+            self.window.drain(..self.window.len() - lz77::WINDOW_SIZE);
+        }
+        self.pos = self.window.len();
+
+        loop {
+            match self.state {
+                DecoderState::Header => {
+                    self.read_header()?;
+                    self.state = DecoderState::Blocks;
+                }
+                DecoderState::Blocks => {
+                    let last = deflate::read_block(&mut self.bits, &mut self.window)?;
+                    if last {
+                        self.state = DecoderState::Trailer;
+                    }
+                    let block = &self.window[self.pos..];
+                    if !block.is_empty() {
+                        self.hasher.update(block);
+                        self.size = self.size.wrapping_add(block.len() as u32);
+                        return Ok(true);
+                    }
+                }
+                DecoderState::Trailer => {
+                    self.read_trailer()?;
+                    if self.bits.at_end()? {
+                        self.state = DecoderState::Done;
+                        return Ok(false);
+                    }
+                    // Another member follows, which shares nothing with this
+                    // one.
+                    self.window.clear();
+                    self.pos = 0;
+                    self.hasher = crc32fast::Hasher::new();
+                    self.size = 0;
+                    self.state = DecoderState::Header;
+                }
+                DecoderState::Done => return Ok(false),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, HuffmanError> {
+        let mut byte = [0u8; 1];
+        self.bits.read_bytes(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Skip a zero-terminated string.
+    fn skip_string(&mut self) -> Result<(), HuffmanError> {
+        while self.read_byte()? != 0 {}
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> Result<(), HuffmanError> {
+        let mut header = [0u8; 10];
+        self.bits.read_bytes(&mut header)?;
+        if header[..2] != MAGIC {
+            return Err(HuffmanError::BadMagic);
+        }
+        if header[2] != CM_DEFLATE {
+            return Err(HuffmanError::UnsupportedMethod(header[2]));
+        }
+        let flags = header[3];
+        if flags & !(FTEXT | FHCRC | FEXTRA | FNAME | FCOMMENT) != 0 {
+            return Err(HuffmanError::UnsupportedFlags(flags));
+        }
+
+        if flags & FEXTRA != 0 {
+            let mut xlen = [0u8; 2];
+            self.bits.read_bytes(&mut xlen)?;
+            let mut extra = vec![0u8; u16::from_le_bytes(xlen) as usize];
+            self.bits.read_bytes(&mut extra)?;
+        }
+        if flags & FNAME != 0 {
+            self.skip_string()?;
+        }
+        if flags & FCOMMENT != 0 {
+            self.skip_string()?;
+        }
+        if flags & FHCRC != 0 {
+            self.bits.read_bytes(&mut [0u8; 2])?;
+        }
+        Ok(())
+    }
+
+    fn read_trailer(&mut self) -> Result<(), HuffmanError> {
+        self.bits.align();
+        let mut trailer = [0u8; 8];
+        self.bits.read_bytes(&mut trailer)?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+        let actual = self.hasher.clone().finalize();
+        if actual != crc {
+            return Err(HuffmanError::ChecksumMismatch {
+                expected: crc,
+                actual,
+            });
+        }
+        if self.size != size {
+            return Err(HuffmanError::SizeMismatch {
+                expected: size as u64,
+                actual: self.size as u64,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.window.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.window.len() - self.pos);
+        buf[..n].copy_from_slice(&self.window[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Compress `input` to `output` as a gzip file.
+pub fn compress<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    options: &CompressOptions,
+) -> Result<(), HuffmanError> {
+    let mut encoder = Encoder::with_options(output, *options);
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Decompress a gzip file from `input` to `output`, checking each member
+/// against the checksum and size stored in it.
+pub fn decompress<R: BufRead, W: Write>(input: R, mut output: W) -> Result<(), HuffmanError> {
+    let mut decoder = Decoder::new(input);
+    while decoder.next_block()? {
+        output.write_all(decoder.block())?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::BufReader;
+
+    fn gzip(data: &[u8], options: &CompressOptions) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        compress(data, &mut compressed, options).unwrap();
+        compressed
+    }
+
+    fn gunzip(data: &[u8]) -> Result<Vec<u8>, HuffmanError> {
+        let mut out = Vec::new();
+        decompress(data, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn can_round_trip() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let options = CompressOptions {
+            block_size: 30_000,
+            ..CompressOptions::default()
+        };
+        let compressed = gzip(&text, &options);
+        assert!(compressed.len() < text.len() / 2);
+
+        let mut out = Vec::new();
+        decompress(BufReader::with_capacity(5, &compressed[..]), &mut out).unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn can_round_trip_empty_and_incompressible_input() {
+        let options = CompressOptions::default();
+        assert_eq!(gunzip(&gzip(b"", &options)).unwrap(), b"");
+
+        let noise: Vec<u8> = (0u32..200_000)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let compressed = gzip(&noise, &options);
+        assert!(compressed.len() < noise.len() + 100);
+        assert_eq!(gunzip(&compressed).unwrap(), noise);
+    }
+
+    #[test]
+    fn output_decompresses_with_flate2() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        for data in [&text[..], b"", b"a", &[7u8; 70_000][..]] {
+            let compressed = gzip(data, &CompressOptions::default());
+            let mut out = Vec::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_end(&mut out)
+                .unwrap();
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn reads_flate2_output() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        for level in [0, 1, 6, 9] {
+            let mut encoder = flate2::GzBuilder::new()
+                .filename("test-100kB.txt")
+                .comment("a comment")
+                .extra(vec![1, 2, 3])
+                .write(Vec::new(), flate2::Compression::new(level));
+            encoder.write_all(&text).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            assert_eq!(gunzip(&compressed).unwrap(), text, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn reads_concatenated_members() {
+        let options = CompressOptions::default();
+        let mut compressed = gzip(b"Hello ", &options);
+        compressed.append(&mut gzip(b"World", &options));
+
+        assert_eq!(gunzip(&compressed).unwrap(), b"Hello World");
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let mut compressed = gzip(b"Hello World", &CompressOptions::default());
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 1;
+
+        let result = gunzip(&compressed);
+        assert!(matches!(result, Err(HuffmanError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let compressed = gzip(&text, &CompressOptions::default());
+
+        let result = gunzip(&compressed[..compressed.len() / 2]);
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_other_methods() {
+        let mut compressed = gzip(b"Hello World", &CompressOptions::default());
+        compressed[2] = 7;
+
+        let result = gunzip(&compressed);
+        assert!(matches!(result, Err(HuffmanError::UnsupportedMethod(7))));
+    }
+}
@@ -1,31 +1,229 @@
 use bitvec::prelude::*;
 use bitvec::vec::BitVec;
 use core::hash::Hash;
-use core::panic;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::time::Instant;
+
+use crate::bits::BitSource;
+
+use log::{debug, trace};
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+
+pub type Code = BitVec<u8, Lsb0>;
 
-#[allow(dead_code)]
 #[derive(Debug)]
 pub enum HuffmanError {
-    String(&'static str),
+    Io(std::io::Error),
+    /// The input ended in the middle of the file.
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnsupportedFlags(u8),
+    /// The file was compressed with a method this version does not know.
+    UnsupportedMethod(u8),
+    /// The file was transformed before compression with a transform this
+    /// version does not know.
+    UnsupportedTransform(u8),
+    /// The file was compressed with a dictionary, given by its ID, and a
+    /// different dictionary or none was supplied.
+    WrongDictionary {
+        expected: u32,
+        actual: Option<u32>,
+    },
+    /// The file is encrypted and no passphrase was given.
+    PassphraseRequired,
+    /// The passphrase is not the one the file was encrypted with.
+    WrongPassphrase,
+    /// Encrypted data failed authentication: it was modified, reordered or
+    /// cut short.
+    Tampered,
+    /// The archive is not laid out as an archive should be.
+    BadArchive(&'static str),
+    /// An archive entry has a path that could lead outside the directory it
+    /// is extracted to.
+    UnsafePath(String),
+    /// The block index at the end of the file is inconsistent.
+    BadIndex(&'static str),
+    /// The code table does not describe a usable prefix code.
+    BadTable(&'static str),
+    /// The bitstream does not decode with its code table.
+    BadBitstream(&'static str),
+    /// Codes of at most `max_len` bits cannot be stored, or cannot give
+    /// each of `symbols` symbols a code.
+    BadCodeLength {
+        max_len: usize,
+        symbols: usize,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl std::fmt::Display for HuffmanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Truncated => write!(f, "File is truncated."),
+            Self::BadMagic => write!(f, "File is not in press format."),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported format version {}.", v),
+            Self::UnsupportedFlags(flags) => write!(f, "Unsupported format flags {:#04x}.", flags),
+            Self::UnsupportedMethod(m) => write!(f, "Unsupported compression method {}.", m),
+            Self::UnsupportedTransform(t) => write!(f, "Unsupported transform {}.", t),
+            Self::WrongDictionary {
+                expected,
+                actual: None,
+            } => write!(f, "File needs dictionary {:08x}.", expected),
+            Self::WrongDictionary {
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "File needs dictionary {:08x}, given {:08x}.",
+                expected, actual
+            ),
+            Self::PassphraseRequired => write!(f, "File is encrypted; a passphrase is needed."),
+            Self::WrongPassphrase => write!(f, "Wrong passphrase."),
+            Self::Tampered => write!(f, "Encrypted data was modified or truncated."),
+            Self::BadArchive(reason) => write!(f, "Invalid archive: {}", reason),
+            Self::UnsafePath(path) => write!(
+                f,
+                "Refusing to extract {:?}, which could lead outside the destination.",
+                path
+            ),
+            Self::BadIndex(reason) => write!(f, "Invalid block index: {}", reason),
+            Self::BadTable(reason) => write!(f, "Invalid code table: {}", reason),
+            Self::BadBitstream(reason) => write!(f, "Invalid bitstream: {}", reason),
+            Self::BadCodeLength { max_len, .. } if *max_len > MAX_STORABLE_CODE_LENGTH => write!(
+                f,
+                "Code lengths above {} bits cannot be stored.",
+                MAX_STORABLE_CODE_LENGTH
+            ),
+            Self::BadCodeLength { max_len, symbols } => write!(
+                f,
+                "{} symbols cannot be coded in at most {} bits.",
+                symbols, max_len
+            ),
+            Self::SizeMismatch { expected, actual } => write!(
+                f,
+                "Decompressed size is {} bytes, expected {} bytes.",
+                actual, expected
+            ),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Decompressed data has checksum {:08x}, expected {:08x}.",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HuffmanError {}
+
+impl From<HuffmanError> for std::io::Error {
+    fn from(e: HuffmanError) -> Self {
+        match e {
+            HuffmanError::Io(e) => e,
+            HuffmanError::Truncated => Self::new(std::io::ErrorKind::UnexpectedEof, e),
+            e => Self::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl From<std::io::Error> for HuffmanError {
+    fn from(e: std::io::Error) -> Self {
+        // Unwrap errors passed through a `Read` or `Write` implementation.
+        if e.get_ref().is_some_and(|inner| inner.is::<HuffmanError>()) {
+            return *e.into_inner().unwrap().downcast().unwrap();
+        }
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(e),
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Ord, PartialOrd, Hash, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(PartialEq, Eq, Ord, PartialOrd, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Symbol {
     Char(u8),
     EOT,
+    /// LZ77 match length code, followed by extra bits refining the length.
+    Length(u8),
+    /// LZ77 match distance code, followed by extra bits refining the distance.
+    Distance(u8),
+    /// Two bytes coded as one symbol, the first in the low byte. Used for
+    /// 16-bit words and for frequent byte pairs, see `Alphabet`.
+    Word(u16),
+}
+
+/// Number of LZ77 match length codes.
+pub const LENGTH_CODES: usize = 29;
+
+/// Number of LZ77 match distance codes.
+pub const DISTANCE_CODES: usize = 30;
+
+/// Index of the first `Symbol::Word`.
+const WORD_BASE: usize = 257 + LENGTH_CODES + DISTANCE_CODES;
+
+/// Number of distinct symbols: every byte value, EOT, the LZ77 length and
+/// distance codes, and every 16-bit word.
+pub const ALPHABET_SIZE: usize = WORD_BASE + (1 << 16);
+
+/// Default limit on code length, as in DEFLATE.
+pub const MAX_CODE_LENGTH: usize = 15;
+
+/// Longest code length `HuffmanEncoding::save` can represent.
+pub const MAX_STORABLE_CODE_LENGTH: usize = 0x7F;
+
+impl Symbol {
+    /// Position of the symbol in the code table: bytes first, then EOT, then
+    /// length codes, distance codes and words.
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Char(c) => *c as usize,
+            Self::EOT => 256,
+            Self::Length(l) => 257 + *l as usize,
+            Self::Distance(d) => 257 + LENGTH_CODES + *d as usize,
+            Self::Word(w) => WORD_BASE + *w as usize,
+        }
+    }
+
+    pub fn from_index(i: usize) -> Symbol {
+        match i {
+            0..=255 => Self::Char(i as u8),
+            256 => Self::EOT,
+            i if i < 257 + LENGTH_CODES => Self::Length((i - 257) as u8),
+            i if i < WORD_BASE => Self::Distance((i - 257 - LENGTH_CODES) as u8),
+            i => Self::Word((i - WORD_BASE) as u16),
+        }
+    }
 }
 
 impl std::fmt::Debug for Symbol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Char(c) => write!(f, "'{}'", *c as char),
+            Self::Char(c) => write!(f, "'{}'", std::ascii::escape_default(*c)),
             Self::EOT => write!(f, "EOT"),
+            Self::Length(l) => write!(f, "L{}", l),
+            Self::Distance(d) => write!(f, "D{}", d),
+            Self::Word(w) => write!(f, "W{:04x}", w),
         }
     }
 }
 
+/// Serialised with the kind of each node in a `kind` field, so that every
+/// level of the tree nests one level deeper.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum HuffmanTree {
     Terminal {
         freq: u64,
@@ -39,7 +237,12 @@ pub enum HuffmanTree {
 }
 
 impl std::fmt::Debug for HuffmanTree {
+    /// With `{:#?}`, one node per line, indented by depth and marked with
+    /// the bit that leads to it.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            return self.fmt_indented(f, "");
+        }
         match self {
             Self::Terminal { freq, symbol } => write!(f, "T({:?}, {})", symbol, freq),
             Self::Node { freq, left, right } => write!(f, "N({:?},{:?})[{}]", left, right, freq),
@@ -48,6 +251,18 @@ impl std::fmt::Debug for HuffmanTree {
 }
 
 impl HuffmanTree {
+    fn fmt_indented(&self, f: &mut std::fmt::Formatter<'_>, prefix: &str) -> std::fmt::Result {
+        match self {
+            Self::Terminal { freq, symbol } => writeln!(f, "{}{:?} {}", prefix, symbol, freq),
+            Self::Node { freq, left, right } => {
+                writeln!(f, "{}* {}", prefix, freq)?;
+                let indent = " ".repeat(prefix.len());
+                left.fmt_indented(f, &format!("{}0 ", indent))?;
+                right.fmt_indented(f, &format!("{}1 ", indent))
+            }
+        }
+    }
+
     pub fn freq(&self) -> u64 {
         match self {
             HuffmanTree::Node { freq, .. } => *freq,
@@ -55,10 +270,70 @@ impl HuffmanTree {
         }
     }
 
+    /// Length of the longest code in the tree.
+    pub fn depth(&self) -> usize {
+        match self {
+            HuffmanTree::Node { left, right, .. } => 1 + left.depth().max(right.depth()),
+            HuffmanTree::Terminal { .. } => 0,
+        }
+    }
+
+    /// Build a Huffman tree with codes of at most `MAX_CODE_LENGTH` bits,
+    /// or as many as it takes to give every symbol a code.
     pub fn from_frequencies(frequencies: &HashMap<Symbol, u64>) -> HuffmanTree {
+        let max_len = MAX_CODE_LENGTH.max(bits_for(frequencies.len()));
+        HuffmanTree::from_frequencies_limited(frequencies, max_len)
+            .expect("the limit fits every symbol")
+    }
+
+    /// Build a Huffman tree whose codes are at most `max_len` bits long.
+    ///
+    /// The plain Huffman tree is used when it already fits, otherwise code
+    /// lengths are recomputed with package-merge, which gives the optimal
+    /// codes under the length limit. Fails if codes that long cannot be
+    /// stored, or are too short to give every symbol a code.
+    pub fn from_frequencies_limited(
+        frequencies: &HashMap<Symbol, u64>,
+        max_len: usize,
+    ) -> Result<HuffmanTree, HuffmanError> {
+        if max_len > MAX_STORABLE_CODE_LENGTH || max_len < bits_for(frequencies.len()) {
+            return Err(HuffmanError::BadCodeLength {
+                max_len,
+                symbols: frequencies.len(),
+            });
+        }
+
+        let tree = HuffmanTree::from_frequencies_unlimited(frequencies);
+        if tree.depth() <= max_len {
+            return Ok(tree);
+        }
+
+        let mut symbols: Vec<(u64, Symbol)> =
+            frequencies.iter().map(|(s, freq)| (*freq, *s)).collect();
+        symbols.sort();
+        let weights: Vec<u64> = symbols.iter().map(|(freq, _)| *freq).collect();
+
+        let mut codes: Vec<(Code, Symbol, u64)> = Vec::new();
+        let lengths: HashMap<Symbol, usize> = symbols
+            .iter()
+            .zip(package_merge(&weights, max_len))
+            .map(|((_, s), len)| (*s, len))
+            .collect();
+        for (symbol, code) in HuffmanEncoding::from_lengths(&lengths).encoding {
+            codes.push((code, symbol, frequencies[&symbol]));
+        }
+        codes.sort_by(|a, b| a.0.iter().cmp(b.0.iter()));
+
+        Ok(HuffmanTree::from_sorted_codes(&codes, 0))
+    }
+
+    fn from_frequencies_unlimited(frequencies: &HashMap<Symbol, u64>) -> HuffmanTree {
         let mut heap: BinaryHeap<HuffmanTree> = BinaryHeap::new();
 
-        for (c, freq) in frequencies.iter() {
+        // Push in symbol order so ties are broken the same way on every run.
+        let mut symbols: Vec<(&Symbol, &u64)> = frequencies.iter().collect();
+        symbols.sort();
+        for (c, freq) in symbols {
             heap.push(HuffmanTree::Terminal {
                 symbol: *c,
                 freq: *freq,
@@ -77,6 +352,85 @@ impl HuffmanTree {
 
         heap.pop().unwrap()
     }
+
+    /// Rebuild the tree spelled out by a complete prefix code, given as
+    /// `(code, symbol, frequency)` sorted by code. `depth` is the number of
+    /// leading bits shared by every code in `codes`.
+    fn from_sorted_codes(codes: &[(Code, Symbol, u64)], depth: usize) -> HuffmanTree {
+        if let [(code, symbol, freq)] = codes {
+            if code.len() == depth {
+                return HuffmanTree::Terminal {
+                    freq: *freq,
+                    symbol: *symbol,
+                };
+            }
+        }
+
+        let split = codes.partition_point(|(code, _, _)| !code[depth]);
+        let left = HuffmanTree::from_sorted_codes(&codes[..split], depth + 1);
+        let right = HuffmanTree::from_sorted_codes(&codes[split..], depth + 1);
+        HuffmanTree::Node {
+            freq: left.freq() + right.freq(),
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+}
+
+/// Optimal code lengths for `weights`, sorted in ascending order, such that
+/// no code is longer than `max_len` bits.
+///
+/// This is the package-merge algorithm: every symbol is a coin of its weight
+/// at each of the `max_len` levels. Starting from the deepest level, the
+/// cheapest coins are paired into packages that are merged with the coins of
+/// the level above. A symbol's code length is the number of times it appears
+/// among the `2n - 2` cheapest items of the final level.
+fn package_merge(weights: &[u64], max_len: usize) -> Vec<usize> {
+    let n = weights.len();
+    if n <= 1 {
+        return vec![1; n];
+    }
+
+    let leaves: Vec<(u64, Vec<usize>)> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (*w, vec![i]))
+        .collect();
+
+    let mut items = leaves.clone();
+    for _ in 1..max_len {
+        let packages = items.chunks_exact(2).map(|pair| {
+            let mut symbols = pair[0].1.clone();
+            symbols.extend(&pair[1].1);
+            (pair[0].0 + pair[1].0, symbols)
+        });
+
+        let mut merged = Vec::with_capacity(2 * n);
+        let mut leaves = leaves.iter().cloned().peekable();
+        let mut packages = packages.peekable();
+        loop {
+            let take_leaf = match (leaves.peek(), packages.peek()) {
+                (Some(leaf), Some(package)) => leaf.0 <= package.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if take_leaf {
+                merged.push(leaves.next().unwrap());
+            } else {
+                merged.push(packages.next().unwrap());
+            }
+        }
+        items = merged;
+    }
+
+    let mut lengths = vec![0; n];
+    for (_, symbols) in items.iter().take(2 * n - 2) {
+        for &i in symbols {
+            lengths[i] += 1;
+        }
+    }
+    lengths
 }
 
 impl Ord for HuffmanTree {
@@ -110,8 +464,96 @@ where
     }
 }
 
+/// How input bytes are split into symbols before coding. Decoding does not
+/// depend on it, as every symbol stands for its own bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alphabet {
+    /// One symbol per byte.
+    #[default]
+    Bytes,
+    /// One symbol per 16-bit little-endian word, for data such as UTF-16
+    /// text or sensor samples. An odd final byte is coded on its own.
+    Words,
+    /// One symbol per byte, except that the most frequent byte pairs are
+    /// coded as single symbols.
+    Digrams,
+}
+
+/// Most byte pairs given their own symbol in `Alphabet::Digrams`. Each one
+/// costs a byte or two of code table.
+const MAX_DIGRAMS: usize = 1024;
+
+/// Pairs seen fewer times than this are not worth their table entry.
+const MIN_DIGRAM_COUNT: u32 = 16;
+
+impl Alphabet {
+    /// Split `input` into symbols, choosing the pairs to use for `Digrams`
+    /// from its pair counts.
+    pub fn symbols(&self, input: &[u8]) -> Vec<Symbol> {
+        match self {
+            Alphabet::Bytes => input.iter().map(|c| Symbol::Char(*c)).collect(),
+            Alphabet::Words => {
+                let words = input.chunks_exact(2);
+                let rest = words.remainder().iter().map(|c| Symbol::Char(*c));
+                words
+                    .map(|w| Symbol::Word(u16::from_le_bytes([w[0], w[1]])))
+                    .chain(rest)
+                    .collect()
+            }
+            Alphabet::Digrams => digram_symbols(input, &frequent_pairs(input)),
+        }
+    }
+}
+
+/// The byte pairs of `input` worth coding as single symbols, as a table
+/// indexed by `Symbol::Word` value.
+fn frequent_pairs(input: &[u8]) -> Vec<bool> {
+    let mut counts = vec![0u32; 1 << 16];
+    for pair in input.windows(2) {
+        counts[u16::from_le_bytes([pair[0], pair[1]]) as usize] += 1;
+    }
+
+    let mut frequent: Vec<(u32, usize)> = counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count >= MIN_DIGRAM_COUNT)
+        .map(|(w, count)| (*count, w))
+        .collect();
+    frequent.sort_by(|a, b| b.cmp(a));
+
+    let mut pairs = vec![false; 1 << 16];
+    for (_, w) in frequent.into_iter().take(MAX_DIGRAMS) {
+        pairs[w] = true;
+    }
+    pairs
+}
+
+/// Split `input` into symbols, greedily coding each pair marked in `pairs`
+/// as one symbol.
+fn digram_symbols(input: &[u8], pairs: &[bool]) -> Vec<Symbol> {
+    let mut symbols = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if i + 1 < input.len() {
+            let w = u16::from_le_bytes([input[i], input[i + 1]]);
+            if pairs[w as usize] {
+                symbols.push(Symbol::Word(w));
+                i += 2;
+                continue;
+            }
+        }
+        symbols.push(Symbol::Char(input[i]));
+        i += 1;
+    }
+    symbols
+}
+
+#[derive(Clone)]
 pub struct HuffmanEncoding {
-    pub encoding: HashMap<Symbol, BitVec<u8, Lsb0>>,
+    pub encoding: HashMap<Symbol, Code>,
+    /// How `encode` splits its input into symbols.
+    pub alphabet: Alphabet,
 }
 
 impl PartialEq for HuffmanEncoding {
@@ -121,129 +563,319 @@ impl PartialEq for HuffmanEncoding {
 }
 impl Eq for HuffmanEncoding {}
 
-fn descend(t: &HuffmanTree, seq: BitVec<u8, Lsb0>) -> HashMap<Symbol, BitVec<u8, Lsb0>> {
+fn descend(t: &HuffmanTree, seq: Code) -> HashMap<Symbol, Code> {
     match t {
         HuffmanTree::Node { left, right, .. } => {
             let mut lpath = seq.clone();
             lpath.push(false);
-            let l = descend(&left, lpath);
+            let l = descend(left, lpath);
 
             let mut rpath = seq.clone();
             rpath.push(true);
-            let r = descend(&right, rpath);
+            let r = descend(right, rpath);
 
             l.merge(r)
         }
-        HuffmanTree::Terminal { symbol, .. } => return HashMap::from_iter(vec![(*symbol, seq)]),
+        HuffmanTree::Terminal { symbol, .. } => HashMap::from_iter(vec![(*symbol, seq)]),
     }
 }
 
-fn frequency_map(input: &Vec<u8>) -> HashMap<Symbol, u64> {
+fn frequency_map(symbols: &[Symbol]) -> HashMap<Symbol, u64> {
     let mut hm: HashMap<Symbol, u64> = HashMap::new();
-    
+
     hm.insert(Symbol::EOT, 1);
 
-    for &b in input {
-        *hm.entry(Symbol::Char(b)).or_insert(0) += 1;
+    for &s in symbols {
+        *hm.entry(s).or_insert(0) += 1;
     }
     hm
 }
 
+/// Read the length and bits of a code in a legacy table, returning the code
+/// and what follows it.
+fn read_legacy_code(d: &[u8]) -> Result<(Code, &[u8]), HuffmanError> {
+    let (&len, rest) = d
+        .split_first()
+        .ok_or(HuffmanError::BadTable("legacy table is empty"))?;
+    let size = (len as usize).div_ceil(8);
+    if rest.len() < size {
+        return Err(HuffmanError::BadTable("legacy table ends inside a code"));
+    }
+    let mut code = Code::from_slice(&rest[..size]);
+    code.truncate(len as usize);
+    Ok((code, &rest[size..]))
+}
+
+/// Fewest bits that give each of `symbols` symbols a code of its own.
+fn bits_for(symbols: usize) -> usize {
+    (usize::BITS - symbols.saturating_sub(1).leading_zeros()) as usize
+}
+
 impl HuffmanEncoding {
-    pub fn from_data_vec(input: &Vec<u8>) -> HuffmanEncoding {
-        let frequencies = frequency_map(input);
-        HuffmanEncoding::from_frequencies(frequencies)
+    pub fn from_data_vec(input: &[u8]) -> HuffmanEncoding {
+        HuffmanEncoding::from_data_vec_limited(input, MAX_CODE_LENGTH)
+            .expect("the default limit can be stored")
+    }
+
+    pub fn from_data_vec_limited(
+        input: &[u8],
+        max_len: usize,
+    ) -> Result<HuffmanEncoding, HuffmanError> {
+        HuffmanEncoding::from_data_vec_with(input, Alphabet::Bytes, max_len)
+    }
+
+    /// Build codes for the symbols `alphabet` splits `input` into. `max_len`
+    /// is raised if needed to give every distinct symbol a code, which only
+    /// happens with many distinct words. Fails if `max_len` is above
+    /// `MAX_STORABLE_CODE_LENGTH`.
+    pub fn from_data_vec_with(
+        input: &[u8],
+        alphabet: Alphabet,
+        max_len: usize,
+    ) -> Result<HuffmanEncoding, HuffmanError> {
+        let start = Instant::now();
+        let frequencies = frequency_map(&alphabet.symbols(input));
+        debug!(
+            "counted {} distinct symbols in {} bytes in {:.2?}",
+            frequencies.len(),
+            input.len(),
+            start.elapsed()
+        );
+
+        let start = Instant::now();
+        let needed = bits_for(frequencies.len());
+        let mut encoding =
+            HuffmanEncoding::from_frequencies_limited(frequencies, max_len.max(needed))?;
+        encoding.alphabet = alphabet;
+        debug!(
+            "built codes of up to {} bits in {:.2?}",
+            encoding
+                .encoding
+                .values()
+                .map(|code| code.len())
+                .max()
+                .unwrap_or(0),
+            start.elapsed()
+        );
+        Ok(encoding)
     }
 
     pub fn from_frequencies(frequencies: HashMap<Symbol, u64>) -> HuffmanEncoding {
-        let tree = HuffmanTree::from_frequencies(&frequencies);
-        HuffmanEncoding::from_tree(&tree)
+        HuffmanEncoding::from_tree(&HuffmanTree::from_frequencies(&frequencies))
     }
 
+    /// Build codes of at most `max_len` bits, failing as
+    /// `HuffmanTree::from_frequencies_limited` does.
+    pub fn from_frequencies_limited(
+        frequencies: HashMap<Symbol, u64>,
+        max_len: usize,
+    ) -> Result<HuffmanEncoding, HuffmanError> {
+        let tree = HuffmanTree::from_frequencies_limited(&frequencies, max_len)?;
+        Ok(HuffmanEncoding::from_tree(&tree))
+    }
+
+    /// Only the shape of the tree is kept: its code lengths are reassigned
+    /// canonical codes, see `from_lengths`.
     pub fn from_tree(tree: &HuffmanTree) -> HuffmanEncoding {
-        HuffmanEncoding {
-            encoding: descend(tree, BitVec::new()),
+        // A lone symbol sits at the root with an empty path; give it one bit.
+        let lengths = descend(tree, BitVec::new())
+            .into_iter()
+            .map(|(s, code)| (s, code.len().max(1)))
+            .collect();
+        HuffmanEncoding::from_lengths(&lengths)
+    }
+
+    /// Build canonical codes from code lengths: symbols are sorted by length
+    /// then by symbol, and each is given the next code in counting order.
+    /// The codes are therefore fully determined by their lengths, which is
+    /// all `save` needs to store.
+    pub fn from_lengths(lengths: &HashMap<Symbol, usize>) -> HuffmanEncoding {
+        let mut symbols: Vec<(usize, Symbol)> = lengths
+            .iter()
+            .filter(|(_, len)| **len > 0)
+            .map(|(s, len)| (*len, *s))
+            .collect();
+        symbols.sort();
+
+        let mut encoding: HashMap<Symbol, Code> = HashMap::new();
+        let mut next: u128 = 0;
+        let mut prev_len = 0;
+        for (len, symbol) in symbols {
+            next <<= len - prev_len;
+            prev_len = len;
+
+            // Canonical codes are read most significant bit first.
+            let code: Code = (0..len).rev().map(|i| (next >> i) & 1 == 1).collect();
+            encoding.insert(symbol, code);
+            next += 1;
         }
+
+        // Tables with words are taken to be digram tables; see `restore_from`.
+        let alphabet = if encoding.keys().any(|s| matches!(s, Symbol::Word(_))) {
+            Alphabet::Digrams
+        } else {
+            Alphabet::Bytes
+        };
+        HuffmanEncoding { encoding, alphabet }
     }
 
+    /// Serialise the code lengths of every symbol, in symbol index order.
+    ///
+    /// Each byte is either a code length below 128 for the next symbol, or
+    /// `0x80 | (n - 1)` for a run of `n` unused symbols. Trailing unused
+    /// symbols are left out.
     pub fn save(&self) -> Vec<u8> {
         let mut out: Vec<u8> = Vec::new();
-        // let max_bitfield_size = self.encoding.right_values().map(|f| f.len()).max().unwrap();
-
-        // Encode EOT
-        let eot_bits = self.encoding.get(&Symbol::EOT).unwrap();
-        let eot_bits_len = eot_bits.len();
-        let mut eot_bytes = eot_bits.clone().into_vec();
-
-        out.push(eot_bits_len as u8);
-        out.append(&mut eot_bytes);
-
-        for (ref c, r) in self.encoding.iter() {
-            match c {
-                Symbol::Char(c) => {
-                    let len = r.len();
-                    if len > std::u8::MAX.into() {
-                        panic!("Cannot encode bitfield length in 8 bits");
+        let mut unused: usize = 0;
+
+        for i in 0..ALPHABET_SIZE {
+            match self.encoding.get(&Symbol::from_index(i)) {
+                None => unused += 1,
+                Some(code) => {
+                    // Runs are only written before a used symbol, so the
+                    // unused symbols at the end cost nothing.
+                    while unused > 0 {
+                        let run = unused.min(128);
+                        out.push(0x80 | (run - 1) as u8);
+                        unused -= run;
                     }
-                    let mut nr = r.clone();
-                    nr.set_uninitialized(false);
-                    let bits = nr.into_vec();
-
-                    // { symbol: Symbol, len: u8, bits: [u8, len]}
-                    out.push(*c);
-                    out.push(len as u8);
-                    out.append(&mut bits.clone());
+                    out.push(code.len() as u8);
                 }
-                Symbol::EOT => continue,
             }
         }
-        // eprintln!("{:?}", out);
-        return out;
+        trace!("saved code table {:?}", out);
+        out
     }
 
-    pub fn restore_from(d: &Vec<u8>) -> HuffmanEncoding {
-        // eprintln!("{:?}", d);
-        let mut encoding: HashMap<Symbol, BitVec<u8, Lsb0>> = HashMap::new();
+    /// Restore a code table saved with `save`. Every bitstream ends with
+    /// EOT, so the table must have a code for it. The alphabet is not saved,
+    /// so a table with words is restored as a digram table.
+    pub fn restore_from(d: &[u8]) -> Result<HuffmanEncoding, HuffmanError> {
+        let encoding = HuffmanEncoding::restore_table_from(d)?;
+        if !encoding.encoding.contains_key(&Symbol::EOT) {
+            return Err(HuffmanError::BadTable("no code for EOT"));
+        }
+        Ok(encoding)
+    }
 
+    /// Restore a code table saved with `save` that need not code EOT, such as
+    /// a table of LZ77 distances.
+    pub fn restore_table_from(d: &[u8]) -> Result<HuffmanEncoding, HuffmanError> {
+        trace!("restoring code table {:?}", d);
+        let mut lengths: HashMap<Symbol, usize> = HashMap::new();
         let mut index: usize = 0;
 
-        // First decode EOT
-        let nbits_eot = *d.get(index).expect("Error deserialising input file");
-        let nbytes_eot = (nbits_eot as f64 / 8.0).ceil() as usize;
-        let bits_eot = &d[index + 1..index + nbytes_eot + 1];
-        let mut eot_bits: BitVec<u8, Lsb0> = BitVec::from_slice(bits_eot);
-        eot_bits.truncate(nbits_eot as usize);
-        encoding.insert(Symbol::EOT, eot_bits);
+        for &b in d {
+            if b & 0x80 != 0 {
+                index += (b & 0x7F) as usize + 1;
+            } else {
+                if index >= ALPHABET_SIZE {
+                    return Err(HuffmanError::BadTable("more lengths than symbols"));
+                }
+                if b > 0 {
+                    lengths.insert(Symbol::from_index(index), b as usize);
+                }
+                index += 1;
+            }
+        }
+
+        HuffmanEncoding::check_lengths(&lengths)?;
+        Ok(HuffmanEncoding::from_lengths(&lengths))
+    }
 
-        index += nbytes_eot + 1;
+    /// Restore a code table saved by press before the container format,
+    /// which stored the codes themselves rather than their lengths: the
+    /// length and bits of the EOT code, then `{ byte, length, bits }` for
+    /// every byte with a code, the bits packed LSB first into whole bytes.
+    pub fn restore_legacy_from(d: &[u8]) -> Result<HuffmanEncoding, HuffmanError> {
+        let mut encoding: HashMap<Symbol, Code> = HashMap::new();
+        let (eot, mut rest) = read_legacy_code(d)?;
+        encoding.insert(Symbol::EOT, eot);
+        while let Some((&c, tail)) = rest.split_first() {
+            let (code, tail) = read_legacy_code(tail)?;
+            if encoding.insert(Symbol::Char(c), code).is_some() {
+                return Err(HuffmanError::BadTable("byte coded twice"));
+            }
+            rest = tail;
+        }
 
-        // Decode rest of symbols
-        loop {
-            let c = *d.get(index).expect("Error deserialising input file");
-            let len = *d.get(index + 1).expect("Error deserialising input file");
-            let size = (len as f64 / 8.0).ceil() as usize;
-            let bits: &[u8] = &d[index + 2..index + size + 2];
-            let mut v: BitVec<u8, Lsb0> = BitVec::from_slice(bits);
-            v.truncate(len as usize);
+        let lengths = encoding.iter().map(|(s, code)| (*s, code.len())).collect();
+        HuffmanEncoding::check_lengths(&lengths)?;
+        // The codes are stored as they are, so unlike canonical codes they
+        // need not be prefix free. Sorted, a code is followed by the codes
+        // it is a prefix of.
+        let mut codes: Vec<&Code> = encoding.values().collect();
+        codes.sort();
+        if codes.windows(2).any(|w| w[1].starts_with(w[0])) {
+            return Err(HuffmanError::BadTable("a code is a prefix of another"));
+        }
+        Ok(HuffmanEncoding {
+            encoding,
+            alphabet: Alphabet::Bytes,
+        })
+    }
+
+    /// Check that code lengths read from a file describe a prefix code, so
+    /// they can be given to `from_lengths`.
+    pub fn check_lengths(lengths: &HashMap<Symbol, usize>) -> Result<(), HuffmanError> {
+        if lengths.values().any(|len| *len > MAX_STORABLE_CODE_LENGTH) {
+            return Err(HuffmanError::BadTable("code length too long"));
+        }
+        // Kraft's inequality: a prefix code can only have lengths whose
+        // codes fit in the code space.
+        let kraft = lengths
+            .values()
+            .filter(|len| **len > 0)
+            .try_fold(0u128, |sum, len| {
+                sum.checked_add(1 << (MAX_STORABLE_CODE_LENGTH - len))
+            });
+        if kraft.is_none_or(|k| k > 1 << MAX_STORABLE_CODE_LENGTH) {
+            return Err(HuffmanError::BadTable(
+                "code lengths overflow the code space",
+            ));
+        }
+        Ok(())
+    }
 
-            v.set_uninitialized(false);
-            encoding.insert(Symbol::Char(c), v);
+    /// Split `input` into symbols as `alphabet` says and code them. With
+    /// `Alphabet::Digrams`, the pairs coded as one symbol are those the table
+    /// has codes for.
+    pub fn encode(&self, input: &[u8]) -> Vec<u8> {
+        self.encode_symbols(&self.symbols(input))
+    }
 
-            index += size + 2;
+    /// Number of bits `encode` would code `input` in, EOT included, or `None`
+    /// if the table has no code for some of its symbols.
+    pub fn encoded_bits(&self, input: &[u8]) -> Option<u64> {
+        self.symbols(input)
+            .iter()
+            .chain([&Symbol::EOT])
+            .try_fold(0u64, |bits, s| {
+                Some(bits + self.encoding.get(s)?.len() as u64)
+            })
+    }
 
-            if index > d.len() - 3 {
-                break;
+    fn symbols(&self, input: &[u8]) -> Vec<Symbol> {
+        match self.alphabet {
+            Alphabet::Digrams => {
+                let mut pairs = vec![false; 1 << 16];
+                for symbol in self.encoding.keys() {
+                    if let Symbol::Word(w) = symbol {
+                        pairs[*w as usize] = true;
+                    }
+                }
+                digram_symbols(input, &pairs)
             }
+            alphabet => alphabet.symbols(input),
         }
-
-        return HuffmanEncoding { encoding: encoding };
     }
 
-    pub fn encode(&self, input: &Vec<u8>) -> Vec<u8> {
+    /// Code `symbols` followed by EOT.
+    pub fn encode_symbols(&self, symbols: &[Symbol]) -> Vec<u8> {
         let mut filestream = bitvec![u8, Lsb0;];
 
-        for c in input {
-            let mut code = self.encoding.get(&Symbol::Char(*c)).unwrap().clone();
+        for s in symbols {
+            let mut code = self.encoding.get(s).unwrap().clone();
             filestream.append(&mut code);
         }
         let mut eot = self.encoding.get(&Symbol::EOT).unwrap().clone();
@@ -252,36 +884,24 @@ impl HuffmanEncoding {
         filestream.into_vec()
     }
 
-    pub fn decode(self, input: &Vec<u8>) -> Vec<u8> {
-        let in_bits: BitVec<u8, Lsb0> = BitVec::from_slice(input);
+    pub fn decode(&self, input: &[u8]) -> Result<Vec<u8>, HuffmanError> {
         let mut out: Vec<u8> = vec![];
-        let mut cursor = 0;
-
-        assert!(self.encoding.contains_key(&Symbol::EOT));
-
-        while cursor < in_bits.len() {
-            for (s, pat) in self.encoding.iter() {
-                let len = pat.len();
-                if cursor + len <= in_bits.len() {
-                    let stream = &in_bits[cursor..cursor + len];
-                    if pat == stream {
-                        match s {
-                            Symbol::Char(c) => {
-                                out.push(*c);
-                                cursor += len;
-                                break;
-                            }
-                            Symbol::EOT => return out,
-                        }
-                    }
-                }
-            }
-        }
-        unreachable!()
+        self.decode_from(&mut &input[..], &mut out)?;
+        Ok(out)
     }
 
-    #[allow(dead_code)]
-    pub fn diff(&self, other: &Self) -> Vec<(Symbol, BitVec<u8, Lsb0>, BitVec<u8, Lsb0>)> {
+    /// Decode a bitstream from `input` until the EOT symbol is reached, writing
+    /// decoded bytes to `output`. Only bytes holding bits of the stream are
+    /// consumed, so `input` is left positioned at whatever follows the stream.
+    pub fn decode_from<R: BufRead, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<(), HuffmanError> {
+        HuffmanDecoder::new(self).decode_from(input, output, usize::MAX)
+    }
+
+    pub fn diff(&self, other: &Self) -> Vec<(Symbol, Code, Code)> {
         let mut diffs = Vec::new();
         for (l, r) in self.encoding.iter() {
             let sr = self.encoding.get(l).unwrap();
@@ -290,34 +910,661 @@ impl HuffmanEncoding {
                 diffs.push((*l, r.clone(), or.clone()))
             }
         }
-        return diffs;
+        diffs
+    }
+}
+
+/// The form `HuffmanEncoding` is serialised in: its alphabet, then the
+/// length and code of every symbol, sorted by length then symbol. Codes are
+/// strings of bits in the order they are written.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "HuffmanEncoding")]
+struct EncodingRepr {
+    alphabet: Alphabet,
+    codes: Vec<CodeRepr>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CodeRepr {
+    symbol: Symbol,
+    length: usize,
+    /// Optional when deserialising, as canonical codes follow from their
+    /// lengths; checked if given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl Serialize for HuffmanEncoding {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut codes: Vec<(&Symbol, &Code)> = self.encoding.iter().collect();
+        codes.sort_by_key(|(symbol, code)| (code.len(), **symbol));
+        EncodingRepr {
+            alphabet: self.alphabet,
+            codes: codes
+                .into_iter()
+                .map(|(symbol, code)| CodeRepr {
+                    symbol: *symbol,
+                    length: code.len(),
+                    code: Some(code.iter().map(|b| if *b { '1' } else { '0' }).collect()),
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HuffmanEncoding {
+    /// Codes are rebuilt from their lengths, which must describe a prefix
+    /// code with a code for EOT, as `restore_from` requires.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let repr = EncodingRepr::deserialize(deserializer)?;
+        let mut lengths: HashMap<Symbol, usize> = HashMap::new();
+        for code in &repr.codes {
+            if code.length == 0 || lengths.insert(code.symbol, code.length).is_some() {
+                return Err(D::Error::custom(format!(
+                    "bad or repeated code length for {:?}",
+                    code.symbol
+                )));
+            }
+        }
+        if !lengths.contains_key(&Symbol::EOT) {
+            return Err(D::Error::custom(HuffmanError::BadTable("no code for EOT")));
+        }
+        HuffmanEncoding::check_lengths(&lengths).map_err(D::Error::custom)?;
+
+        let mut encoding = HuffmanEncoding::from_lengths(&lengths);
+        for code in &repr.codes {
+            let Some(bits) = &code.code else { continue };
+            let expected: String = encoding.encoding[&code.symbol]
+                .iter()
+                .map(|b| if *b { '1' } else { '0' })
+                .collect();
+            if *bits != expected {
+                return Err(D::Error::custom(format!(
+                    "code {} for {:?} is not the canonical code {}",
+                    bits, code.symbol, expected
+                )));
+            }
+        }
+        encoding.alphabet = repr.alphabet;
+        Ok(encoding)
     }
 }
 
-// impl Serialize for HuffmanEncoding {
-//     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-//     where
-//         S: serde::Serializer
-//     {
-//         todo!()
-//     }
-// }
+/// Number of stream bits resolved by a single `HuffmanDecoder` table lookup.
+const LOOKUP_BITS: usize = 11;
+
+/// Table-driven decoder for a `HuffmanEncoding`.
+///
+/// Every code of at most `LOOKUP_BITS` bits is resolved by indexing a table
+/// with the next bits of the stream. Longer codes, and codes straddling the
+/// end of the reader's buffer, fall back to matching one bit at a time.
+pub struct HuffmanDecoder {
+    table: Vec<Option<(Symbol, u8)>>,
+    table_bits: usize,
+    patterns: HashMap<Code, Symbol>,
+    max_len: usize,
+}
+
+impl HuffmanDecoder {
+    pub fn new(encoding: &HuffmanEncoding) -> HuffmanDecoder {
+        let max_len = encoding
+            .encoding
+            .values()
+            .map(|c| c.len())
+            .max()
+            .unwrap_or(0);
+        let table_bits = max_len.min(LOOKUP_BITS);
+        let mut table = vec![None; 1 << table_bits];
+
+        for (symbol, code) in encoding.encoding.iter() {
+            let len = code.len();
+            if len > table_bits {
+                continue;
+            }
+            // Stream bits are packed LSB first, so the first bit of a code is
+            // the lowest bit of the index.
+            let prefix = code
+                .iter()
+                .by_vals()
+                .enumerate()
+                .fold(0, |acc, (i, bit)| acc | ((bit as usize) << i));
+            for suffix in 0..(1 << (table_bits - len)) {
+                table[prefix | (suffix << len)] = Some((*symbol, len as u8));
+            }
+        }
+
+        HuffmanDecoder {
+            table,
+            table_bits,
+            patterns: encoding
+                .encoding
+                .iter()
+                .map(|(s, pat)| (pat.clone(), *s))
+                .collect(),
+            max_len,
+        }
+    }
+
+    /// Decode the next symbol from a bitstream.
+    pub(crate) fn decode_symbol<B: BitSource>(&self, bits: &mut B) -> Result<Symbol, HuffmanError> {
+        let mask = (1 << self.table_bits) - 1;
+        if let Some((symbol, len)) = self.table[bits.peek(self.table_bits)? as usize & mask] {
+            if len as usize <= bits.available() {
+                bits.consume(len as usize);
+                return Ok(symbol);
+            }
+        }
+
+        let mut current: Code = BitVec::new();
+        loop {
+            current.push(bits.read_bit()?);
+            match self.patterns.get(&current) {
+                Some(symbol) => return Ok(*symbol),
+                None if current.len() >= self.max_len => {
+                    return Err(HuffmanError::BadBitstream("unknown code"))
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// See `HuffmanEncoding::decode_from`. Fails as soon as the stream
+    /// holds more than `limit` bytes.
+    pub fn decode_from<R: BufRead, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        limit: usize,
+    ) -> Result<(), HuffmanError> {
+        let mask = (1u64 << self.table_bits) - 1;
+        let mut out: Vec<u8> = Vec::new();
+        let mut written = 0;
 
-// impl<'de> Deserialize<'de> for HuffmanEncoding {
-//     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-//     where
-//         D: serde::Deserializer<'de>
-//     {
-//         todo!()
-//     }
-// }
+        // Bits of the last consumed byte that have not been decoded yet.
+        let mut leftover: u64 = 0;
+        let mut nleftover: usize = 0;
+
+        loop {
+            let buf = input.fill_buf()?;
+            let mut acc = leftover;
+            let mut nacc = nleftover;
+            let mut loaded = 0;
+            let mut done = false;
+
+            loop {
+                while nacc <= 56 && loaded < buf.len() {
+                    acc |= (buf[loaded] as u64) << nacc;
+                    nacc += 8;
+                    loaded += 1;
+                }
+                match self.table[(acc & mask) as usize] {
+                    Some((symbol, len)) if len as usize <= nacc => {
+                        acc >>= len;
+                        nacc -= len as usize;
+                        match symbol {
+                            Symbol::Char(c) => out.push(c),
+                            Symbol::Word(w) => out.extend_from_slice(&w.to_le_bytes()),
+                            Symbol::EOT => {
+                                done = true;
+                                break;
+                            }
+                            _ => return Err(HuffmanError::BadBitstream("unexpected LZ77 code")),
+                        }
+                        if written + out.len() > limit {
+                            return Err(HuffmanError::BadBitstream(
+                                "more data than the block holds",
+                            ));
+                        }
+                    }
+                    _ => break,
+                }
+            }
+
+            // Hand back whole bytes that were loaded but not decoded.
+            input.consume(loaded - nacc / 8);
+            leftover = acc & ((1 << (nacc % 8)) - 1);
+            nleftover = nacc % 8;
+
+            output.write_all(&out)?;
+            written += out.len();
+            out.clear();
+            if done {
+                return Ok(());
+            }
+
+            // Slow path: match the next code one bit at a time, reading a
+            // single byte whenever the leftover bits run out.
+            let mut current: Code = BitVec::new();
+            loop {
+                if nleftover == 0 {
+                    let mut byte = [0u8; 1];
+                    input.read_exact(&mut byte)?;
+                    leftover = byte[0] as u64;
+                    nleftover = 8;
+                }
+                current.push(leftover & 1 == 1);
+                leftover >>= 1;
+                nleftover -= 1;
+
+                match self.patterns.get(&current) {
+                    Some(Symbol::Char(c)) => {
+                        out.push(*c);
+                        break;
+                    }
+                    Some(Symbol::Word(w)) => {
+                        out.extend_from_slice(&w.to_le_bytes());
+                        break;
+                    }
+                    Some(Symbol::EOT) => return Ok(()),
+                    Some(_) => return Err(HuffmanError::BadBitstream("unexpected LZ77 code")),
+                    None if current.len() >= self.max_len => {
+                        return Err(HuffmanError::BadBitstream("unknown code"))
+                    }
+                    None => {}
+                }
+            }
+            if written + out.len() > limit {
+                return Err(HuffmanError::BadBitstream("more data than the block holds"));
+            }
+            output.write_all(&out)?;
+            written += out.len();
+            out.clear();
+        }
+    }
+}
 
 impl std::fmt::Debug for HuffmanEncoding {
+    /// One line per symbol, in canonical order: the symbol, the length of
+    /// its code and the code.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut codes: Vec<(&Symbol, &Code)> = self.encoding.iter().collect();
+        codes.sort_by_key(|(symbol, code)| (code.len(), **symbol));
+
         writeln!(f, "(")?;
-        for (c, v) in self.encoding.iter() {
-            write!(f, "[{:02X?}]  {}\n", *c, v)?
+        for (symbol, code) in codes {
+            let bits: String = code
+                .iter()
+                .map(|bit| if *bit { '1' } else { '0' })
+                .collect();
+            let symbol = format!("{:?}", symbol);
+            writeln!(f, "  {:<8} {:>3}  {}", symbol, code.len(), bits)?
         }
         write!(f, ")")
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Fibonacci frequencies for `symbols`, which give the most unbalanced
+    /// tree possible.
+    fn fibonacci_frequencies(symbols: &[u8]) -> HashMap<Symbol, u64> {
+        let mut frequencies: HashMap<Symbol, u64> = HashMap::new();
+        let (mut a, mut b) = (1u64, 1u64);
+        frequencies.insert(Symbol::EOT, 1);
+        for c in symbols {
+            frequencies.insert(Symbol::Char(*c), a);
+            (a, b) = (b, a + b);
+        }
+        frequencies
+    }
+
+    #[test]
+    fn encoding_will_always_have_eot() {
+        let in_buf: Vec<u8> = b"".to_vec();
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+
+        assert!(encoding.encoding.contains_key(&Symbol::EOT));
+    }
+
+    #[test]
+    fn decoded_table_has_eot() {
+        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
+
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+        let saved = encoding.save();
+        let restored = HuffmanEncoding::restore_from(&saved).unwrap();
+
+        assert!(restored.encoding.contains_key(&Symbol::EOT));
+    }
+
+    #[test]
+    fn test_encoding_serialise_deserialise() {
+        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
+
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+        let saved = encoding.save();
+        let restored = HuffmanEncoding::restore_from(&saved).unwrap();
+
+        assert_eq!(encoding, restored);
+    }
+
+    #[test]
+    fn can_decode_codes_longer_than_lookup_table() {
+        let symbols: Vec<u8> = (0..30).collect();
+        let encoding =
+            HuffmanEncoding::from_frequencies_limited(fibonacci_frequencies(&symbols), 32).unwrap();
+        let in_buf: Vec<u8> = (0..30u8).chain((0..30u8).rev()).collect();
+
+        assert!(encoding.encoding.values().any(|code| code.len() > 16));
+        assert_eq!(encoding.decode(&encoding.encode(&in_buf)).unwrap(), in_buf);
+    }
+
+    #[test]
+    fn rejects_code_lengths_that_cannot_be_met() {
+        let symbols: Vec<u8> = (0..30).collect();
+        for max_len in [4, MAX_STORABLE_CODE_LENGTH + 1] {
+            let result =
+                HuffmanEncoding::from_frequencies_limited(fibonacci_frequencies(&symbols), max_len);
+            assert!(matches!(
+                result,
+                Err(HuffmanError::BadCodeLength { symbols: 31, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn codes_are_canonical() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-10kB.txt").unwrap();
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+
+        let mut codes: Vec<(usize, Symbol, u128)> = encoding
+            .encoding
+            .iter()
+            .map(|(s, code)| {
+                let value = code
+                    .iter()
+                    .by_vals()
+                    .fold(0, |acc, b| (acc << 1) | b as u128);
+                (code.len(), *s, value)
+            })
+            .collect();
+        codes.sort();
+
+        for pair in codes.windows(2) {
+            let (len, _, value) = pair[0];
+            let (next_len, _, next_value) = pair[1];
+            assert_eq!((value + 1) << (next_len - len), next_value);
+        }
+    }
+
+    #[test]
+    fn code_table_only_stores_lengths() {
+        let in_buf: Vec<u8> = std::fs::read("tests/les-miserables.txt").unwrap();
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+
+        assert!(encoding.save().len() <= ALPHABET_SIZE);
+    }
+
+    #[test]
+    fn byte_tables_leave_out_unused_symbols_at_the_end() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-1kB.txt").unwrap();
+        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        let every_byte = HuffmanEncoding::from_data_vec(&all_bytes);
+
+        assert!(encoding.save().len() <= 300);
+        // A length for every byte and EOT, and nothing after.
+        assert_eq!(every_byte.save().len(), 257);
+        assert!(HuffmanEncoding::restore_from(&encoding.save()).unwrap() == encoding);
+    }
+
+    #[test]
+    fn can_restore_legacy_table() {
+        // EOT is 00, 'a' is 1 and 'b' is 01, each packed LSB first.
+        let table = [2, 0b00, b'a', 1, 0b1, b'b', 2, 0b10];
+        let encoding = HuffmanEncoding::restore_legacy_from(&table).unwrap();
+
+        assert_eq!(encoding.decode(&[0b001101]).unwrap(), b"aba");
+        assert!(encoding.decode(&[0b001101, 0xFF]).is_ok());
+    }
+
+    #[test]
+    fn rejects_corrupt_legacy_tables() {
+        for table in [
+            &[][..],
+            &[2, 0b00, b'a', 9, 0b1][..],
+            &[2, 0b00, b'a', 1, 0b1, b'a', 2, 0b10][..],
+            &[2, 0b00, b'a', 1, 0b1, b'b', 3, 0b101][..],
+        ] {
+            assert!(matches!(
+                HuffmanEncoding::restore_legacy_from(table),
+                Err(HuffmanError::BadTable(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_table_without_eot() {
+        let result = HuffmanEncoding::restore_from(&[1, 1]);
+        assert!(matches!(result, Err(HuffmanError::BadTable(_))));
+    }
+
+    #[test]
+    fn rejects_table_with_too_many_lengths() {
+        let mut table = vec![0xFF; ALPHABET_SIZE / 128 + 1];
+        table.extend_from_slice(&[1, 1]);
+        let result = HuffmanEncoding::restore_from(&table);
+        assert!(matches!(result, Err(HuffmanError::BadTable(_))));
+    }
+
+    #[test]
+    fn rejects_oversubscribed_table() {
+        // Three one-bit codes: bytes 0 and 1, then 254 unused bytes, then EOT.
+        let result = HuffmanEncoding::restore_from(&[1, 1, 0xFF, 0xFD, 1]);
+        assert!(matches!(result, Err(HuffmanError::BadTable(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_code_in_bitstream() {
+        // Only EOT is coded, as "0", so a leading 1 bit matches nothing.
+        let encoding = HuffmanEncoding::restore_from(&[0xFF, 0xFF, 1]).unwrap();
+
+        let result = encoding.decode(&[0xFF]);
+        assert!(matches!(result, Err(HuffmanError::BadBitstream(_))));
+    }
+
+    #[test]
+    fn default_codes_are_length_limited() {
+        let symbols: Vec<u8> = (0..60).collect();
+        let encoding = HuffmanEncoding::from_frequencies(fibonacci_frequencies(&symbols));
+
+        assert!(encoding
+            .encoding
+            .values()
+            .all(|code| code.len() <= MAX_CODE_LENGTH));
+        assert_eq!(
+            encoding,
+            HuffmanEncoding::restore_from(&encoding.save()).unwrap()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn limited_codes_are_complete_and_within_limit(
+            symbols in Just((0..=255u8).collect::<Vec<u8>>())
+                .prop_shuffle()
+                .prop_flat_map(|s| (1..90usize).prop_map(move |n| s[..n].to_vec())),
+            slack in 0..16usize,
+        ) {
+            let frequencies = fibonacci_frequencies(&symbols);
+            let min_len = (usize::BITS - (frequencies.len() - 1).leading_zeros()) as usize;
+            let max_len = min_len + slack;
+
+            let tree = HuffmanTree::from_frequencies_limited(&frequencies, max_len).unwrap();
+            prop_assert!(tree.depth() <= max_len);
+
+            let encoding = HuffmanEncoding::from_tree(&tree);
+            prop_assert!(encoding.encoding.values().all(|code| code.len() <= max_len));
+            let kraft: u128 = encoding
+                .encoding
+                .values()
+                .map(|code| 1u128 << (max_len - code.len()))
+                .sum();
+            prop_assert_eq!(kraft, 1u128 << max_len);
+
+            let in_buf: Vec<u8> = symbols.iter().chain(symbols.iter().rev()).copied().collect();
+            prop_assert_eq!(encoding.decode(&encoding.encode(&in_buf)).unwrap(), in_buf);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn restore_from_rejects_arbitrary_tables_without_panicking(
+            d in prop::collection::vec(any::<u8>(), 0..1024),
+        ) {
+            if let Ok(encoding) = HuffmanEncoding::restore_from(&d) {
+                let _ = encoding.decode(&d);
+            }
+        }
+
+        #[test]
+        fn restore_from_rejects_corrupt_tables_without_panicking(
+            in_buf in prop::collection::vec(any::<u8>(), 0..2048),
+            corruption in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+            keep in any::<prop::sample::Index>(),
+        ) {
+            let mut d = HuffmanEncoding::from_data_vec(&in_buf).save();
+            for (at, byte) in corruption {
+                let at = at.index(d.len());
+                d[at] ^= byte;
+            }
+            d.truncate(keep.index(d.len() + 1));
+
+            if let Ok(encoding) = HuffmanEncoding::restore_from(&d) {
+                let _ = encoding.decode(&d);
+            }
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips_through_json_and_cbor() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        for alphabet in [Alphabet::Bytes, Alphabet::Words, Alphabet::Digrams] {
+            let encoding =
+                HuffmanEncoding::from_data_vec_with(&text, alphabet, MAX_CODE_LENGTH).unwrap();
+
+            let json = serde_json::to_string(&encoding).unwrap();
+            let restored: HuffmanEncoding = serde_json::from_str(&json).unwrap();
+            assert!(restored == encoding);
+            assert_eq!(restored.alphabet, alphabet);
+
+            let mut cbor = Vec::new();
+            ciborium::into_writer(&encoding, &mut cbor).unwrap();
+            let restored: HuffmanEncoding = ciborium::from_reader(&cbor[..]).unwrap();
+            assert!(restored == encoding);
+        }
+    }
+
+    #[test]
+    fn serialises_encoding_as_lengths_and_codes() {
+        let encoding = HuffmanEncoding::from_data_vec(b"aab");
+
+        assert_eq!(
+            serde_json::to_value(&encoding).unwrap(),
+            serde_json::json!({
+                "alphabet": "bytes",
+                "codes": [
+                    {"symbol": {"char": 97}, "length": 1, "code": "0"},
+                    {"symbol": {"char": 98}, "length": 2, "code": "10"},
+                    {"symbol": "eot", "length": 2, "code": "11"},
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn deserialising_checks_the_code_table() {
+        let parse = |json| serde_json::from_str::<HuffmanEncoding>(json);
+
+        let lengths_only = r#"{"alphabet": "bytes", "codes": [
+            {"symbol": "eot", "length": 1}, {"symbol": {"char": 97}, "length": 1}]}"#;
+        assert_eq!(parse(lengths_only).unwrap().decode(&[0b10]).unwrap(), b"a");
+
+        let no_eot = r#"{"alphabet": "bytes", "codes": [{"symbol": {"char": 97}, "length": 1}]}"#;
+        assert!(parse(no_eot).is_err());
+        let too_many = r#"{"alphabet": "bytes", "codes": [{"symbol": "eot", "length": 1},
+            {"symbol": {"char": 97}, "length": 1}, {"symbol": {"char": 98}, "length": 1}]}"#;
+        assert!(parse(too_many).is_err());
+        let wrong_code = r#"{"alphabet": "bytes", "codes": [
+            {"symbol": "eot", "length": 1, "code": "0"}, {"symbol": {"char": 97}, "length": 1}]}"#;
+        assert!(parse(wrong_code).is_err());
+    }
+
+    #[test]
+    fn tree_round_trips_through_json() {
+        let tree =
+            HuffmanTree::from_frequencies(&frequency_map(&Alphabet::Bytes.symbols(b"abracadabra")));
+
+        let json = serde_json::to_value(&tree).unwrap();
+        assert_eq!(json["kind"], "node");
+        assert_eq!(json["freq"], 12);
+
+        let restored: HuffmanTree = serde_json::from_value(json).unwrap();
+        assert!(HuffmanEncoding::from_tree(&restored) == HuffmanEncoding::from_tree(&tree));
+    }
+
+    #[test]
+    fn can_encode_decode_words() {
+        let text = std::fs::read_to_string("tests/test-10kB.txt").unwrap();
+        let utf16: Vec<u8> = text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+
+        let words =
+            HuffmanEncoding::from_data_vec_with(&utf16, Alphabet::Words, MAX_CODE_LENGTH).unwrap();
+        let bytes = HuffmanEncoding::from_data_vec(&utf16);
+        let encoded = words.encode(&utf16);
+
+        assert_eq!(words.decode(&encoded).unwrap(), utf16);
+        assert!(encoded.len() + words.save().len() < bytes.encode(&utf16).len());
+
+        let odd = &utf16[..101];
+        let words =
+            HuffmanEncoding::from_data_vec_with(odd, Alphabet::Words, MAX_CODE_LENGTH).unwrap();
+        assert_eq!(words.decode(&words.encode(odd)).unwrap(), odd);
+    }
+
+    #[test]
+    fn gives_every_word_a_code() {
+        // More distinct words than 12-bit codes can tell apart.
+        let in_buf: Vec<u8> = (0..5000u16).flat_map(|w| w.to_le_bytes()).collect();
+        let encoding = HuffmanEncoding::from_data_vec_with(&in_buf, Alphabet::Words, 12).unwrap();
+
+        assert_eq!(encoding.decode(&encoding.encode(&in_buf)).unwrap(), in_buf);
+    }
+
+    #[test]
+    fn can_encode_decode_digrams() {
+        let in_buf: Vec<u8> = std::fs::read("tests/test-100kB.txt").unwrap();
+        let digrams =
+            HuffmanEncoding::from_data_vec_with(&in_buf, Alphabet::Digrams, MAX_CODE_LENGTH)
+                .unwrap();
+        let bytes = HuffmanEncoding::from_data_vec(&in_buf);
+        let encoded = digrams.encode(&in_buf);
+
+        assert_eq!(digrams.decode(&encoded).unwrap(), in_buf);
+        assert!(encoded.len() + digrams.save().len() < bytes.encode(&in_buf).len());
+
+        // The restored table codes the same pairs.
+        let restored = HuffmanEncoding::restore_from(&digrams.save()).unwrap();
+        assert_eq!(restored.encode(&in_buf), encoded);
+    }
+
+    #[test]
+    fn digram_pairs_need_enough_occurrences() {
+        let symbols = Alphabet::Digrams.symbols(b"abcdefgh");
+        assert!(symbols.iter().all(|s| matches!(s, Symbol::Char(_))));
+
+        let symbols = Alphabet::Digrams.symbols(&b"ab".repeat(100));
+        assert_eq!(symbols, vec![Symbol::Word(u16::from_le_bytes(*b"ab")); 100]);
+    }
+}
@@ -0,0 +1,401 @@
+//! Reports on how data compresses and where the bytes of a press file go,
+//! for `press inspect`.
+
+use crate::huffman::{HuffmanEncoding, HuffmanError, HuffmanTree, Symbol};
+use crate::{crypto, lz77, Decoder, DecompressOptions, Method, Transforms};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// How well the bytes of some data can be Huffman coded.
+pub struct Analysis {
+    size: u64,
+    frequencies: HashMap<Symbol, u64>,
+    tree: HuffmanTree,
+    encoding: HuffmanEncoding,
+}
+
+impl Analysis {
+    /// Count the bytes of `data` and build the Huffman tree the `huffman`
+    /// method would code it with as a single block.
+    pub fn of(data: &[u8], max_code_length: usize) -> Result<Analysis, HuffmanError> {
+        let mut counts = [0u64; 256];
+        for c in data {
+            counts[*c as usize] += 1;
+        }
+        let mut frequencies: HashMap<Symbol, u64> = (0..=255u8)
+            .filter(|c| counts[*c as usize] > 0)
+            .map(|c| (Symbol::Char(c), counts[c as usize]))
+            .collect();
+        frequencies.insert(Symbol::EOT, 1);
+
+        let tree = HuffmanTree::from_frequencies_limited(&frequencies, max_code_length)?;
+        let encoding = HuffmanEncoding::from_tree(&tree);
+        Ok(Analysis {
+            size: data.len() as u64,
+            frequencies,
+            tree,
+            encoding,
+        })
+    }
+
+    pub fn tree(&self) -> &HuffmanTree {
+        &self.tree
+    }
+
+    pub fn encoding(&self) -> &HuffmanEncoding {
+        &self.encoding
+    }
+
+    /// Shannon entropy of the bytes, in bits per byte: the fewest bits any
+    /// code that looks at bytes one at a time could average.
+    pub fn entropy(&self) -> f64 {
+        let size = self.size as f64;
+        self.byte_counts()
+            .map(|(_, count)| {
+                let p = count as f64 / size;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Average length of the Huffman codes of the bytes, in bits per byte.
+    pub fn average_code_length(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        let bits: u64 = self
+            .byte_counts()
+            .map(|(symbol, count)| count * self.encoding.encoding[&symbol].len() as u64)
+            .sum();
+        bits as f64 / self.size as f64
+    }
+
+    fn byte_counts(&self) -> impl Iterator<Item = (Symbol, u64)> + '_ {
+        self.frequencies
+            .iter()
+            .filter(|(symbol, _)| **symbol != Symbol::EOT)
+            .map(|(symbol, count)| (*symbol, *count))
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (entropy, average) = (self.entropy(), self.average_code_length());
+        writeln!(f, "Size:             {} bytes", self.size)?;
+        writeln!(f, "Distinct bytes:   {}", self.frequencies.len() - 1)?;
+        writeln!(f, "Entropy:          {:.4} bits/byte", entropy)?;
+        write!(f, "Average code:     {:.4} bits/byte", average)?;
+        if average > 0.0 {
+            write!(f, ", {:.1}% of the entropy", 100.0 * entropy / average)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Tree depth:       {}", self.tree.depth())?;
+
+        writeln!(f, "\nCodes by length:")?;
+        let mut by_length: HashMap<usize, usize> = HashMap::new();
+        for code in self.encoding.encoding.values() {
+            *by_length.entry(code.len()).or_insert(0) += 1;
+        }
+        let mut lengths: Vec<_> = by_length.into_iter().collect();
+        lengths.sort();
+        for (len, symbols) in lengths {
+            writeln!(
+                f,
+                "  {:>3} bits  {:>3}  {}",
+                len,
+                symbols,
+                "#".repeat(symbols)
+            )?;
+        }
+
+        writeln!(f, "\nSymbols:")?;
+        writeln!(f, "  symbol        count        p   -log2 p  bits")?;
+        let mut symbols: Vec<_> = self.frequencies.iter().collect();
+        symbols.sort_by_key(|(symbol, count)| (std::cmp::Reverse(**count), **symbol));
+        for (symbol, count) in symbols {
+            let p = *count as f64 / self.size.max(1) as f64;
+            writeln!(
+                f,
+                "  {:<8} {:>10}  {:.5}  {:>7.3}  {:>4}",
+                format!("{:?}", symbol),
+                count,
+                p,
+                -p.log2(),
+                self.encoding.encoding[symbol].len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the bytes of one block go.
+pub struct BlockLayout {
+    pub original_len: u64,
+    pub payload_len: u64,
+    /// Bytes of the code tables at the start of the payload.
+    pub table_len: u64,
+    /// The code table of the block, for the methods that store one.
+    pub table: Option<HuffmanEncoding>,
+    /// Whether the block is coded with the table of a dictionary.
+    pub uses_dictionary: bool,
+}
+
+/// Where the bytes of a press file go.
+pub struct Layout {
+    pub method: Method,
+    /// Transforms the blocks went through before they were coded.
+    pub transforms: Transforms,
+    /// Bytes of the header, from the magic number on.
+    pub header_len: u64,
+    pub blocks: Vec<BlockLayout>,
+    /// Bytes of the end marker, the footer and any block index.
+    pub trailer_len: u64,
+    /// Bytes of the authentication tags, if the file is encrypted.
+    pub tags_len: Option<u64>,
+}
+
+/// Bytes in front of each block for its lengths.
+const BLOCK_HEADER_LEN: u64 = 8;
+
+impl Layout {
+    /// Read the layout of the press file `file`, restoring the code tables
+    /// of its blocks.
+    pub fn read(file: &[u8], options: DecompressOptions) -> Result<Layout, HuffmanError> {
+        let mut rest = file;
+        let mut decoder = Decoder::with_options(&mut rest, options);
+        decoder.read_header()?;
+        if decoder.is_unversioned() {
+            return Err(HuffmanError::UnsupportedVersion(0));
+        }
+        let header_len = (file.len() - decoder.get_ref().len()) as u64;
+        let (method, transforms) = (decoder.method(), decoder.transforms());
+
+        let mut blocks = Vec::new();
+        while let Some((original_len, payload)) = decoder.read_block_payload()? {
+            let mut block = BlockLayout {
+                original_len,
+                payload_len: payload.len() as u64,
+                table_len: 0,
+                table: None,
+                uses_dictionary: false,
+            };
+            match method {
+                Method::Huffman => {
+                    let (table, _) = lz77::read_table(&payload)?;
+                    block.table_len = 4 + table.len() as u64;
+                    block.uses_dictionary = table.is_empty() && decoder.dictionary().is_some();
+                    block.table = Some(match decoder.dictionary() {
+                        Some(dictionary) if table.is_empty() => dictionary.encoding().clone(),
+                        _ => HuffmanEncoding::restore_from(table)?,
+                    });
+                }
+                Method::Range => {
+                    let (table, _) = lz77::read_table(&payload)?;
+                    block.table_len = 4 + table.len() as u64;
+                }
+                Method::Lzh => {
+                    let (litlen, rest) = lz77::read_table(&payload)?;
+                    let (dist, _) = lz77::read_table(rest)?;
+                    block.table_len = 8 + (litlen.len() + dist.len()) as u64;
+                    block.table = Some(HuffmanEncoding::restore_from(litlen)?);
+                }
+                Method::Adaptive | Method::Stored => {}
+            }
+            blocks.push(block);
+        }
+        let blocks_len: u64 = blocks
+            .iter()
+            .map(|block| BLOCK_HEADER_LEN + block.payload_len)
+            .sum();
+        let tags_len = decoder
+            .is_encrypted()
+            .then(|| crypto::tags_len(file.len() as u64 - header_len));
+        let trailer_len = file.len() as u64 - header_len - blocks_len - tags_len.unwrap_or(0);
+
+        Ok(Layout {
+            method,
+            transforms,
+            header_len,
+            blocks,
+            trailer_len,
+            tags_len,
+        })
+    }
+
+    pub fn original_size(&self) -> u64 {
+        self.blocks.iter().map(|block| block.original_len).sum()
+    }
+
+    pub fn compressed_size(&self) -> u64 {
+        let blocks: u64 = self
+            .blocks
+            .iter()
+            .map(|block| BLOCK_HEADER_LEN + block.payload_len)
+            .sum();
+        self.header_len + blocks + self.trailer_len + self.tags_len.unwrap_or(0)
+    }
+
+    /// Bytes that are not coded data: the header, block headers, code
+    /// tables, footer and authentication tags.
+    pub fn overhead(&self) -> u64 {
+        let tables: u64 = self.blocks.iter().map(|block| block.table_len).sum();
+        self.header_len
+            + BLOCK_HEADER_LEN * self.blocks.len() as u64
+            + tables
+            + self.trailer_len
+            + self.tags_len.unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (original, compressed) = (self.original_size(), self.compressed_size());
+        let block_headers = BLOCK_HEADER_LEN * self.blocks.len() as u64;
+        let tables: u64 = self.blocks.iter().map(|block| block.table_len).sum();
+        let payload: u64 = self.blocks.iter().map(|block| block.payload_len).sum();
+        let overhead = self.overhead();
+        let percent = |n: u64| 100.0 * n as f64 / compressed.max(1) as f64;
+
+        writeln!(f, "Method:           {}", self.method)?;
+        if !self.transforms.is_empty() {
+            writeln!(f, "Transforms:       {}", self.transforms)?;
+        }
+        if self.tags_len.is_some() {
+            writeln!(f, "Encryption:       XChaCha20-Poly1305, Argon2id key")?;
+        }
+        writeln!(f, "Original size:    {} bytes", original)?;
+        write!(f, "Compressed size:  {} bytes", compressed)?;
+        if original > 0 {
+            let ratio = 100.0 * compressed as f64 / original as f64;
+            write!(f, ", {:.1}% of the original", ratio)?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "  header          {:>10}  {:>5.1}%",
+            self.header_len,
+            percent(self.header_len)
+        )?;
+        writeln!(
+            f,
+            "  block headers   {:>10}  {:>5.1}%",
+            block_headers,
+            percent(block_headers)
+        )?;
+        writeln!(
+            f,
+            "  code tables     {:>10}  {:>5.1}%",
+            tables,
+            percent(tables)
+        )?;
+        writeln!(
+            f,
+            "  coded data      {:>10}  {:>5.1}%",
+            payload - tables,
+            percent(payload - tables)
+        )?;
+        writeln!(
+            f,
+            "  footer          {:>10}  {:>5.1}%",
+            self.trailer_len,
+            percent(self.trailer_len)
+        )?;
+        if let Some(tags_len) = self.tags_len {
+            writeln!(
+                f,
+                "  auth tags       {:>10}  {:>5.1}%",
+                tags_len,
+                percent(tags_len)
+            )?;
+        }
+        writeln!(
+            f,
+            "Overhead:         {} bytes, {:.1}%",
+            overhead,
+            percent(overhead)
+        )?;
+
+        writeln!(f, "\nBlocks:")?;
+        writeln!(f, "  block    original  compressed   table")?;
+        for (i, block) in self.blocks.iter().enumerate() {
+            write!(
+                f,
+                "  {:>5}  {:>10}  {:>10}  {:>6}",
+                i + 1,
+                block.original_len,
+                BLOCK_HEADER_LEN + block.payload_len,
+                block.table_len
+            )?;
+            if block.uses_dictionary {
+                write!(f, "  dictionary")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{compress_with, CompressOptions};
+
+    #[test]
+    fn entropy_of_uniform_bytes() {
+        let data: Vec<u8> = (0..=255).cycle().take(256 * 100).collect();
+        let analysis = Analysis::of(&data, 15).unwrap();
+
+        assert!((analysis.entropy() - 8.0).abs() < 1e-9);
+        // EOT takes a code too, so one byte gets a 9-bit code.
+        assert!(analysis.average_code_length() > 8.0);
+        assert!(analysis.average_code_length() < 8.01);
+    }
+
+    #[test]
+    fn average_code_is_close_to_entropy() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let analysis = Analysis::of(&text, 15).unwrap();
+
+        assert!(analysis.average_code_length() >= analysis.entropy());
+        assert!(analysis.average_code_length() < analysis.entropy() + 1.0);
+    }
+
+    #[test]
+    fn layout_accounts_for_every_byte() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        for method in [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ] {
+            let options = CompressOptions {
+                block_size: 4000,
+                method,
+                index: true,
+                ..CompressOptions::default()
+            };
+            let mut compressed = Vec::new();
+            compress_with(&text[..], &mut compressed, &options).unwrap();
+
+            let layout = Layout::read(&compressed, DecompressOptions::default()).unwrap();
+            assert_eq!(layout.blocks.len(), 3);
+            assert_eq!(layout.original_size(), text.len() as u64);
+            assert_eq!(layout.compressed_size(), compressed.len() as u64);
+        }
+    }
+
+    #[test]
+    fn layout_restores_tables() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed = Vec::new();
+        crate::compress(&text[..], &mut compressed).unwrap();
+
+        let layout = Layout::read(&compressed, DecompressOptions::default()).unwrap();
+        let table = layout.blocks[0].table.as_ref().unwrap();
+        assert!(*table == HuffmanEncoding::from_data_vec(&text));
+    }
+}
@@ -0,0 +1,574 @@
+//! `press` compresses data with Huffman coding.
+//!
+//! [`compress`] and [`decompress`] stream between a reader and a writer.
+//! [`Encoder`] and [`Decoder`] wrap a writer or reader so press files can be
+//! produced and consumed with the `std::io` traits:
+//!
+//! ```
+//! use std::io::{Read, Write};
+//!
+//! let mut encoder = press::Encoder::new(Vec::new());
+//! encoder.write_all(b"Hello World").unwrap();
+//! let compressed = encoder.finish().unwrap();
+//!
+//! let mut decoded = Vec::new();
+//! press::Decoder::new(&compressed[..])
+//!     .read_to_end(&mut decoded)
+//!     .unwrap();
+//! assert_eq!(decoded, b"Hello World");
+//! ```
+
+mod adaptive;
+pub mod archive;
+pub mod bench;
+mod bits;
+mod container;
+mod crypto;
+mod deflate;
+pub mod dictionary;
+mod entropy;
+pub mod gzip;
+pub mod huffman;
+pub mod inspect;
+mod lz77;
+pub mod range;
+mod seekable;
+mod transform;
+mod workers;
+
+pub use container::{Decoder, Encoder};
+pub use crypto::Key;
+pub use dictionary::Dictionary;
+pub use entropy::EntropyCoder;
+pub use huffman::{Alphabet, HuffmanEncoding, HuffmanError};
+pub use seekable::IndexedReader;
+pub use transform::{Transform, Transforms};
+
+use std::io::{BufRead, Read, Write};
+
+/// Amount of input read and encoded at a time. Each block carries its own
+/// code table, so memory use is bounded by the block size rather than the
+/// input size.
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// How blocks are compressed. Recorded in the file header, so the decoder
+/// does not need to be told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Method {
+    /// Huffman coding of the bytes of each block.
+    #[default]
+    Huffman = 0,
+    /// LZ77 matching of repeated strings, then Huffman coding of the
+    /// literals, lengths and distances, as in DEFLATE.
+    Lzh = 1,
+    /// Adaptive Huffman coding, updating the codes after every byte so that
+    /// no code table is stored.
+    Adaptive = 2,
+    /// Range coding with adaptive frequency counts, which codes skewed data
+    /// more tightly than whole-bit Huffman codes.
+    Range = 3,
+    /// The bytes as they are, for data that no method makes smaller.
+    Stored = 4,
+}
+
+impl Method {
+    pub fn from_u8(b: u8) -> Option<Method> {
+        match b {
+            0 => Some(Method::Huffman),
+            1 => Some(Method::Lzh),
+            2 => Some(Method::Adaptive),
+            3 => Some(Method::Range),
+            4 => Some(Method::Stored),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "huffman" => Ok(Method::Huffman),
+            "lzh" => Ok(Method::Lzh),
+            "adaptive" => Ok(Method::Adaptive),
+            "range" => Ok(Method::Range),
+            "stored" => Ok(Method::Stored),
+            _ => Err(format!("unknown method '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Method::Huffman => "huffman",
+            Method::Lzh => "lzh",
+            Method::Adaptive => "adaptive",
+            Method::Range => "range",
+            Method::Stored => "stored",
+        })
+    }
+}
+
+/// Settings for `compress_with` and `Encoder::with_options`.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressOptions {
+    pub block_size: usize,
+    pub max_code_length: usize,
+    pub method: Method,
+    /// Context order of the range coder, up to `range::MAX_ORDER`.
+    pub context_order: u8,
+    /// Symbols the Huffman method codes.
+    pub alphabet: Alphabet,
+    /// Blocks compressed at a time, on as many worker threads. The output
+    /// is the same whatever the number of threads.
+    pub threads: usize,
+    /// Write an index of the blocks at the end of the file, so that
+    /// `IndexedReader` can decompress any part of it without decompressing
+    /// what comes before. Not written with the adaptive method.
+    pub index: bool,
+    /// Transforms each block goes through before it is coded.
+    pub transforms: Transforms,
+    /// Earlier positions the `lzh` method and gzip try when looking for a
+    /// match. Longer chains find longer matches, more slowly.
+    pub max_chain: usize,
+    /// Key to encrypt the compressed data with, as described in the
+    /// `crypto` module. Encrypted files get no block index.
+    pub encryption: Option<Key>,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            block_size: BLOCK_SIZE,
+            max_code_length: huffman::MAX_CODE_LENGTH,
+            method: Method::default(),
+            context_order: 0,
+            alphabet: Alphabet::Bytes,
+            threads: 1,
+            index: false,
+            transforms: Transforms::default(),
+            max_chain: lz77::MAX_CHAIN,
+            encryption: None,
+        }
+    }
+}
+
+impl CompressOptions {
+    /// Settings for a gzip-style compression level from 1, fastest, to 9,
+    /// smallest. Levels outside that range are clamped to it.
+    ///
+    /// Level 1 range codes with an order 1 context, levels 2 to 7 use the
+    /// `lzh` method with ever longer match chains, and levels 8 and 9 range
+    /// code after `bwt,mtf,rle`, which is several times slower. Level 8
+    /// sorts blocks of 256KiB, level 9 the whole 1MB block, which is slower
+    /// again but finds more of the repeats. The match chains grow with the
+    /// level throughout, for gzip.
+    pub fn level(level: u8) -> CompressOptions {
+        let level = level.clamp(1, 9);
+        let lzh = CompressOptions {
+            method: Method::Lzh,
+            max_chain: [4, 8, 16, 32, 64, lz77::MAX_CHAIN, 4096, 4096, 4096][level as usize - 1],
+            ..CompressOptions::default()
+        };
+        let range = |context_order, transforms: &[Transform]| CompressOptions {
+            method: Method::Range,
+            context_order,
+            transforms: Transforms::new(transforms).unwrap(),
+            ..lzh
+        };
+        let bwt = [Transform::Bwt, Transform::Mtf, Transform::Rle];
+        match level {
+            1 => range(1, &[]),
+            2..=7 => lzh,
+            8 => CompressOptions {
+                block_size: BLOCK_SIZE / 4,
+                ..range(0, &bwt)
+            },
+            _ => range(0, &bwt),
+        }
+    }
+}
+
+/// Settings for `decompress_with` and `Decoder::with_options`.
+#[derive(Clone)]
+pub struct DecompressOptions {
+    /// Blocks decompressed at a time, on as many worker threads.
+    pub threads: usize,
+    /// Dictionary the input may have been compressed with.
+    pub dictionary: Option<Dictionary>,
+    /// Passphrase the input may have been encrypted with.
+    pub passphrase: Option<Vec<u8>>,
+}
+
+impl Default for DecompressOptions {
+    fn default() -> Self {
+        DecompressOptions {
+            threads: 1,
+            dictionary: None,
+            passphrase: None,
+        }
+    }
+}
+
+pub fn compress<R: Read, W: Write>(input: R, output: W) -> Result<(), HuffmanError> {
+    compress_with(input, output, &CompressOptions::default())
+}
+
+/// Compress `input` to `output` one block at a time.
+pub fn compress_with<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    options: &CompressOptions,
+) -> Result<(), HuffmanError> {
+    let mut encoder = Encoder::with_options(output, *options);
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Bytes at the start of the input `compress_auto` tries each method on.
+const AUTO_SAMPLE_SIZE: u64 = 1 << 18;
+
+/// Methods `compress_auto` tries, with their context orders. On a tie the
+/// earlier one is taken.
+const AUTO_METHODS: [(Method, u8); 5] = [
+    (Method::Huffman, 0),
+    (Method::Range, 0),
+    (Method::Range, 1),
+    (Method::Lzh, 0),
+    (Method::Adaptive, 0),
+];
+
+/// Compress `input` to `output` with whichever method compresses the start
+/// of it smallest, keeping the other settings of `options`. When no method
+/// comes out smaller than storing the start as it is, the input is stored
+/// with `Method::Stored`. Only the start is tried, so input that gets harder
+/// to compress further on can still come out larger than stored. Returns the
+/// settings used.
+pub fn compress_auto<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    options: &CompressOptions,
+) -> Result<CompressOptions, HuffmanError> {
+    let mut sample = Vec::new();
+    input
+        .by_ref()
+        .take(AUTO_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+
+    let stored = CompressOptions {
+        method: Method::Stored,
+        context_order: 0,
+        ..*options
+    };
+    let mut stored_buf = Vec::new();
+    compress_with(&sample[..], &mut stored_buf, &stored)?;
+
+    let mut best = (stored_buf.len(), stored);
+    for (method, context_order) in AUTO_METHODS {
+        let candidate = CompressOptions {
+            method,
+            context_order,
+            ..*options
+        };
+        let mut compressed = Vec::new();
+        compress_with(&sample[..], &mut compressed, &candidate)?;
+        if compressed.len() < best.0 {
+            best = (compressed.len(), candidate);
+        }
+    }
+
+    let (_, options) = best;
+    compress_with(sample.chain(input), output, &options)?;
+    Ok(options)
+}
+
+/// Compress `input` to `output` like `compress_with`, coding blocks with the
+/// table of `dictionary` where that makes them smaller.
+pub fn compress_with_dictionary<R: Read, W: Write>(
+    mut input: R,
+    output: W,
+    options: &CompressOptions,
+    dictionary: &Dictionary,
+) -> Result<(), HuffmanError> {
+    let mut encoder = Encoder::with_dictionary(output, *options, dictionary.clone());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Decompress `input` to `output`, checking the result against the size and
+/// checksum stored in the file. gzip files are recognised by their magic
+/// number and decompressed with `gzip::decompress`.
+pub fn decompress<R: BufRead, W: Write>(input: R, output: W) -> Result<(), HuffmanError> {
+    decompress_with(input, output, &DecompressOptions::default())
+}
+
+/// Decompress `input` to `output` like `decompress`, with the dictionary
+/// and number of threads in `options`.
+pub fn decompress_with<R: BufRead, W: Write>(
+    mut input: R,
+    mut output: W,
+    options: &DecompressOptions,
+) -> Result<(), HuffmanError> {
+    if input.fill_buf()?.starts_with(&gzip::MAGIC) {
+        return gzip::decompress(input, output);
+    }
+    let mut decoder = Decoder::with_options(input, options.clone());
+    while decoder.next_block()? {
+        output.write_all(decoder.block())?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn can_compress_decompress() {
+        let in_buf: Vec<u8> = b"Hello World".to_vec();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress(&in_buf[..], &mut compressed_buf).unwrap();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn can_compress_decompress_empty() {
+        let in_buf: Vec<u8> = Vec::new();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress(&in_buf[..], &mut compressed_buf).unwrap();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn decompress_recognises_gzip() {
+        let in_buf: Vec<u8> = b"Hello World".to_vec();
+        let mut compressed_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        gzip::compress(
+            &in_buf[..],
+            &mut compressed_buf,
+            &CompressOptions::default(),
+        )
+        .unwrap();
+        decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn auto_stores_what_does_not_shrink() {
+        let in_buf: Vec<u8> = b"Hello World".to_vec();
+        let mut huffman_buf: Vec<u8> = Vec::new();
+        let mut auto_buf: Vec<u8> = Vec::new();
+        let mut out_buf: Vec<u8> = Vec::new();
+
+        compress(&in_buf[..], &mut huffman_buf).unwrap();
+        let chosen =
+            compress_auto(&in_buf[..], &mut auto_buf, &CompressOptions::default()).unwrap();
+        decompress(&auto_buf[..], &mut out_buf).unwrap();
+
+        assert_eq!(chosen.method, Method::Stored);
+        assert!(auto_buf.len() < huffman_buf.len());
+        assert_eq!(in_buf, out_buf);
+    }
+
+    #[test]
+    fn auto_stores_what_no_method_shrinks() {
+        let stored = CompressOptions {
+            method: Method::Stored,
+            ..CompressOptions::default()
+        };
+        // xorshift, which nothing here compresses.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..10_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 56) as u8
+            })
+            .collect();
+        for input in [&b"Hello World"[..], b"", b"a", &noise] {
+            let mut stored_buf = Vec::new();
+            let mut auto_buf = Vec::new();
+            compress_with(input, &mut stored_buf, &stored).unwrap();
+            let chosen = compress_auto(input, &mut auto_buf, &CompressOptions::default()).unwrap();
+
+            assert_eq!(chosen.method, Method::Stored, "{:?}", input.len());
+            assert_eq!(auto_buf, stored_buf);
+        }
+    }
+
+    #[test]
+    fn rejects_unusable_max_code_length() {
+        for method in [Method::Huffman, Method::Lzh] {
+            let options = CompressOptions {
+                method,
+                max_code_length: 200,
+                ..CompressOptions::default()
+            };
+            let result = compress_with(&b"some data"[..], &mut Vec::new(), &options);
+            assert!(
+                matches!(result, Err(HuffmanError::BadCodeLength { .. })),
+                "{}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn auto_compresses_text() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let options = CompressOptions {
+            block_size: 30_000,
+            ..CompressOptions::default()
+        };
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+
+        let chosen = compress_auto(&text[..], &mut compressed, &options).unwrap();
+        decompress(&compressed[..], &mut decompressed).unwrap();
+
+        assert_ne!(chosen.method, Method::Stored);
+        assert_eq!(chosen.block_size, 30_000);
+        assert!(compressed.len() < text.len() / 2);
+        assert_eq!(text, decompressed);
+    }
+
+    #[test]
+    fn higher_levels_compress_smaller() {
+        let text = std::fs::read("tests/test-1mB.txt").unwrap();
+        let mut sizes = Vec::new();
+        for level in 1..=9 {
+            let mut compressed = Vec::new();
+            let mut decompressed = Vec::new();
+            compress_with(&text[..], &mut compressed, &CompressOptions::level(level)).unwrap();
+            decompress(&compressed[..], &mut decompressed).unwrap();
+            assert_eq!(text, decompressed);
+            sizes.push(compressed.len());
+        }
+        for (level, pair) in (2..).zip(sizes.windows(2)) {
+            assert!(pair[1] < pair[0], "level {} gave {:?}", level, sizes);
+        }
+    }
+
+    /// Inputs that have broken round trips before: arbitrary bytes, runs of
+    /// a single byte, every byte once, and nothing at all.
+    fn round_trip_inputs() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..4096),
+            (any::<u8>(), 1..5000usize).prop_map(|(c, n)| vec![c; n]),
+            Just((0..=255u8).collect::<Vec<u8>>()).prop_shuffle(),
+            Just(Vec::new()),
+        ]
+    }
+
+    fn any_method() -> impl Strategy<Value = Method> {
+        prop_oneof![
+            Just(Method::Huffman),
+            Just(Method::Lzh),
+            Just(Method::Adaptive),
+            Just(Method::Range),
+            Just(Method::Stored),
+        ]
+    }
+
+    fn any_alphabet() -> impl Strategy<Value = Alphabet> {
+        prop_oneof![
+            Just(Alphabet::Bytes),
+            Just(Alphabet::Words),
+            Just(Alphabet::Digrams),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(
+            in_buf in round_trip_inputs(),
+            method in any_method(),
+            alphabet in any_alphabet(),
+            block_size in 1..3000usize,
+            max_code_length in 9..=15usize,
+            transforms in prop_oneof![Just(""), Just("rle"), Just("bwt,mtf,rle")],
+        ) {
+            let options = CompressOptions {
+                block_size,
+                max_code_length,
+                method,
+                alphabet,
+                transforms: transforms.parse().unwrap(),
+                ..CompressOptions::default()
+            };
+            let mut compressed_buf: Vec<u8> = Vec::new();
+            let mut out_buf: Vec<u8> = Vec::new();
+
+            compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+            decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+            prop_assert_eq!(in_buf, out_buf);
+        }
+
+        #[test]
+        fn gzip_round_trips(in_buf in round_trip_inputs()) {
+            let mut compressed_buf: Vec<u8> = Vec::new();
+            let mut out_buf: Vec<u8> = Vec::new();
+
+            gzip::compress(&in_buf[..], &mut compressed_buf, &CompressOptions::default()).unwrap();
+            decompress(&compressed_buf[..], &mut out_buf).unwrap();
+
+            prop_assert_eq!(in_buf, out_buf);
+        }
+
+        #[test]
+        fn decompress_rejects_arbitrary_bodies_without_panicking(
+            magic in prop_oneof![Just(container::MAGIC.to_vec()), Just(gzip::MAGIC.to_vec())],
+            version in 0..=3u8,
+            body in prop::collection::vec(any::<u8>(), 0..512),
+        ) {
+            let mut compressed_buf = magic;
+            compressed_buf.push(version);
+            compressed_buf.extend(body);
+
+            let _ = decompress(&compressed_buf[..], &mut Vec::new());
+        }
+
+        #[test]
+        fn decompress_rejects_corrupt_files_without_panicking(
+            in_buf in round_trip_inputs(),
+            method in any_method(),
+            index in any::<bool>(),
+            corruption in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..4),
+        ) {
+            let options = CompressOptions {
+                block_size: 1000,
+                method,
+                index,
+                ..CompressOptions::default()
+            };
+            let mut compressed_buf: Vec<u8> = Vec::new();
+            compress_with(&in_buf[..], &mut compressed_buf, &options).unwrap();
+            for (at, byte) in corruption {
+                let at = at.index(compressed_buf.len());
+                compressed_buf[at] ^= byte;
+            }
+
+            let _ = decompress(&compressed_buf[..], &mut Vec::new());
+        }
+    }
+}
@@ -0,0 +1,384 @@
+//! LZ77 stage of the `lzh` method.
+//!
+//! Repeated strings are replaced by matches pointing back into the last
+//! `WINDOW_SIZE` bytes of the block. Literals and match lengths share one
+//! Huffman code table and match distances have a second one, with lengths and
+//! distances split into a code and extra bits as in DEFLATE.
+//!
+//! A payload is laid out as:
+//!
+//! ```text
+//! litlen_table_len: u32 | litlen_table | dist_table_len: u32 | dist_table | bitstream
+//! ```
+//!
+//! where each match is coded as its length code, the length extra bits, its
+//! distance code and the distance extra bits. The bitstream ends with EOT.
+
+use crate::bits::{BitReader, BitWriter};
+use crate::huffman::{HuffmanDecoder, HuffmanEncoding, HuffmanError, Symbol};
+
+use std::collections::HashMap;
+
+/// How far back a match may point.
+pub(crate) const WINDOW_SIZE: usize = 1 << 15;
+
+pub(crate) const MIN_MATCH: usize = 3;
+pub(crate) const MAX_MATCH: usize = 258;
+
+/// Number of earlier positions tried when looking for a match, unless told
+/// otherwise. Longer chains find longer matches at the cost of speed.
+pub(crate) const MAX_CHAIN: usize = 128;
+
+const HASH_BITS: usize = 15;
+
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+/// The code whose base is the largest not above `value`, and the extra bits
+/// holding the difference.
+pub(crate) fn split(value: u16, bases: &[u16], extra: &[u8]) -> (u8, u32, usize) {
+    let code = bases.partition_point(|&b| b <= value) - 1;
+    (
+        code as u8,
+        (value - bases[code]) as u32,
+        extra[code] as usize,
+    )
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = (data[0] as usize) << 16 | (data[1] as usize) << 8 | data[2] as usize;
+    (v.wrapping_mul(2654435761) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+/// Hash chains over the positions of a block, to find earlier occurrences of
+/// the next `MIN_MATCH` bytes.
+struct Chains {
+    /// Most recent position with each hash.
+    head: Vec<usize>,
+    /// For each position, the previous one with the same hash.
+    prev: Vec<usize>,
+}
+
+impl Chains {
+    fn new(len: usize) -> Self {
+        Chains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; len],
+        }
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos..]);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    /// Longest match for the data at `pos` within the window, as
+    /// `(length, distance)`, trying at most `max_chain` earlier positions.
+    fn longest_match(&self, data: &[u8], pos: usize, max_chain: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH > data.len() {
+            return best;
+        }
+
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut candidate = self.head[hash(&data[pos..])];
+        for _ in 0..max_chain {
+            if candidate == usize::MAX || pos - candidate > WINDOW_SIZE {
+                break;
+            }
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+        }
+        best
+    }
+}
+
+/// Split `data` into literals and matches, greedily taking the longest match
+/// found at each position among the last `max_chain` with the same hash.
+pub(crate) fn parse(data: &[u8], max_chain: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chains = Chains::new(data.len());
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (length, distance) = chains.longest_match(data, pos, max_chain);
+        if length >= MIN_MATCH {
+            tokens.push(Token::Match {
+                length: length as u16,
+                distance: distance as u16,
+            });
+            for p in pos..pos + length {
+                chains.insert(data, p);
+            }
+            pos += length;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            chains.insert(data, pos);
+            pos += 1;
+        }
+    }
+    tokens
+}
+
+/// Compress a block with LZ77 followed by Huffman coding of the tokens.
+pub(crate) fn encode_block(
+    block: &[u8],
+    max_code_length: usize,
+    max_chain: usize,
+) -> Result<Vec<u8>, HuffmanError> {
+    let tokens = parse(block, max_chain);
+
+    let mut litlen_freqs: HashMap<Symbol, u64> = HashMap::new();
+    let mut dist_freqs: HashMap<Symbol, u64> = HashMap::new();
+    for token in &tokens {
+        match *token {
+            Token::Literal(c) => *litlen_freqs.entry(Symbol::Char(c)).or_insert(0) += 1,
+            Token::Match { length, distance } => {
+                let (l, _, _) = split(length, &LENGTH_BASE, &LENGTH_EXTRA);
+                let (d, _, _) = split(distance, &DISTANCE_BASE, &DISTANCE_EXTRA);
+                *litlen_freqs.entry(Symbol::Length(l)).or_insert(0) += 1;
+                *dist_freqs.entry(Symbol::Distance(d)).or_insert(0) += 1;
+            }
+        }
+    }
+    litlen_freqs.insert(Symbol::EOT, 1);
+
+    let litlen = HuffmanEncoding::from_frequencies_limited(litlen_freqs, max_code_length)?;
+    let dist = if dist_freqs.is_empty() {
+        HuffmanEncoding::from_lengths(&HashMap::new())
+    } else {
+        HuffmanEncoding::from_frequencies_limited(dist_freqs, max_code_length)?
+    };
+
+    let mut bits = BitWriter::new();
+    for token in &tokens {
+        match *token {
+            Token::Literal(c) => bits.write_code(&litlen.encoding[&Symbol::Char(c)]),
+            Token::Match { length, distance } => {
+                let (l, extra, n) = split(length, &LENGTH_BASE, &LENGTH_EXTRA);
+                bits.write_code(&litlen.encoding[&Symbol::Length(l)]);
+                bits.write_bits(extra, n);
+                let (d, extra, n) = split(distance, &DISTANCE_BASE, &DISTANCE_EXTRA);
+                bits.write_code(&dist.encoding[&Symbol::Distance(d)]);
+                bits.write_bits(extra, n);
+            }
+        }
+    }
+    bits.write_code(&litlen.encoding[&Symbol::EOT]);
+
+    let mut out = Vec::new();
+    for table in [litlen.save(), dist.save()] {
+        out.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        out.extend_from_slice(&table);
+    }
+    out.append(&mut bits.finish());
+    Ok(out)
+}
+
+/// Read one code table of a payload, returning it and the rest of the
+/// payload.
+pub(crate) fn read_table(payload: &[u8]) -> Result<(&[u8], &[u8]), HuffmanError> {
+    if payload.len() < 4 {
+        return Err(HuffmanError::Truncated);
+    }
+    let (len, rest) = payload.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if rest.len() < len {
+        return Err(HuffmanError::Truncated);
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Decode a payload written by `encode_block`, appending the data to `out`.
+/// Fails as soon as the block holds more than `limit` bytes.
+pub(crate) fn decode_block(
+    payload: &[u8],
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), HuffmanError> {
+    let (litlen, rest) = read_table(payload)?;
+    let (dist, stream) = read_table(rest)?;
+    let litlen = HuffmanDecoder::new(&HuffmanEncoding::restore_from(litlen)?);
+    let dist = HuffmanDecoder::new(&HuffmanEncoding::restore_table_from(dist)?);
+
+    let start = out.len();
+    let end = start.saturating_add(limit);
+    let mut bits = BitReader::new(stream);
+    loop {
+        match litlen.decode_symbol(&mut bits)? {
+            Symbol::Char(_) if out.len() >= end => {
+                return Err(HuffmanError::BadBitstream("more data than the block holds"))
+            }
+            Symbol::Char(c) => out.push(c),
+            Symbol::EOT => break,
+            Symbol::Length(l) => {
+                let l = l as usize;
+                let length =
+                    LENGTH_BASE[l] as usize + bits.read_bits(LENGTH_EXTRA[l] as usize)? as usize;
+                let d = match dist.decode_symbol(&mut bits)? {
+                    Symbol::Distance(d) => d as usize,
+                    _ => return Err(HuffmanError::BadBitstream("expected a distance code")),
+                };
+                if d >= DISTANCE_BASE.len() {
+                    return Err(HuffmanError::BadBitstream("unknown distance code"));
+                }
+                let distance = DISTANCE_BASE[d] as usize
+                    + bits.read_bits(DISTANCE_EXTRA[d] as usize)? as usize;
+                if distance > out.len() - start {
+                    return Err(HuffmanError::BadBitstream("match before start of block"));
+                }
+                if length > end - out.len() {
+                    return Err(HuffmanError::BadBitstream("more data than the block holds"));
+                }
+                // Matches may overlap the data they produce, so copy byte by
+                // byte.
+                let from = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[from + i]);
+                }
+            }
+            Symbol::Distance(_) | Symbol::Word(_) => {
+                return Err(HuffmanError::BadBitstream("unexpected symbol"))
+            }
+        }
+    }
+
+    if bits.remaining() >= 8 {
+        return Err(HuffmanError::BadBitstream("data after EOT"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let payload = encode_block(data, crate::huffman::MAX_CODE_LENGTH, MAX_CHAIN).unwrap();
+        let mut out = Vec::new();
+        decode_block(&payload, &mut out, usize::MAX).unwrap();
+        out
+    }
+
+    #[test]
+    fn splits_lengths_and_distances_into_codes() {
+        assert_eq!(split(3, &LENGTH_BASE, &LENGTH_EXTRA), (0, 0, 0));
+        assert_eq!(split(12, &LENGTH_BASE, &LENGTH_EXTRA), (8, 1, 1));
+        assert_eq!(split(257, &LENGTH_BASE, &LENGTH_EXTRA), (27, 30, 5));
+        assert_eq!(split(258, &LENGTH_BASE, &LENGTH_EXTRA), (28, 0, 0));
+        assert_eq!(split(1, &DISTANCE_BASE, &DISTANCE_EXTRA), (0, 0, 0));
+        assert_eq!(
+            split(32768, &DISTANCE_BASE, &DISTANCE_EXTRA),
+            (29, 8191, 13)
+        );
+    }
+
+    #[test]
+    fn finds_repeated_strings() {
+        let tokens = parse(b"abcabcabcabc", MAX_CHAIN);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Literal(b'a'),
+                Token::Literal(b'b'),
+                Token::Literal(b'c'),
+                Token::Match {
+                    length: 9,
+                    distance: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_stay_within_window() {
+        let data: Vec<u8> = (0..3 * WINDOW_SIZE).map(|i| (i % 251) as u8).collect();
+        for token in parse(&data, MAX_CHAIN) {
+            if let Token::Match { length, distance } = token {
+                assert!((MIN_MATCH..=MAX_MATCH).contains(&(length as usize)));
+                assert!(distance as usize <= WINDOW_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn can_round_trip_blocks() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        assert_eq!(round_trip(&text), text);
+        assert_eq!(round_trip(b""), b"");
+        assert_eq!(round_trip(b"a"), b"a");
+        assert_eq!(round_trip(&[0u8; 100_000]), vec![0u8; 100_000]);
+    }
+
+    #[test]
+    fn shrinks_repetitive_data() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let huffman = HuffmanEncoding::from_data_vec(&text);
+        let huffman_len = huffman.save().len() + huffman.encode(&text).len();
+        assert!(
+            encode_block(&text, crate::huffman::MAX_CODE_LENGTH, MAX_CHAIN)
+                .unwrap()
+                .len()
+                < huffman_len
+        );
+    }
+
+    #[test]
+    fn rejects_match_before_start_of_block() {
+        let mut litlen = HashMap::new();
+        litlen.insert(Symbol::Length(0), 1);
+        litlen.insert(Symbol::EOT, 1);
+        let litlen = HuffmanEncoding::from_lengths(&litlen);
+        let mut dist = HashMap::new();
+        dist.insert(Symbol::Distance(0), 1);
+        let dist = HuffmanEncoding::from_lengths(&dist);
+
+        let mut bits = BitWriter::new();
+        bits.write_code(&litlen.encoding[&Symbol::Length(0)]);
+        bits.write_code(&dist.encoding[&Symbol::Distance(0)]);
+        bits.write_code(&litlen.encoding[&Symbol::EOT]);
+
+        let mut payload = Vec::new();
+        for table in [litlen.save(), dist.save()] {
+            payload.extend_from_slice(&(table.len() as u32).to_be_bytes());
+            payload.extend_from_slice(&table);
+        }
+        payload.append(&mut bits.finish());
+
+        let result = decode_block(&payload, &mut Vec::new(), usize::MAX);
+        assert!(matches!(result, Err(HuffmanError::BadBitstream(_))));
+    }
+}
@@ -1,142 +1,1330 @@
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
 use clap::Command;
-use clap::{Arg, ArgAction};
-use huffman::HuffmanEncoding;
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches};
+use log::{info, warn, Level, LevelFilter, Log, Metadata, Record};
+use press::archive::{self, ArchiveReader, ArchiveWriter, EntryKind};
+use press::bench::{self, Report, TrackingAllocator};
+use press::inspect::{Analysis, BlockLayout, Layout};
+use press::{
+    Alphabet, CompressOptions, DecompressOptions, Dictionary, HuffmanEncoding, HuffmanError,
+    IndexedReader, Key, Method, Transforms,
+};
 
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-mod huffman;
+/// Counts heap use for `press bench`, which is the only command that turns
+/// the counting on.
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
 
-const MAGIC: [u8; 5] = [b'P', b'R', b'E', b'S', b'S'];
+/// Exit code for each class of failure. Usage errors exit with 2, as
+/// reported by clap.
+const EXIT_IO: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_NOT_PRESS: u8 = 3;
+const EXIT_TRUNCATED: u8 = 4;
+const EXIT_CORRUPT: u8 = 5;
+const EXIT_INTEGRITY: u8 = 6;
+const EXIT_DICTIONARY: u8 = 7;
+const EXIT_PASSPHRASE: u8 = 8;
 
-fn compress(input: &Vec<u8>, output: &mut Vec<u8>) {
-    let encoding = HuffmanEncoding::from_data_vec(input);
-    let mut filestream = encoding.encode(input);
-    let mut code_table = encoding.save();
+fn exit_code(e: &HuffmanError) -> u8 {
+    match e {
+        HuffmanError::Io(_) => EXIT_IO,
+        HuffmanError::BadCodeLength { .. } => EXIT_USAGE,
+        HuffmanError::BadMagic
+        | HuffmanError::UnsupportedVersion(_)
+        | HuffmanError::UnsupportedFlags(_)
+        | HuffmanError::UnsupportedMethod(_)
+        | HuffmanError::UnsupportedTransform(_) => EXIT_NOT_PRESS,
+        HuffmanError::WrongDictionary { .. } => EXIT_DICTIONARY,
+        HuffmanError::PassphraseRequired | HuffmanError::WrongPassphrase => EXIT_PASSPHRASE,
+        HuffmanError::Truncated => EXIT_TRUNCATED,
+        HuffmanError::BadTable(_)
+        | HuffmanError::BadBitstream(_)
+        | HuffmanError::BadIndex(_)
+        | HuffmanError::BadArchive(_)
+        | HuffmanError::UnsafePath(_) => EXIT_CORRUPT,
+        HuffmanError::SizeMismatch { .. }
+        | HuffmanError::ChecksumMismatch { .. }
+        | HuffmanError::Tampered => EXIT_INTEGRITY,
+    }
+}
+
+fn report(message: &dyn std::fmt::Display, code: u8) -> ExitCode {
+    clear_progress();
+    eprintln!("press: {}", message);
+    ExitCode::from(code)
+}
+
+/// Writes log messages to stderr after `press: `, with the level in front
+/// of debugging ones, clearing any progress bar first.
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        clear_progress();
+        match record.level() {
+            Level::Error | Level::Warn | Level::Info => eprintln!("press: {}", record.args()),
+            level => eprintln!(
+                "press: {}: {}",
+                level.as_str().to_lowercase(),
+                record.args()
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log at the level set by `--quiet` or the number of `-v` flags: warnings
+/// by default, with `-v` what happened to each file, with `-vv` each phase
+/// of the work and its timing, and with `-vvv` the code tables too.
+fn init_logging(matches: &ArgMatches) {
+    let level = match (matches.get_flag("quiet"), matches.get_count("verbose")) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Files at least this large get a progress bar.
+const PROGRESS_MIN_SIZE: u64 = 1 << 20;
+
+/// Time between redraws of the progress bar.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether a progress bar is on the last line of stderr.
+static PROGRESS_SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Erase the progress bar, if one is shown, so a message can take its line.
+fn clear_progress() {
+    if PROGRESS_SHOWN.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[K");
+    }
+}
+
+/// Reads a file while drawing a bar on stderr of how much of it has been
+/// read. The bar is erased when the reader is dropped.
+struct Progress<R> {
+    inner: R,
+    name: String,
+    total: u64,
+    done: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl<R: BufRead> Progress<R> {
+    fn new(inner: R, path: &Path, total: u64) -> Progress<R> {
+        Progress {
+            inner,
+            name: path.display().to_string(),
+            total,
+            done: 0,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.done += n as u64;
+        if self
+            .drawn
+            .is_none_or(|drawn| drawn.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.draw();
+            self.drawn = Some(Instant::now());
+        }
+    }
+
+    fn draw(&self) {
+        const WIDTH: u64 = 30;
+        let filled = (WIDTH * self.done / self.total.max(1)).min(WIDTH) as usize;
+        let megabytes = |bytes: u64| bytes as f64 / 1e6;
+        let secs = self.started.elapsed().as_secs_f64();
+        eprint!(
+            "\r\x1b[K{} [{}{}] {:>3}% {:.1}/{:.1} MB {:.1} MB/s",
+            self.name,
+            "#".repeat(filled),
+            "-".repeat(WIDTH as usize - filled),
+            100 * self.done / self.total.max(1),
+            megabytes(self.done),
+            megabytes(self.total),
+            if secs > 0.0 {
+                megabytes(self.done) / secs
+            } else {
+                0.0
+            }
+        );
+        PROGRESS_SHOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<R: BufRead> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.advance(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Progress<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.advance(amt);
+    }
+}
+
+impl<R> Drop for Progress<R> {
+    fn drop(&mut self) {
+        clear_progress();
+    }
+}
+
+fn max_code_length_arg() -> Arg {
+    Arg::new("max-code-length")
+        .long("max-code-length")
+        .help("Longest Huffman code allowed, in bits.")
+        .value_parser(clap::value_parser!(u8).range(9..=127))
+        .default_value("15")
+}
+
+fn method_arg() -> Arg {
+    Arg::new("method")
+        .long("method")
+        .help("Compression method: huffman, lzh to find repeated strings first, adaptive to code in a single pass without code tables, range for range coding, or stored to leave the data as it is.")
+        .value_parser(clap::value_parser!(Method))
+        .default_value("huffman")
+}
+
+/// Ids of the `-1` to `-9` flags, which are also their short names.
+const LEVELS: [&str; 9] = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+
+/// `-1` to `-9`, of which only the ends are shown in the help, as
+/// `--fast` and `--best`.
+fn level_args() -> Vec<Arg> {
+    LEVELS
+        .iter()
+        .map(|level| {
+            let arg = Arg::new(*level)
+                .short(level.chars().next().unwrap())
+                .action(ArgAction::SetTrue);
+            match *level {
+                "1" => arg
+                    .long("fast")
+                    .help("Compress fast, at level 1 of -1 to -9. Levels set --method, --order and --transforms, unless they are given too."),
+                "9" => arg
+                    .long("best")
+                    .help("Compress as small as possible, slowly, at level 9."),
+                _ => arg.hide(true),
+            }
+        })
+        .collect()
+}
+
+/// The method, context order, transforms and code length asked for, where a
+/// level stands for a method and transforms unless they are given too.
+fn coding_options(matches: &ArgMatches) -> CompressOptions {
+    let level = LEVELS
+        .iter()
+        .position(|level| matches.get_flag(level))
+        .map(|i| i as u8 + 1);
+    let preset = level.map_or_else(CompressOptions::default, CompressOptions::level);
+    let explicit =
+        |id| level.is_none() || matches.value_source(id) == Some(ValueSource::CommandLine);
+    CompressOptions {
+        max_code_length: *matches.get_one::<u8>("max-code-length").unwrap() as usize,
+        method: if explicit("method") {
+            *matches.get_one::<Method>("method").unwrap()
+        } else {
+            preset.method
+        },
+        context_order: if explicit("order") {
+            *matches.get_one::<u8>("order").unwrap()
+        } else {
+            preset.context_order
+        },
+        transforms: if explicit("transforms") {
+            *matches.get_one::<Transforms>("transforms").unwrap()
+        } else {
+            preset.transforms
+        },
+        ..preset
+    }
+}
+
+fn order_arg() -> Arg {
+    Arg::new("order")
+        .long("order")
+        .help("Context order of the range method: 0, or 1 to model each byte given the one before.")
+        .value_parser(clap::value_parser!(u8).range(0..=press::range::MAX_ORDER as i64))
+        .default_value("0")
+}
+
+fn threads_arg() -> Arg {
+    Arg::new("threads")
+        .long("threads")
+        .value_name("N")
+        .help("Number of blocks to compress or decompress at a time, on as many worker threads.")
+        .value_parser(clap::value_parser!(u16).range(1..))
+        .default_value("1")
+}
+
+fn transforms_arg() -> Arg {
+    Arg::new("transforms")
+        .long("transforms")
+        .value_name("LIST")
+        .help("Transforms to put each block through before coding it, in order, such as bwt,mtf,rle as in bzip2: rle for run-length encoding, bwt for the Burrows-Wheeler transform, mtf for move-to-front. Not used with --format gzip.")
+        .value_parser(clap::value_parser!(Transforms))
+        .default_value("")
+}
+
+/// Read every file under `dir`, in subdirectories too.
+fn read_samples(dir: &Path, samples: &mut Vec<Vec<u8>>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_samples(&path, samples)?;
+        } else {
+            samples.push(std::fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Build a dictionary from the files of a directory and write it out.
+fn train(matches: &ArgMatches) -> ExitCode {
+    let dir = matches.get_one::<String>("DIR").unwrap();
+    let filename = matches.get_one::<String>("output").unwrap();
+    let max_code_length = *matches.get_one::<u8>("max-code-length").unwrap() as usize;
+
+    let mut samples = Vec::new();
+    if let Err(e) = read_samples(Path::new(dir), &mut samples) {
+        return report(&format!("{}: {}", dir, e), EXIT_IO);
+    }
+    let dictionary = match Dictionary::train(samples.iter().map(Vec::as_slice), max_code_length) {
+        Ok(dictionary) => dictionary,
+        Err(e) => return report(&format!("{}: {}", dir, e), exit_code(&e)),
+    };
+    if let Err(e) = std::fs::write(filename, dictionary.save()) {
+        return report(&format!("{}: {}", filename, e), EXIT_IO);
+    }
+    println!(
+        "Dictionary {:08x} trained on {} files.",
+        dictionary.id(),
+        samples.len()
+    );
+    ExitCode::SUCCESS
+}
+
+/// Parse a byte range `A-B`, from `A` up to but not including `B`, or `A-`
+/// to the end.
+fn parse_range(s: &str) -> Result<Range<u64>, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("'{}' is not a range like 1000-2000", s))?;
+    let start = start.parse::<u64>().map_err(|e| e.to_string())?;
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse::<u64>().map_err(|e| e.to_string())?,
+    };
+    if end < start {
+        return Err(format!("range {} ends before it starts", s));
+    }
+    Ok(start..end)
+}
+
+fn dictionary_arg() -> Arg {
+    Arg::new("dict")
+        .long("dict")
+        .value_name("FILE")
+        .help("Dictionary made with 'press train', to code small files without storing their code tables.")
+}
+
+/// Environment variable holding the passphrase of encrypted files, when
+/// `--key-file` is not given.
+const PASSPHRASE_VAR: &str = "PRESS_PASSPHRASE";
+
+fn key_file_arg() -> Arg {
+    Arg::new("key-file")
+        .long("key-file")
+        .value_name("FILE")
+        .help("File holding the passphrase of encrypted files, less one trailing newline. Without it, the PRESS_PASSPHRASE environment variable is used.")
+}
+
+/// The passphrase from `--key-file`, or else from `PASSPHRASE_VAR`, if
+/// either is given.
+fn read_passphrase(matches: &ArgMatches) -> Result<Option<Vec<u8>>, ExitCode> {
+    let Some(filename) = matches.get_one::<String>("key-file") else {
+        return Ok(std::env::var_os(PASSPHRASE_VAR).map(|var| var.into_encoded_bytes()));
+    };
+    let mut passphrase =
+        std::fs::read(filename).map_err(|e| report(&format!("{}: {}", filename, e), EXIT_IO))?;
+    if passphrase.ends_with(b"\n") {
+        passphrase.pop();
+        if passphrase.ends_with(b"\r") {
+            passphrase.pop();
+        }
+    }
+    Ok(Some(passphrase))
+}
+
+/// Pack a directory tree into an archive.
+fn archive(matches: &ArgMatches) -> ExitCode {
+    let dir = matches.get_one::<String>("DIR").unwrap();
+    let filename = matches.get_one::<String>("output").unwrap();
+    let options = CompressOptions {
+        max_code_length: *matches.get_one::<u8>("max-code-length").unwrap() as usize,
+        method: *matches.get_one::<Method>("method").unwrap(),
+        ..CompressOptions::default()
+    };
+
+    let file = match File::create(filename) {
+        Ok(file) => file,
+        Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+    };
+    let result = ArchiveWriter::new(BufWriter::new(file), options).and_then(|mut writer| {
+        writer.add_tree(Path::new(dir))?;
+        writer.finish()?;
+        Ok(())
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report(&format!("{}: {}", dir, e), exit_code(&e)),
+    }
+}
+
+/// A modification time as a UTC date and time.
+fn format_mtime(mtime: i64) -> String {
+    let (days, seconds) = (mtime.div_euclid(86400), mtime.rem_euclid(86400));
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// Print the entries of an archive, in the manner of `ls -l`.
+fn list(matches: &ArgMatches) -> ExitCode {
+    let filename = matches.get_one::<String>("ARCHIVE").unwrap();
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+    };
+
+    let mut output = BufWriter::new(std::io::stdout());
+    let result = ArchiveReader::new(BufReader::new(file)).and_then(|mut reader| {
+        while let Some(entry) = reader.next_entry()? {
+            let (kind, size, suffix) = match entry.kind {
+                EntryKind::File { size } => ('-', size, String::new()),
+                EntryKind::Directory => ('d', 0, String::new()),
+                EntryKind::Symlink { target } => ('l', 0, format!(" -> {}", target)),
+            };
+            let permissions: String = (0..9)
+                .map(|i| match entry.mode & (0o400 >> i) {
+                    0 => '-',
+                    _ => ['r', 'w', 'x'][i % 3],
+                })
+                .collect();
+            writeln!(
+                output,
+                "{}{} {:>12} {} {}{}",
+                kind,
+                permissions,
+                size,
+                format_mtime(entry.mtime),
+                entry.path,
+                suffix
+            )?;
+        }
+        Ok(output.flush()?)
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report(&format!("{}: {}", filename, e), exit_code(&e)),
+    }
+}
+
+/// Unpack an archive into a directory.
+fn extract(matches: &ArgMatches) -> ExitCode {
+    let filename = matches.get_one::<String>("ARCHIVE").unwrap();
+    let dest = matches.get_one::<String>("output").unwrap();
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+    };
+    let options = archive::ExtractOptions {
+        preserve_permissions: matches.get_flag("preserve-permissions"),
+    };
+    match archive::extract_with(BufReader::new(file), Path::new(dest), &options) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => report(&format!("{}: {}", filename, e), exit_code(&e)),
+    }
+}
+
+/// Write part of a compressed file to stdout.
+fn cat(matches: &ArgMatches) -> ExitCode {
+    let filename = matches.get_one::<String>("FILE").unwrap();
+    let range = matches.get_one::<Range<u64>>("range").cloned();
+    let dictionary = match matches.get_one::<String>("dict") {
+        Some(filename) => match read_dictionary(filename) {
+            Ok(dictionary) => Some(dictionary),
+            Err(code) => return code,
+        },
+        None => None,
+    };
+
+    let passphrase = match read_passphrase(matches) {
+        Ok(passphrase) => passphrase,
+        Err(code) => return code,
+    };
+
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+    };
+    let options = DecompressOptions {
+        dictionary,
+        passphrase,
+        ..DecompressOptions::default()
+    };
+    let mut output = BufWriter::new(std::io::stdout());
+    let result = IndexedReader::open(file, options).and_then(|mut reader| {
+        reader.read_range(range.unwrap_or(0..u64::MAX), &mut output)?;
+        Ok(output.flush()?)
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => report(&format!("{}: {}", filename, e), exit_code(&e)),
+    }
+}
 
-    let mut table_len = u32_to_u8s(code_table.len() as u32);
+/// Time compression and decompression over the files of a directory, and
+/// compare them with an earlier run.
+fn run_bench(matches: &ArgMatches) -> ExitCode {
+    let dir = matches.get_one::<String>("DIR").unwrap();
+    let runs = *matches.get_one::<u16>("runs").unwrap() as usize;
+    let options = CompressOptions {
+        threads: *matches.get_one::<u16>("threads").unwrap() as usize,
+        ..coding_options(matches)
+    };
 
-    output.append(&mut Vec::from(MAGIC));
-    output.append(&mut table_len);
-    output.append(&mut code_table);
-    output.append(&mut filestream);
+    let baseline = match matches.get_one::<String>("baseline") {
+        Some(filename) => {
+            let baseline = std::fs::read(filename)
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    serde_json::from_slice::<Report>(&json).map_err(|e| e.to_string())
+                });
+            match baseline {
+                Ok(baseline) => Some(baseline),
+                Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+            }
+        }
+        None => None,
+    };
+
+    TrackingAllocator::start_counting();
+    let files = match bench::corpus(Path::new(dir)) {
+        Ok(files) => files,
+        Err(e) => return report(&format!("{}: {}", dir, e), EXIT_IO),
+    };
+    let mut results = Report {
+        settings: describe(&options),
+        files: Vec::new(),
+    };
+    for path in files {
+        let name = path
+            .strip_prefix(dir)
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+        let measured = std::fs::read(&path)
+            .map_err(HuffmanError::from)
+            .and_then(|data| bench::measure(&name, &data, &options, runs));
+        match measured {
+            Ok(measurement) => {
+                info!(
+                    "{}: {:.3} of the original, {:.2?} to compress",
+                    name,
+                    measurement.ratio(),
+                    Duration::from_secs_f64(measurement.compress_secs)
+                );
+                results.files.push(measurement);
+            }
+            Err(e) => return report(&format!("{}: {}", path.display(), e), exit_code(&e)),
+        }
+    }
+
+    if let Some(baseline) = baseline.as_ref().filter(|b| b.settings != results.settings) {
+        warn!(
+            "the baseline was compressed with {}, not {}",
+            baseline.settings, results.settings
+        );
+    }
+    let stdout = std::io::stdout().lock();
+    let written = if matches.get_flag("csv") {
+        results.write_csv(stdout, baseline.as_ref())
+    } else {
+        results.write_table(stdout, baseline.as_ref())
+    };
+    if let Err(e) = written {
+        return report(&e, EXIT_IO);
+    }
+
+    if let Some(filename) = matches.get_one::<String>("save-baseline") {
+        let saved = serde_json::to_vec_pretty(&results)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(filename, json).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            return report(&format!("{}: {}", filename, e), EXIT_IO);
+        }
+    }
+    ExitCode::SUCCESS
 }
 
-fn decompress(input: &Vec<u8>, output: &mut Vec<u8>) {
-    let magic_len = MAGIC.len();
-    if &input[0..magic_len] != &MAGIC {
-        panic!("File is not in press format.");
+/// The settings of `options` that `press bench` varies, for its reports.
+fn describe(options: &CompressOptions) -> String {
+    let mut settings = format!("--method {}", options.method);
+    if options.method == Method::Range {
+        settings += &format!(" --order {}", options.context_order);
+    }
+    if options.method == Method::Lzh {
+        settings += &format!(" (match chains of {})", options.max_chain);
     }
+    if !options.transforms.is_empty() {
+        settings += &format!(" --transforms {}", options.transforms);
+    }
+    settings
+}
 
-    let u32_len: usize = 4; // 4 bytes in a u32
-    let table_length = u8s_to_u32(&Vec::from(&input[magic_len..magic_len + u32_len])) as usize;
+/// Report on the entropy of a file and, compressed, where its bytes go.
+fn inspect(matches: &ArgMatches) -> ExitCode {
+    let filename = matches.get_one::<String>("FILE").unwrap();
+    let max_code_length = *matches.get_one::<u8>("max-code-length").unwrap() as usize;
+    let block = *matches.get_one::<u32>("block").unwrap() as usize;
+    let dictionary = match matches.get_one::<String>("dict") {
+        Some(filename) => match read_dictionary(filename) {
+            Ok(dictionary) => Some(dictionary),
+            Err(code) => return code,
+        },
+        None => None,
+    };
+    let passphrase = match read_passphrase(matches) {
+        Ok(passphrase) => passphrase,
+        Err(code) => return code,
+    };
+    let file = match std::fs::read(filename) {
+        Ok(file) => file,
+        Err(e) => return report(&format!("{}: {}", filename, e), EXIT_IO),
+    };
+    let options = DecompressOptions {
+        dictionary,
+        passphrase,
+        ..DecompressOptions::default()
+    };
 
-    let table_start = magic_len + u32_len;
-    let table_end = table_start + table_length;
-    let table_raw = &input[table_start..table_end];
-    let data_start = table_end;
-    let data_raw = &input[data_start..];
+    let (is_press, layout) = match Layout::read(&file, options.clone()) {
+        Ok(layout) => (true, layout),
+        Err(HuffmanError::BadMagic) => {
+            let options = CompressOptions {
+                method: *matches.get_one::<Method>("method").unwrap(),
+                max_code_length,
+                transforms: *matches.get_one::<Transforms>("transforms").unwrap(),
+                ..CompressOptions::default()
+            };
+            let mut compressed = Vec::new();
+            let layout = press::compress_with(&file[..], &mut compressed, &options)
+                .and_then(|()| Layout::read(&compressed, DecompressOptions::default()));
+            match layout {
+                Ok(layout) => (false, layout),
+                Err(e) => return report(&format!("{}: {}", filename, e), exit_code(&e)),
+            }
+        }
+        Err(e) => return report(&format!("{}: {}", filename, e), exit_code(&e)),
+    };
 
-    let encoding = HuffmanEncoding::restore_from(&Vec::from(table_raw));
+    let table = match layout.blocks.get(block - 1) {
+        Some(BlockLayout {
+            table: Some(table), ..
+        }) => Some(table),
+        _ => None,
+    };
+    if let Some(export) = matches.get_one::<String>("export-table") {
+        let Some(table) = table else {
+            return report(
+                &format!("{}: block {} has no code table", filename, block),
+                EXIT_USAGE,
+            );
+        };
+        let format = matches.get_one::<String>("table-format").unwrap();
+        return match export_table(table, format, export) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report(&format!("{}: {}", export, e), EXIT_IO),
+        };
+    }
+
+    let data = if is_press {
+        let mut data = Vec::new();
+        if let Err(e) = press::decompress_with(&file[..], &mut data, &options) {
+            return report(&format!("{}: {}", filename, e), exit_code(&e));
+        }
+        data
+    } else {
+        file
+    };
+    let analysis = match Analysis::of(&data, max_code_length) {
+        Ok(analysis) => analysis,
+        Err(e) => return report(&format!("{}: {}", filename, e), exit_code(&e)),
+    };
+    println!("{}", analysis);
+    if matches.get_flag("tree") {
+        println!("Tree:\n{:#?}\n", analysis.tree());
+    }
+    println!("{}", layout);
+    match table {
+        Some(table) => println!("Code table of block {}:\n{:?}", block, table),
+        None if block <= layout.blocks.len() => println!("Block {} stores no code table.", block),
+        None => println!("There is no block {}.", block),
+    }
+    ExitCode::SUCCESS
+}
+
+/// Write `table` as JSON or CBOR to the file `filename`, or to stdout for
+/// "-".
+fn export_table(table: &HuffmanEncoding, format: &str, filename: &str) -> std::io::Result<()> {
+    let mut output: Box<dyn Write> = if filename == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(File::create(filename)?)
+    };
+    match format {
+        "cbor" => ciborium::into_writer(table, &mut output).map_err(std::io::Error::other)?,
+        _ => {
+            serde_json::to_writer_pretty(&mut output, table)?;
+            writeln!(output)?;
+        }
+    }
+    output.flush()
+}
 
-    assert!(encoding.encoding.contains_key(&huffman::Symbol::EOT));
-    let mut data = encoding.decode(&Vec::from(data_raw));
-    output.append(&mut data);
+fn read_dictionary(filename: &str) -> Result<Dictionary, ExitCode> {
+    let data =
+        std::fs::read(filename).map_err(|e| report(&format!("{}: {}", filename, e), EXIT_IO))?;
+    Dictionary::restore_from(&data)
+        .map_err(|e| report(&format!("{}: {}", filename, e), exit_code(&e)))
 }
 
-fn main() -> Result<(), std::io::Error> {
-    let matches = Command::new("Press")
+fn main() -> ExitCode {
+    let mut command = Command::new("Press")
         .version("1.0")
         .author("Brice Fernandes <brice@fractallambda.com>")
         .about("Basic Huffam coding file compressor.")
-        .arg(Arg::new("INPUT").help("Input file"))
-        .arg(Arg::new("OUTPUT").help("Output file."))
+        .arg(
+            Arg::new("FILE")
+                .help("Files to compress to FILE.press, or with -d to decompress to their original names. With none, or with -, read stdin and write stdout.")
+                .action(ArgAction::Append),
+        )
         .arg(
             Arg::new("decompress")
                 .short('d')
-                .long("Decompress input file instead of compressing.")
+                .long("decompress")
+                .help("Decompress instead of compressing.")
                 .action(ArgAction::SetTrue),
         )
         .arg(
             Arg::new("compress")
+                .short('z')
+                .long("compress")
+                .help("Compress, which is the default.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("decompress"),
+        )
+        .arg(
+            Arg::new("stdout")
                 .short('c')
-                .long("Compress input file to output file.")
+                .long("stdout")
+                .help("Write to stdout, keeping the input files.")
                 .action(ArgAction::SetTrue),
         )
-        .get_matches();
+        .arg(
+            Arg::new("keep")
+                .short('k')
+                .long("keep")
+                .help("Keep the input files instead of deleting them.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("force")
+                .short('f')
+                .long("force")
+                .help("Overwrite existing output files, and write compressed data to a terminal.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Report what happened to each file, with -vv each phase of the work and its timing too, and with -vvv the code tables.")
+                .action(ArgAction::Count)
+                .global(true),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Report only errors, without warnings or progress bars.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .args(level_args())
+        .group(ArgGroup::new("level").args(LEVELS))
+        .arg(
+            Arg::new("auto")
+                .long("auto")
+                .help("Try each method on the start of the input and use the one that compresses it smallest, storing data that none makes smaller. Not used with --format gzip.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["method", "dict"]),
+        )
+        .arg(max_code_length_arg())
+        .arg(method_arg())
+        .arg(transforms_arg())
+        .arg(
+            Arg::new("symbols")
+                .long("symbols")
+                .help("Symbols of the huffman method: bytes, 16-bit words, or digrams to code frequent byte pairs as one symbol.")
+                .value_parser(["bytes", "words", "digrams"])
+                .default_value("bytes"),
+        )
+        .arg(order_arg())
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .help("Format to compress to. gzip files are recognised when decompressing.")
+                .value_parser(["press", "gzip"])
+                .default_value("press"),
+        )
+        .arg(dictionary_arg())
+        .arg(
+            Arg::new("index")
+                .long("index")
+                .help("Write an index of the blocks, so 'press cat --range' can decompress part of the file quickly.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(threads_arg())
+        .arg(
+            Arg::new("encrypt")
+                .short('e')
+                .long("encrypt")
+                .help("Encrypt the compressed data with a key derived from the passphrase given with --key-file or PRESS_PASSPHRASE. Encrypted files are decrypted with the same passphrase, and are not indexed.")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["decompress", "index"]),
+        )
+        .arg(key_file_arg())
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("train")
+                .about("Build a dictionary from sample files.")
+                .arg(
+                    Arg::new("DIR")
+                        .help("Directory of sample files.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Dictionary file to write.")
+                        .required(true),
+                )
+                .arg(max_code_length_arg()),
+        )
+        .subcommand(
+            Command::new("cat")
+                .about("Decompress part of a file to stdout.")
+                .arg(
+                    Arg::new("FILE")
+                        .help("Compressed file.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("range")
+                        .long("range")
+                        .value_name("A-B")
+                        .help("Bytes to decompress, from A up to but not including B, or from A to the end with A-. Files compressed with --index only have the blocks holding them decompressed.")
+                        .value_parser(parse_range),
+                )
+                .arg(dictionary_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            Command::new("archive")
+                .about("Pack a directory tree, with file names, permissions, mtimes and symlinks, into an archive.")
+                .arg(
+                    Arg::new("DIR")
+                        .help("Directory to archive.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Archive file to write.")
+                        .required(true),
+                )
+                .arg(method_arg())
+                .arg(max_code_length_arg()),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List the entries of an archive.")
+                .arg(
+                    Arg::new("ARCHIVE")
+                        .help("Archive file.")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Unpack an archive. Entries that would land outside the destination are refused.")
+                .arg(
+                    Arg::new("ARCHIVE")
+                        .help("Archive file.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("DIR")
+                        .help("Directory to unpack into.")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("preserve-permissions")
+                        .long("preserve-permissions")
+                        .help("Also restore the setuid, setgid and sticky bits of entries, which are dropped by default.")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Show the entropy and Huffman codes of a file, and where the bytes of it compressed go. Compressed files are described as they are, with their data decompressed; other files as compressed with --method and --transforms.")
+                .arg(
+                    Arg::new("FILE")
+                        .help("File to inspect, compressed or not.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("block")
+                        .long("block")
+                        .value_name("N")
+                        .help("Block whose stored code table to show, counting from 1.")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("tree")
+                        .long("tree")
+                        .help("Also draw the Huffman tree of the data.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("export-table")
+                        .long("export-table")
+                        .value_name("FILE")
+                        .help("Write the code table of the block to FILE, or to stdout for -, instead of the report."),
+                )
+                .arg(
+                    Arg::new("table-format")
+                        .long("table-format")
+                        .help("Format of the exported code table.")
+                        .value_parser(["json", "cbor"])
+                        .default_value("json"),
+                )
+                .arg(method_arg())
+                .arg(transforms_arg())
+                .arg(max_code_length_arg())
+                .arg(dictionary_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Compress and decompress every file under a directory, reporting the ratio, speed, peak heap use and code table overhead of each.")
+                .arg(
+                    Arg::new("DIR")
+                        .help("Directory of files to compress, such as tests.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("runs")
+                        .long("runs")
+                        .value_name("N")
+                        .help("Times to compress and decompress each file, keeping the fastest.")
+                        .value_parser(clap::value_parser!(u16).range(1..))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("csv")
+                        .long("csv")
+                        .help("Write CSV instead of a table.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("baseline")
+                        .long("baseline")
+                        .value_name("FILE")
+                        .help("Results saved by an earlier run with --save-baseline, to show the change in size and speed from."),
+                )
+                .arg(
+                    Arg::new("save-baseline")
+                        .long("save-baseline")
+                        .value_name("FILE")
+                        .help("Save the results as JSON, for later runs to compare against."),
+                )
+                .args(level_args())
+                .group(ArgGroup::new("level").args(LEVELS))
+                .arg(method_arg())
+                .arg(order_arg())
+                .arg(transforms_arg())
+                .arg(max_code_length_arg())
+                .arg(threads_arg()),
+        );
+    let matches = command.get_matches_mut();
+    init_logging(&matches);
 
-    let mut input: Box<dyn BufRead>;
-    let mut output: Box<dyn Write>;
+    match matches.subcommand() {
+        Some(("train", train_matches)) => return train(train_matches),
+        Some(("cat", cat_matches)) => return cat(cat_matches),
+        Some(("archive", archive_matches)) => return archive(archive_matches),
+        Some(("list", list_matches)) => return list(list_matches),
+        Some(("extract", extract_matches)) => return extract(extract_matches),
+        Some(("inspect", inspect_matches)) => return inspect(inspect_matches),
+        Some(("bench", bench_matches)) => return run_bench(bench_matches),
+        _ => {}
+    }
+    let dictionary = match matches.get_one::<String>("dict") {
+        Some(filename) => match read_dictionary(filename) {
+            Ok(dictionary) => Some(dictionary),
+            Err(code) => return code,
+        },
+        None => None,
+    };
 
-    if atty::is(atty::Stream::Stdin) {
-        if let Some(filename) = matches.get_one::<String>("INPUT") {
-            if filename == "-" {
-                input = Box::new(BufReader::new(std::io::stdin()));
-            } else {
-                let file = File::open(filename)
-                    .unwrap_or_else(|_| panic!("Could not open file {}", filename));
-                input = Box::new(BufReader::new(file));
+    let decompress = matches.get_flag("decompress");
+    let files: Vec<&String> = matches.get_many("FILE").unwrap_or_default().collect();
+    let reads_stdin = files.is_empty() || files.iter().any(|f| *f == "-");
+    if reads_stdin && atty::is(atty::Stream::Stdin) {
+        command
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "Must specify an input file, or pipe data to stdin.",
+            )
+            .exit();
+    }
+    let writes_stdout = reads_stdin || matches.get_flag("stdout");
+    if writes_stdout && !decompress && !matches.get_flag("force") && atty::is(atty::Stream::Stdout)
+    {
+        command
+            .error(
+                ErrorKind::ArgumentConflict,
+                "Refusing to write compressed data to a terminal. Use -f to force it.",
+            )
+            .exit();
+    }
+
+    let passphrase = match read_passphrase(&matches) {
+        Ok(passphrase) => passphrase,
+        Err(code) => return code,
+    };
+    let gzip = matches.get_one::<String>("format").unwrap() == "gzip";
+    // Derived once, as it is slow on purpose; each file still gets a nonce
+    // of its own.
+    let encryption = match (matches.get_flag("encrypt"), &passphrase) {
+        (false, _) => None,
+        (true, _) if gzip => command
+            .error(
+                ErrorKind::ArgumentConflict,
+                "gzip files cannot be encrypted.",
+            )
+            .exit(),
+        (true, None) => command
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--encrypt needs a passphrase, from --key-file or PRESS_PASSPHRASE.",
+            )
+            .exit(),
+        (true, Some(passphrase)) => match Key::from_passphrase(passphrase) {
+            Ok(key) => Some(key),
+            Err(e) => return report(&e, exit_code(&e)),
+        },
+    };
+
+    let threads = *matches.get_one::<u16>("threads").unwrap() as usize;
+    let convert = |input: &mut dyn BufRead, output: &mut dyn Write| {
+        if decompress {
+            let options = DecompressOptions {
+                threads,
+                dictionary: dictionary.clone(),
+                passphrase: passphrase.clone(),
+            };
+            return press::decompress_with(input, output, &options);
+        }
+        let options = CompressOptions {
+            alphabet: match matches.get_one::<String>("symbols").unwrap().as_str() {
+                "words" => Alphabet::Words,
+                "digrams" => Alphabet::Digrams,
+                _ => Alphabet::Bytes,
+            },
+            threads,
+            index: matches.get_flag("index"),
+            encryption,
+            ..coding_options(&matches)
+        };
+        match (
+            matches.get_one::<String>("format").unwrap().as_str(),
+            &dictionary,
+        ) {
+            ("gzip", _) => press::gzip::compress(input, output, &options),
+            (_, Some(dictionary)) => {
+                press::compress_with_dictionary(input, output, &options, dictionary)
             }
-        } else {
-            panic!("Must specify an input file.");
+            (_, None) if matches.get_flag("auto") => {
+                press::compress_auto(input, output, &options).map(|_| ())
+            }
+            (_, None) => press::compress_with(input, output, &options),
         }
-    } else {
-        input = Box::new(BufReader::new(std::io::stdin()));
+    };
+
+    if files.is_empty() {
+        return match convert_stream(&mut std::io::stdin().lock(), &convert) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => report(&e, exit_code(&e)),
+        };
     }
 
-    if atty::is(atty::Stream::Stdout) {
-        if let Some(filename) = matches.get_one::<String>("OUTPUT") {
-            if filename == "-" {
-                output = Box::new(BufWriter::new(std::io::stdout()));
-            } else {
-                let file = File::open(filename)
-                    .unwrap_or_else(|_| panic!("Could not open file {}", filename));
-                output = Box::new(file);
-            }
+    let suffix = if gzip { GZIP_SUFFIX } else { SUFFIX };
+    let job = Job {
+        decompress,
+        suffix,
+        to_stdout: matches.get_flag("stdout"),
+        keep: matches.get_flag("keep"),
+        force: matches.get_flag("force"),
+        progress: !matches.get_flag("quiet") && atty::is(atty::Stream::Stderr),
+    };
+    // Carry on with the other files after a failure, as gzip does, and exit
+    // with the code of the first.
+    let mut status = ExitCode::SUCCESS;
+    let mut failed = false;
+    for filename in files {
+        let result = if filename == "-" {
+            convert_stream(&mut std::io::stdin().lock(), &convert)
+                .map_err(|e| report(&e, exit_code(&e)))
         } else {
-            panic!("Must specify an output file.");
+            job.run(Path::new(filename), &convert)
+        };
+        if let Err(code) = result {
+            if !failed {
+                status = code;
+                failed = true;
+            }
         }
-    } else {
-        output = Box::new(BufWriter::new(std::io::stdout()));
     }
+    status
+}
 
-    let mut in_buf: Vec<u8> = Vec::new();
-    let mut out_buf: Vec<u8> = Vec::new();
-    input.read_to_end(&mut in_buf)?;
+/// Suffix of compressed files, added when compressing and removed when
+/// decompressing.
+const SUFFIX: &str = ".press";
 
-    if matches.get_flag("decompress") {
-        decompress(&in_buf, &mut out_buf);
-    } else if matches.get_flag("compress") {
-        compress(&in_buf, &mut out_buf);
-    } else {
-        panic!("Must specify either compression or decompression. See --help option.");
-    }
+/// Suffix of files compressed with `--format gzip`.
+const GZIP_SUFFIX: &str = ".gz";
+
+/// Compresses or decompresses from one stream to another.
+type Convert<'a> = dyn Fn(&mut dyn BufRead, &mut dyn Write) -> Result<(), HuffmanError> + 'a;
+
+/// Convert stdin, or any other stream, to stdout.
+fn convert_stream(input: &mut dyn BufRead, convert: &Convert) -> Result<(), HuffmanError> {
+    let mut output = BufWriter::new(std::io::stdout().lock());
+    convert(input, &mut output)?;
+    Ok(output.flush()?)
+}
 
-    output.write(&out_buf)?;
+/// How each input file named on the command line is handled.
+struct Job {
+    decompress: bool,
+    suffix: &'static str,
+    to_stdout: bool,
+    keep: bool,
+    force: bool,
+    /// Draw a progress bar on stderr for large files.
+    progress: bool,
+}
 
-    // eprintln!("in: {}, out: {}, ratio: {}", in_buf.len(), out_buf.len(), out_buf.len() as f64/in_buf.len() as f64);
+impl Job {
+    /// Convert the file at `path`, to stdout or to a file beside it, and
+    /// delete it unless told to keep it. Failures are reported here.
+    fn run(&self, path: &Path, convert: &Convert) -> Result<(), ExitCode> {
+        let fail = |message: &dyn std::fmt::Display, code| {
+            Err(report(&format!("{}: {}", path.display(), message), code))
+        };
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return fail(&e, EXIT_IO),
+        };
+        if !metadata.is_file() {
+            return fail(&"not a regular file, skipped", EXIT_IO);
+        }
+        let input = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => return fail(&e, EXIT_IO),
+        };
+        let mut input: Box<dyn BufRead> = if self.progress && metadata.len() >= PROGRESS_MIN_SIZE {
+            Box::new(Progress::new(input, path, metadata.len()))
+        } else {
+            Box::new(input)
+        };
+        let start = Instant::now();
+
+        if self.to_stdout {
+            convert_stream(&mut input, convert).or_else(|e| fail(&e, exit_code(&e)))?;
+            drop(input);
+            info!(
+                "{}: {} bytes to stdout in {:.2?}",
+                path.display(),
+                metadata.len(),
+                start.elapsed()
+            );
+            return Ok(());
+        }
+        let target = match output_path(path, self.decompress, self.suffix) {
+            Ok(target) => target,
+            Err(message) => return fail(&message, EXIT_USAGE),
+        };
+        if !self.force && target.symlink_metadata().is_ok() {
+            let message = format!(
+                "{} already exists; use -f to overwrite it",
+                target.display()
+            );
+            return fail(&message, EXIT_IO);
+        }
 
-    return Ok(());
+        let result = write_atomically(&target, |output| {
+            convert(&mut input, output)?;
+            output.flush()?;
+            let file = output.get_ref();
+            file.set_permissions(metadata.permissions())?;
+            file.set_modified(metadata.modified()?)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            return fail(&e, exit_code(&e));
+        }
+        drop(input);
+        let written = target.metadata().map_or(0, |metadata| metadata.len());
+        info!(
+            "{}: {} to {} bytes ({:.1}%), written to {} in {:.2?}",
+            path.display(),
+            metadata.len(),
+            written,
+            100.0 * written as f64 / metadata.len().max(1) as f64,
+            target.display(),
+            start.elapsed()
+        );
+        if !self.keep {
+            if let Err(e) = std::fs::remove_file(path) {
+                return fail(&e, EXIT_IO);
+            }
+        }
+        Ok(())
+    }
 }
 
-fn u32_to_u8s(i: u32) -> Vec<u8> {
-    // return i.to_be_bytes().to_vec();
-    Vec::from([
-        ((i >> 24) & 0xff) as u8,
-        ((i >> 16) & 0xff) as u8,
-        ((i >> 8) & 0xff) as u8,
-        (i & 0xff) as u8,
-    ])
+/// Name of the file `path` compresses to, or decompresses to when
+/// `decompress` is set, by adding or removing `suffix`. Files are
+/// decompressed from either suffix, as gzip files are recognised.
+fn output_path(path: &Path, decompress: bool, suffix: &str) -> Result<PathBuf, String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("file name is not valid UTF-8")?;
+    let strip = |suffix| name.strip_suffix(suffix).filter(|stem| !stem.is_empty());
+    if decompress {
+        let stem = strip(SUFFIX)
+            .or_else(|| strip(GZIP_SUFFIX))
+            .ok_or_else(|| format!("unknown suffix, expected {} or {}", SUFFIX, GZIP_SUFFIX))?;
+        Ok(path.with_file_name(stem))
+    } else if strip(suffix).is_some() {
+        Err(format!("already has the {} suffix", suffix))
+    } else {
+        Ok(path.with_file_name(format!("{}{}", name, suffix)))
+    }
 }
 
-fn u8s_to_u32(us: &Vec<u8>) -> u32 {
-    // return u32::from_be_bytes(us);
-    let mut out: u32 = 0;
-    out |= (us[0] as u32) << 24;
-    out |= (us[1] as u32) << 16;
-    out |= (us[2] as u32) << 8;
-    out |= us[3] as u32;
-    return out;
+/// Write `target` through `write`, so that it is either left as it was or
+/// replaced with the complete output. The output goes to a temporary file in
+/// the same directory, which is synced and renamed over `target` once
+/// `write` succeeds, and removed if it fails.
+fn write_atomically<F>(target: &Path, write: F) -> Result<(), HuffmanError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), HuffmanError>,
+{
+    let name = target.file_name().unwrap().to_string_lossy();
+    let temp = target.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let file = File::options().write(true).create_new(true).open(&temp)?;
+
+    let mut output = BufWriter::new(file);
+    let result = write(&mut output)
+        .and_then(|()| Ok(output.into_inner().map_err(|e| e.into_error())?))
+        .and_then(|file| Ok(file.sync_all()?))
+        .and_then(|()| Ok(std::fs::rename(&temp, target)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
 }
 
 #[cfg(test)]
@@ -144,76 +1332,98 @@ mod tests {
 
     use super::*;
 
-    fn _get_tlen(input: &Vec<u8>) -> u32 {
-        if input[0..MAGIC.len()] != MAGIC {
-            panic!("File is not in press format.");
-        }
-        u8s_to_u32(&Vec::from(&input[MAGIC.len()..MAGIC.len() + 4]))
-    }
-
     #[test]
-    fn can_encode_decode_u32() {
-        for i in 0..400 {
-            let xs = u32_to_u8s(i);
-            let y = u8s_to_u32(&xs);
-            assert_eq!(i, y);
+    fn error_classes_have_distinct_exit_codes() {
+        let codes = [
+            exit_code(&HuffmanError::Io(std::io::ErrorKind::Other.into())),
+            exit_code(&HuffmanError::BadMagic),
+            exit_code(&HuffmanError::Truncated),
+            exit_code(&HuffmanError::WrongDictionary {
+                expected: 0,
+                actual: None,
+            }),
+            exit_code(&HuffmanError::BadTable("")),
+            exit_code(&HuffmanError::WrongPassphrase),
+            exit_code(&HuffmanError::ChecksumMismatch {
+                expected: 0,
+                actual: 1,
+            }),
+        ];
+
+        for (i, code) in codes.iter().enumerate() {
+            assert!(*code != 0 && *code != 2);
+            assert!(!codes[..i].contains(code));
         }
     }
 
     #[test]
-    fn can_get_encoding_table_length() {
-        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
-        let mut out_buf: Vec<u8> = Vec::new();
-
-        let encoding_original = HuffmanEncoding::from_data_vec(&in_buf);
-        let saved = encoding_original.save();
-        let expected_length = saved.len() as u32;
-
-        compress(&in_buf, &mut out_buf);
-
-        assert_eq!(_get_tlen(&out_buf), expected_length)
+    fn parses_ranges() {
+        assert_eq!(parse_range("1000-2000"), Ok(1000..2000));
+        assert_eq!(parse_range("5-"), Ok(5..u64::MAX));
+        assert!(parse_range("2000-1000").is_err());
+        assert!(parse_range("1000").is_err());
+        assert!(parse_range("a-b").is_err());
     }
 
     #[test]
-    fn encoding_will_always_have_eot() {
-        let in_buf: Vec<u8> = b"".to_vec();
-        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
-
-        assert!(encoding.encoding.contains_key(&huffman::Symbol::EOT));
+    fn formats_mtimes() {
+        assert_eq!(format_mtime(0), "1970-01-01 00:00");
+        assert_eq!(format_mtime(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_mtime(1_700_000_000), "2023-11-14 22:13");
+        assert_eq!(format_mtime(-60), "1969-12-31 23:59");
     }
 
     #[test]
-    fn decoded_table_has_eot() {
-        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
-
-        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
-        let saved = encoding.save();
-        let restored = HuffmanEncoding::restore_from(&saved);
+    fn progress_passes_data_through() {
+        let data: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        let mut progress = Progress::new(BufReader::new(&data[..]), Path::new("data"), 100_000);
+        let mut read = Vec::new();
+        progress.read_to_end(&mut read).unwrap();
 
-        assert!(restored.encoding.contains_key(&huffman::Symbol::EOT));
+        assert_eq!(read, data);
+        assert_eq!(progress.done, 100_000);
+        drop(progress);
+        assert!(!PROGRESS_SHOWN.load(Ordering::Relaxed));
     }
 
     #[test]
-    fn can_compress_decompress() {
-        let in_buf: Vec<u8> = b"Hello World".to_vec();
-        let mut compressed_buf: Vec<u8> = Vec::new();
-        let mut out_buf: Vec<u8> = Vec::new();
+    fn names_output_files() {
+        let output = |path, decompress, suffix| output_path(Path::new(path), decompress, suffix);
 
-        compress(&in_buf, &mut compressed_buf);
-        decompress(&compressed_buf, &mut out_buf);
-
-        assert_eq!(in_buf, out_buf);
+        assert_eq!(
+            output("dir/a.txt", false, SUFFIX),
+            Ok("dir/a.txt.press".into())
+        );
+        assert_eq!(output("a.txt", false, GZIP_SUFFIX), Ok("a.txt.gz".into()));
+        assert_eq!(
+            output("dir/a.txt.press", true, SUFFIX),
+            Ok("dir/a.txt".into())
+        );
+        assert_eq!(output("a.txt.gz", true, SUFFIX), Ok("a.txt".into()));
+        assert!(output("a.txt.press", false, SUFFIX).is_err());
+        assert!(output("a.txt", true, SUFFIX).is_err());
+        assert!(output(".press", true, SUFFIX).is_err());
     }
 
     #[test]
-    fn test_encoding_serialise_deserialise() {
-        let in_buf: Vec<u8> = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Morbi condimentum gravida libero non mollis. Mauris turpis sapien, interdum non tortor id, sollicitudin lobortis mi. Nam sit amet tellus vehicula, condimentum.".to_vec();
+    fn writes_atomically() {
+        let dir = std::env::temp_dir().join(format!("press-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("out");
+        std::fs::write(&target, b"old").unwrap();
 
-        let encoding = HuffmanEncoding::from_data_vec(&in_buf);
-        let saved = encoding.save();
-        let restored = HuffmanEncoding::restore_from(&saved);
+        let result = write_atomically(&target, |output| {
+            output.write_all(b"partial")?;
+            Err(HuffmanError::Truncated)
+        });
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+        assert_eq!(std::fs::read(&target).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
 
-        assert_eq!(encoding, restored);
-    }
+        write_atomically(&target, |output| Ok(output.write_all(b"new")?)).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
 
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
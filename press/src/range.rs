@@ -0,0 +1,352 @@
+//! Range coding, for the `range` method.
+//!
+//! A range coder narrows an interval by the probability of each symbol, so
+//! unlike a Huffman code it does not round every symbol to a whole number of
+//! bits. Probabilities come from adaptive frequency counts that encoder and
+//! decoder update in step, so the only model stored is its context order:
+//! with order 0 every byte is coded with the same counts, with order 1 each
+//! byte is coded with counts kept for the byte before it.
+//!
+//! The coder follows LZMA's, carrying into bytes already written through a
+//! one byte cache. The stream ends with EOT and five flushed bytes, which is
+//! exactly what the decoder reads.
+
+use crate::entropy::EntropyCoder;
+use crate::huffman::{HuffmanError, Symbol};
+use crate::CompressOptions;
+
+use std::io::{BufRead, Write};
+
+/// Number of symbols: every byte value and EOT.
+const SYMBOLS: usize = 257;
+
+/// Added to a symbol's count each time it is coded.
+const INCREMENT: u32 = 24;
+
+/// Counts are halved when their total goes above this, which both keeps
+/// `range / total` precise and lets the model follow changes in the data.
+const MAX_TOTAL: u32 = 1 << 16;
+
+/// The range is renormalised by shifting out a byte whenever it falls below
+/// this.
+const TOP: u32 = 1 << 24;
+
+/// Highest context order supported.
+pub const MAX_ORDER: u8 = 1;
+
+/// Adaptive symbol counts for each context.
+struct Model {
+    freqs: Vec<u32>,
+    totals: Vec<u32>,
+}
+
+impl Model {
+    fn new(contexts: usize) -> Model {
+        Model {
+            freqs: vec![1; contexts * SYMBOLS],
+            totals: vec![SYMBOLS as u32; contexts],
+        }
+    }
+
+    fn counts(&self, context: usize) -> &[u32] {
+        &self.freqs[context * SYMBOLS..(context + 1) * SYMBOLS]
+    }
+
+    /// Cumulative count of the symbols before `symbol`, and its count.
+    fn interval(&self, context: usize, symbol: usize) -> (u32, u32) {
+        let counts = self.counts(context);
+        (counts[..symbol].iter().sum(), counts[symbol])
+    }
+
+    /// The symbol whose interval holds `value`, with its interval.
+    fn find(&self, context: usize, value: u32) -> (usize, u32, u32) {
+        let mut cum = 0;
+        for (symbol, freq) in self.counts(context).iter().enumerate() {
+            if value < cum + freq {
+                return (symbol, cum, *freq);
+            }
+            cum += freq;
+        }
+        unreachable!("value is below the total count")
+    }
+
+    fn update(&mut self, context: usize, symbol: usize) {
+        let counts = &mut self.freqs[context * SYMBOLS..(context + 1) * SYMBOLS];
+        counts[symbol] += INCREMENT;
+        self.totals[context] += INCREMENT;
+        if self.totals[context] > MAX_TOTAL {
+            for freq in counts.iter_mut() {
+                *freq = freq.div_ceil(2);
+            }
+            self.totals[context] = counts.iter().sum();
+        }
+    }
+}
+
+struct RangeEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl RangeEncoder {
+    fn new() -> Self {
+        RangeEncoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, cum: u32, freq: u32, total: u32) {
+        let r = self.range / total;
+        self.low += r as u64 * cum as u64;
+        self.range = r * freq;
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// Move the top byte of `low` out, holding back a run of 0xFF bytes
+    /// until it is known whether a carry will ripple through them.
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+struct RangeDecoder<'a, R: BufRead> {
+    input: &'a mut R,
+    code: u32,
+    range: u32,
+}
+
+impl<'a, R: BufRead> RangeDecoder<'a, R> {
+    fn new(input: &'a mut R) -> Result<Self, HuffmanError> {
+        let mut decoder = RangeDecoder {
+            input,
+            code: 0,
+            range: u32::MAX,
+        };
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte()? as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn next_byte(&mut self) -> Result<u8, HuffmanError> {
+        let mut byte = [0u8; 1];
+        self.input.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Decode the next symbol with `model`.
+    fn decode(&mut self, model: &Model, context: usize) -> Result<usize, HuffmanError> {
+        let total = model.totals[context];
+        let r = self.range / total;
+        let value = (self.code / r).min(total - 1);
+        let (symbol, cum, freq) = model.find(context, value);
+
+        self.code -= r * cum;
+        self.range = r * freq;
+        while self.range < TOP {
+            self.code = (self.code << 8) | self.next_byte()? as u32;
+            self.range <<= 8;
+        }
+        Ok(symbol)
+    }
+}
+
+/// A range coder with an adaptive model of the given context order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeEncoding {
+    order: u8,
+}
+
+impl RangeEncoding {
+    /// # Panics
+    ///
+    /// Panics if `order` is above `MAX_ORDER`.
+    pub fn new(order: u8) -> RangeEncoding {
+        assert!(order <= MAX_ORDER, "Context order {} not supported", order);
+        RangeEncoding { order }
+    }
+
+    fn model(&self) -> Model {
+        Model::new(if self.order == 0 { 1 } else { 256 })
+    }
+
+    fn context(&self, prev: u8) -> usize {
+        if self.order == 0 {
+            0
+        } else {
+            prev as usize
+        }
+    }
+}
+
+impl EntropyCoder for RangeEncoding {
+    fn for_block(_block: &[u8], options: &CompressOptions) -> Result<Self, HuffmanError> {
+        Ok(RangeEncoding::new(options.context_order))
+    }
+
+    fn save(&self) -> Vec<u8> {
+        vec![self.order]
+    }
+
+    fn restore_from(d: &[u8]) -> Result<Self, HuffmanError> {
+        match d {
+            [order] if *order <= MAX_ORDER => Ok(RangeEncoding { order: *order }),
+            _ => Err(HuffmanError::BadTable("unknown context order")),
+        }
+    }
+
+    fn encode(&self, input: &[u8]) -> Vec<u8> {
+        let mut model = self.model();
+        let mut encoder = RangeEncoder::new();
+        let mut prev = 0;
+
+        let symbols = input.iter().map(|c| Symbol::Char(*c)).chain([Symbol::EOT]);
+        for symbol in symbols {
+            let context = self.context(prev);
+            let (cum, freq) = model.interval(context, symbol.index());
+            encoder.encode(cum, freq, model.totals[context]);
+            model.update(context, symbol.index());
+            if let Symbol::Char(c) = symbol {
+                prev = c;
+            }
+        }
+        encoder.finish()
+    }
+
+    fn decode_from<R: BufRead, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        limit: usize,
+    ) -> Result<(), HuffmanError> {
+        let mut model = self.model();
+        let mut decoder = RangeDecoder::new(input)?;
+        let mut out: Vec<u8> = Vec::new();
+        let mut prev = 0;
+
+        loop {
+            let context = self.context(prev);
+            let symbol = decoder.decode(&model, context)?;
+            model.update(context, symbol);
+            match Symbol::from_index(symbol) {
+                Symbol::Char(c) if out.len() < limit => {
+                    out.push(c);
+                    prev = c;
+                }
+                Symbol::Char(_) => {
+                    return Err(HuffmanError::BadBitstream("more data than the block holds"))
+                }
+                _ => break,
+            }
+        }
+        output.write_all(&out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::HuffmanEncoding;
+
+    fn round_trip(coder: &RangeEncoding, data: &[u8]) -> Vec<u8> {
+        let encoded = coder.encode(data);
+        let mut input = &encoded[..];
+        let mut out = Vec::new();
+        coder.decode_from(&mut input, &mut out, usize::MAX).unwrap();
+        assert!(input.is_empty(), "decoder left {} bytes", input.len());
+        out
+    }
+
+    #[test]
+    fn can_round_trip() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        for order in 0..=MAX_ORDER {
+            let coder = RangeEncoding::new(order);
+            assert_eq!(round_trip(&coder, &text), text);
+            assert_eq!(round_trip(&coder, b""), b"");
+            assert_eq!(round_trip(&coder, &[0xFF; 10_000]), vec![0xFF; 10_000]);
+        }
+    }
+
+    #[test]
+    fn beats_huffman_on_skewed_data() {
+        // One byte in a hundred is not an 'a': Huffman needs a whole bit for
+        // each 'a', the range coder much less.
+        let data: Vec<u8> = (0..100_000)
+            .map(|i| if i % 100 == 0 { b'b' } else { b'a' })
+            .collect();
+        let range = RangeEncoding::new(0).encode(&data).len();
+        let huffman = HuffmanEncoding::from_data_vec(&data).encode(&data).len();
+
+        assert!(range * 4 < huffman);
+    }
+
+    #[test]
+    fn order_1_beats_order_0_on_text() {
+        let text = std::fs::read("tests/test-100kB.txt").unwrap();
+        let order_0 = RangeEncoding::new(0).encode(&text).len();
+        let order_1 = RangeEncoding::new(1).encode(&text).len();
+
+        assert!(order_1 < order_0);
+    }
+
+    #[test]
+    fn rejects_unknown_order() {
+        let result = RangeEncoding::restore_from(&[2]);
+        assert!(matches!(result, Err(HuffmanError::BadTable(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_stream() {
+        let encoded = RangeEncoding::new(1).encode(b"Hello World");
+        let mut input = &encoded[..encoded.len() - 1];
+
+        let result = RangeEncoding::new(1).decode_from(&mut input, &mut Vec::new(), usize::MAX);
+        assert!(matches!(result, Err(HuffmanError::Truncated)));
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let data = [0xFF; 100_000];
+        let coder = RangeEncoding::new(0);
+        let encoded = coder.encode(&data);
+        let mut out = Vec::new();
+
+        let result = coder.decode_from(&mut &encoded[..], &mut out, 1000);
+        assert!(matches!(result, Err(HuffmanError::BadBitstream(_))));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+//! Reading parts of a press file without decompressing all of it.
+
+use crate::container::{self, IndexEntry};
+use crate::{Decoder, DecompressOptions, HuffmanError};
+
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Decompresses ranges of a press file.
+///
+/// Files compressed with `CompressOptions::index` are read through their
+/// block index, decoding only the blocks that hold the range and checking
+/// each against the checksum in the index. Other files are decompressed from
+/// the start up to the end of the range.
+pub struct IndexedReader<R: Read + Seek> {
+    input: BufReader<R>,
+    options: DecompressOptions,
+    index: Option<Vec<IndexEntry>>,
+    size: Option<u64>,
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    /// Read the header and, if there is one, the block index of `input`.
+    pub fn open(input: R, options: DecompressOptions) -> Result<Self, HuffmanError> {
+        let mut input = BufReader::new(input);
+        let mut decoder = Decoder::with_options(&mut input, options.clone());
+        decoder.read_header()?;
+
+        let (index, size) = if decoder.is_indexed() {
+            let (index, size) = container::read_index(&mut input)?;
+            (Some(index), Some(size))
+        } else {
+            (None, None)
+        };
+        Ok(IndexedReader {
+            input,
+            options,
+            index,
+            size,
+        })
+    }
+
+    /// Size of the decompressed data, if the file has an index to say so.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Decompress the bytes of `range` to `output`. The range is cut short
+    /// at the end of the data.
+    pub fn read_range<W: Write>(
+        &mut self,
+        range: Range<u64>,
+        output: &mut W,
+    ) -> Result<(), HuffmanError> {
+        self.input.seek(SeekFrom::Start(0))?;
+        let mut decoder = Decoder::with_options(&mut self.input, self.options.clone());
+
+        let Some(index) = &self.index else {
+            let mut offset = 0;
+            while offset < range.end && decoder.next_block()? {
+                let block = decoder.block();
+                write_part(output, block, offset, &range)?;
+                offset += block.len() as u64;
+            }
+            return Ok(());
+        };
+
+        decoder.read_header()?;
+        let size = self.size.unwrap();
+        for (i, entry) in index.iter().enumerate() {
+            let end = index.get(i + 1).map_or(size, |next| next.original_offset);
+            if end <= range.start || entry.original_offset >= range.end {
+                continue;
+            }
+
+            let block = decoder.read_block_at(entry.offset)?;
+            if block.len() as u64 != end - entry.original_offset {
+                return Err(HuffmanError::SizeMismatch {
+                    expected: end - entry.original_offset,
+                    actual: block.len() as u64,
+                });
+            }
+            let actual = crc32fast::hash(&block);
+            if actual != entry.crc {
+                return Err(HuffmanError::ChecksumMismatch {
+                    expected: entry.crc,
+                    actual,
+                });
+            }
+            write_part(output, &block, entry.original_offset, &range)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the part of `block`, which starts at `offset` in the data, that
+/// falls in `range`.
+fn write_part<W: Write>(
+    output: &mut W,
+    block: &[u8],
+    offset: u64,
+    range: &Range<u64>,
+) -> Result<(), HuffmanError> {
+    let end = offset + block.len() as u64;
+    let from = range.start.clamp(offset, end) - offset;
+    let to = range.end.clamp(offset, end) - offset;
+    output.write_all(&block[from as usize..to as usize])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{compress_with, CompressOptions, Method};
+    use std::io::Cursor;
+
+    fn compressed(data: &[u8], method: Method, index: bool) -> Vec<u8> {
+        let options = CompressOptions {
+            block_size: 1000,
+            method,
+            index,
+            ..CompressOptions::default()
+        };
+        let mut compressed = Vec::new();
+        compress_with(data, &mut compressed, &options).unwrap();
+        compressed
+    }
+
+    fn read_range(compressed: &[u8], range: Range<u64>) -> Result<Vec<u8>, HuffmanError> {
+        let mut reader = IndexedReader::open(Cursor::new(compressed), Default::default())?;
+        let mut out = Vec::new();
+        reader.read_range(range, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn reads_ranges_with_and_without_index() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let ranges = [0..10, 990..1010, 2500..7500, 9990..20_000, 0..20_000, 5..5];
+        for method in [
+            Method::Huffman,
+            Method::Lzh,
+            Method::Adaptive,
+            Method::Range,
+            Method::Stored,
+        ] {
+            for index in [false, true] {
+                let compressed = compressed(&text, method, index);
+                for range in ranges.clone() {
+                    let end = range.end.min(text.len() as u64);
+                    let expected = &text[range.start as usize..end as usize];
+                    assert_eq!(read_range(&compressed, range).unwrap(), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn index_is_read_past_by_decoder() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let compressed = compressed(&text, Method::Huffman, true);
+
+        let reader = IndexedReader::open(Cursor::new(&compressed), Default::default()).unwrap();
+        assert_eq!(reader.size(), Some(text.len() as u64));
+
+        let mut decoder = Decoder::new(&compressed[..]);
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(out, text);
+        assert!(decoder.into_inner().is_empty());
+    }
+
+    #[test]
+    fn only_decodes_blocks_in_range() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed = compressed(&text, Method::Huffman, true);
+        // Corrupt the first block's payload length.
+        compressed[crate::container::MAGIC.len() + 3 + 4] ^= 0x7F;
+
+        assert_eq!(
+            read_range(&compressed, 5000..6000).unwrap(),
+            text[5000..6000]
+        );
+        assert!(read_range(&compressed, 0..10).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_index() {
+        let text = std::fs::read("tests/test-10kB.txt").unwrap();
+        let mut compressed = compressed(&text, Method::Huffman, true);
+        let blocks_at = compressed.len() - 1;
+        compressed[blocks_at] += 1;
+
+        let result = IndexedReader::open(Cursor::new(&compressed), Default::default());
+        assert!(matches!(result, Err(HuffmanError::BadIndex(_))));
+    }
+}
@@ -0,0 +1,449 @@
+//! Reversible transforms applied to each block before it is coded, as bzip2
+//! does, to turn repetition that byte-level codes cannot see into runs and
+//! skewed byte frequencies that they can.
+//!
+//! The stages of a pipeline are packed into a `u32` in the file header, four
+//! bits per stage from the high end in the order they are applied, and ended
+//! by a zero nibble if there are fewer than `MAX_TRANSFORMS`.
+
+use crate::huffman::HuffmanError;
+
+use std::borrow::Cow;
+
+/// Longest pipeline that fits in the header.
+pub const MAX_TRANSFORMS: usize = 8;
+
+/// A stage of a transform pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    /// Run-length encoding: after four equal bytes, a count of how many more
+    /// follow, up to 251.
+    Rle = 1,
+    /// Burrows–Wheeler transform: the last bytes of the sorted rotations of
+    /// the block, which groups bytes by the context that follows them,
+    /// prefixed by the position of the block itself among the rotations.
+    Bwt = 2,
+    /// Move-to-front coding: each byte is replaced by its position in a list
+    /// of recently seen bytes, so that bytes repeated close together become
+    /// small numbers.
+    Mtf = 3,
+}
+
+impl Transform {
+    pub fn from_u8(b: u8) -> Option<Transform> {
+        match b {
+            1 => Some(Transform::Rle),
+            2 => Some(Transform::Bwt),
+            3 => Some(Transform::Mtf),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, block: &[u8]) -> Vec<u8> {
+        match self {
+            Transform::Rle => rle_encode(block),
+            Transform::Bwt => bwt_encode(block),
+            Transform::Mtf => mtf_encode(block),
+        }
+    }
+
+    /// Undo `apply`, failing rather than producing more than `limit` bytes.
+    fn invert(&self, data: &[u8], limit: usize) -> Result<Vec<u8>, HuffmanError> {
+        match self {
+            Transform::Rle => rle_decode(data, limit),
+            Transform::Bwt => bwt_decode(data),
+            Transform::Mtf => Ok(mtf_decode(data)),
+        }
+    }
+
+    /// Most bytes `apply` can turn `len` bytes into.
+    fn max_output_len(&self, len: usize) -> usize {
+        match self {
+            Transform::Rle => len + len / RUN_START + 1,
+            Transform::Bwt => len + 4,
+            Transform::Mtf => len,
+        }
+    }
+}
+
+impl std::str::FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rle" => Ok(Transform::Rle),
+            "bwt" => Ok(Transform::Bwt),
+            "mtf" => Ok(Transform::Mtf),
+            _ => Err(format!("unknown transform '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Transform::Rle => "rle",
+            Transform::Bwt => "bwt",
+            Transform::Mtf => "mtf",
+        })
+    }
+}
+
+/// A pipeline of transforms, applied in order when compressing and undone
+/// in reverse order when decompressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transforms {
+    stages: [Option<Transform>; MAX_TRANSFORMS],
+}
+
+impl Transforms {
+    /// The pipeline of `stages`, or `None` if there are more than
+    /// `MAX_TRANSFORMS`.
+    pub fn new(stages: &[Transform]) -> Option<Transforms> {
+        if stages.len() > MAX_TRANSFORMS {
+            return None;
+        }
+        let mut transforms = Transforms::default();
+        for (slot, stage) in transforms.stages.iter_mut().zip(stages) {
+            *slot = Some(*stage);
+        }
+        Some(transforms)
+    }
+
+    /// The stages, in the order they are applied.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Transform> + '_ {
+        self.stages.iter().flatten().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages[0].is_none()
+    }
+
+    /// Run `block` through the pipeline.
+    pub fn apply<'a>(&self, block: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut data = Cow::Borrowed(block);
+        for stage in self.iter() {
+            data = Cow::Owned(stage.apply(&data));
+        }
+        data
+    }
+
+    /// Most bytes `apply` can turn a block of `len` bytes into.
+    pub(crate) fn max_output_len(&self, len: usize) -> usize {
+        self.iter()
+            .fold(len, |len, stage| stage.max_output_len(len))
+    }
+
+    /// Undo `apply` on the transformed data of a block of `len` bytes. The
+    /// length bounds the data at every stage, so that corrupt data cannot
+    /// expand without limit.
+    pub fn invert(&self, data: Vec<u8>, len: usize) -> Result<Vec<u8>, HuffmanError> {
+        let mut limits = vec![len];
+        for stage in self.iter() {
+            limits.push(stage.max_output_len(*limits.last().unwrap()));
+        }
+        let mut data = data;
+        for (stage, limit) in self.iter().rev().zip(limits.into_iter().rev().skip(1)) {
+            data = stage.invert(&data, limit)?;
+        }
+        Ok(data)
+    }
+
+    /// The pipeline as stored in the file header.
+    pub(crate) fn to_u32(self) -> u32 {
+        self.iter().enumerate().fold(0, |packed, (i, stage)| {
+            packed | (stage as u32) << (28 - 4 * i)
+        })
+    }
+
+    /// Read a pipeline stored by `to_u32`.
+    pub(crate) fn from_u32(packed: u32) -> Result<Transforms, HuffmanError> {
+        let mut transforms = Transforms::default();
+        for (i, slot) in transforms.stages.iter_mut().enumerate() {
+            let id = (packed >> (28 - 4 * i)) as u8 & 0x0F;
+            if id == 0 {
+                if packed << (4 * i) != 0 {
+                    return Err(HuffmanError::UnsupportedTransform(id));
+                }
+                break;
+            }
+            *slot = Some(Transform::from_u8(id).ok_or(HuffmanError::UnsupportedTransform(id))?);
+        }
+        Ok(transforms)
+    }
+}
+
+/// Parses a comma-separated list of stages, such as `bwt,mtf,rle`.
+impl std::str::FromStr for Transforms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let stages = s
+            .split(',')
+            .filter(|stage| !stage.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Transform>, _>>()?;
+        Transforms::new(&stages)
+            .ok_or_else(|| format!("at most {} transforms can be chained", MAX_TRANSFORMS))
+    }
+}
+
+impl std::fmt::Display for Transforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stages: Vec<String> = self.iter().map(|stage| stage.to_string()).collect();
+        f.write_str(&stages.join(","))
+    }
+}
+
+/// Equal bytes in a row after which a count of the rest of the run follows.
+const RUN_START: usize = 4;
+
+/// Longest run one count can extend.
+const MAX_RUN_COUNT: usize = 251;
+
+fn rle_encode(block: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(block.len());
+    let mut i = 0;
+    while i < block.len() {
+        let c = block[i];
+        let run = block[i..]
+            .iter()
+            .take(RUN_START + MAX_RUN_COUNT)
+            .take_while(|b| **b == c)
+            .count();
+        if run < RUN_START {
+            out.extend(std::iter::repeat_n(c, run));
+        } else {
+            out.extend(std::iter::repeat_n(c, RUN_START));
+            out.push((run - RUN_START) as u8);
+        }
+        i += run;
+    }
+    out
+}
+
+fn rle_decode(data: &[u8], limit: usize) -> Result<Vec<u8>, HuffmanError> {
+    let mut out = Vec::with_capacity(data.len().min(limit));
+    let mut i = 0;
+    while i < data.len() {
+        if out.len() > limit {
+            return Err(HuffmanError::BadBitstream("runs longer than the block"));
+        }
+        let c = data[i];
+        let run = data[i..]
+            .iter()
+            .take(RUN_START)
+            .take_while(|b| **b == c)
+            .count();
+        out.extend(std::iter::repeat_n(c, run));
+        i += run;
+        if run == RUN_START {
+            let count = *data
+                .get(i)
+                .ok_or(HuffmanError::BadBitstream("run without a count"))?;
+            out.extend(std::iter::repeat_n(c, count as usize));
+            i += 1;
+        }
+    }
+    if out.len() > limit {
+        return Err(HuffmanError::BadBitstream("runs longer than the block"));
+    }
+    Ok(out)
+}
+
+/// Positions in `block` of its rotations, in sorted order, found by prefix
+/// doubling: rotations are ranked by their first `k` bytes, then by their
+/// first `2k` from the ranks of the two halves, until all ranks differ or
+/// whole rotations are compared.
+fn sorted_rotations(block: &[u8]) -> Vec<usize> {
+    let n = block.len();
+    let mut rank: Vec<u32> = block.iter().map(|c| *c as u32).collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut next = vec![0u32; n];
+
+    let mut k = 1;
+    loop {
+        let key = |i: usize| ((rank[i] as u64) << 32) | rank[(i + k) % n] as u64;
+        order.sort_unstable_by_key(|i| key(*i));
+
+        next[order[0]] = 0;
+        for w in 1..n {
+            let distinct = key(order[w]) != key(order[w - 1]);
+            next[order[w]] = next[order[w - 1]] + distinct as u32;
+        }
+        std::mem::swap(&mut rank, &mut next);
+
+        if rank[order[n - 1]] as usize == n - 1 || k >= n {
+            return order;
+        }
+        k *= 2;
+    }
+}
+
+fn bwt_encode(block: &[u8]) -> Vec<u8> {
+    let n = block.len();
+    if n == 0 {
+        return Vec::new();
+    }
+    let order = sorted_rotations(block);
+    let primary = order.iter().position(|i| *i == 0).unwrap() as u32;
+
+    let mut out = primary.to_be_bytes().to_vec();
+    out.extend(order.iter().map(|i| block[(i + n - 1) % n]));
+    out
+}
+
+fn bwt_decode(data: &[u8]) -> Result<Vec<u8>, HuffmanError> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (primary, last) = data
+        .split_first_chunk::<4>()
+        .ok_or(HuffmanError::BadBitstream("BWT block too short"))?;
+    let primary = u32::from_be_bytes(*primary) as usize;
+    if primary >= last.len() {
+        return Err(HuffmanError::BadBitstream("BWT index out of range"));
+    }
+
+    // The rotation at each row, shifted left by one, is at `next[row]`: the
+    // nth occurrence of a byte in the last column is the nth in the first.
+    let mut starts = [0usize; 256];
+    for c in last {
+        starts[*c as usize] += 1;
+    }
+    let mut sum = 0;
+    for start in starts.iter_mut() {
+        (*start, sum) = (sum, sum + *start);
+    }
+    let mut next = vec![0u32; last.len()];
+    for (row, c) in last.iter().enumerate() {
+        next[starts[*c as usize]] = row as u32;
+        starts[*c as usize] += 1;
+    }
+
+    let mut out = Vec::with_capacity(last.len());
+    let mut row = next[primary] as usize;
+    for _ in 0..last.len() {
+        out.push(last[row]);
+        row = next[row] as usize;
+    }
+    Ok(out)
+}
+
+fn mtf_encode(block: &[u8]) -> Vec<u8> {
+    let mut recent: Vec<u8> = (0..=255).collect();
+    block
+        .iter()
+        .map(|c| {
+            let i = recent.iter().position(|r| r == c).unwrap();
+            recent.copy_within(0..i, 1);
+            recent[0] = *c;
+            i as u8
+        })
+        .collect()
+}
+
+fn mtf_decode(data: &[u8]) -> Vec<u8> {
+    let mut recent: Vec<u8> = (0..=255).collect();
+    data.iter()
+        .map(|i| {
+            let c = recent[*i as usize];
+            recent.copy_within(0..*i as usize, 1);
+            recent[0] = c;
+            c
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn bwt_of_banana() {
+        let out = bwt_encode(b"banana");
+
+        // Rotations sorted: abanan, anaban, ananab, banana, nabana, nanaba.
+        assert_eq!(out[..4], 3u32.to_be_bytes());
+        assert_eq!(&out[4..], b"nnbaaa");
+        assert_eq!(bwt_decode(&out).unwrap(), b"banana");
+    }
+
+    #[test]
+    fn rle_counts_runs_after_four() {
+        assert_eq!(rle_encode(b"aaabbbbcccccc"), b"aaabbbb\x00cccc\x02");
+        assert_eq!(rle_encode(&[7; 300]), [7, 7, 7, 7, 251, 7, 7, 7, 7, 41]);
+        assert_eq!(
+            rle_decode(b"aaabbbb\x00cccc\x02", 13).unwrap(),
+            b"aaabbbbcccccc"
+        );
+        assert!(rle_decode(b"aaabbbb\x00cccc\x02", 12).is_err());
+        assert!(rle_decode(b"aaaa", 100).is_err());
+    }
+
+    #[test]
+    fn mtf_turns_repeats_into_zeros() {
+        assert_eq!(mtf_encode(b"bbbaab"), [98, 0, 0, 98, 0, 1]);
+        assert_eq!(mtf_decode(&[98, 0, 0, 98, 0, 1]), b"bbbaab");
+    }
+
+    #[test]
+    fn pipeline_is_packed_in_order() {
+        let transforms: Transforms = "bwt,mtf,rle".parse().unwrap();
+        assert_eq!(transforms.to_u32(), 0x2310_0000);
+        assert_eq!(Transforms::from_u32(0x2310_0000).unwrap(), transforms);
+        assert_eq!(transforms.to_string(), "bwt,mtf,rle");
+
+        assert!(Transforms::from_u32(0).unwrap().is_empty());
+        assert!(Transforms::from_u32(0x2400_0000).is_err());
+        assert!(Transforms::from_u32(0x2030_0000).is_err());
+        assert!("bwt,zip".parse::<Transforms>().is_err());
+        assert!("rle,rle,rle,rle,rle,rle,rle,rle,rle"
+            .parse::<Transforms>()
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_bad_bwt_index() {
+        let mut out = bwt_encode(b"banana");
+        out[3] = 6;
+        assert!(bwt_decode(&out).is_err());
+        assert!(bwt_decode(&out[..2]).is_err());
+    }
+
+    fn any_transforms() -> impl Strategy<Value = Transforms> {
+        let stage = prop_oneof![
+            Just(Transform::Rle),
+            Just(Transform::Bwt),
+            Just(Transform::Mtf)
+        ];
+        prop::collection::vec(stage, 0..=4).prop_map(|stages| Transforms::new(&stages).unwrap())
+    }
+
+    proptest! {
+        #[test]
+        fn pipelines_round_trip(
+            block in prop_oneof![
+                prop::collection::vec(any::<u8>(), 0..2000),
+                prop::collection::vec(0..3u8, 0..2000),
+                (any::<u8>(), 1..1000usize).prop_map(|(c, n)| vec![c; n]),
+                prop::collection::vec(any::<u8>(), 1..8).prop_map(|unit| unit.repeat(100)),
+            ],
+            transforms in any_transforms(),
+        ) {
+            let transformed = transforms.apply(&block).into_owned();
+            prop_assert_eq!(transforms.invert(transformed, block.len()).unwrap(), block);
+        }
+
+        #[test]
+        fn inverting_arbitrary_data_does_not_panic(
+            data in prop::collection::vec(any::<u8>(), 0..512),
+            transforms in any_transforms(),
+            len in 0..4096usize,
+        ) {
+            let _ = transforms.invert(data, len);
+        }
+    }
+}
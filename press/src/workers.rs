@@ -0,0 +1,125 @@
+//! A pool of threads that encode or decode blocks, kept for as long as the
+//! `Encoder` or `Decoder` that uses it, so a large file does not pay for
+//! starting a thread per block.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that take jobs from a shared channel until the pool is dropped.
+pub(crate) struct Workers {
+    jobs: Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    pub(crate) fn new(threads: usize) -> Workers {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                std::thread::spawn(move || loop {
+                    // The lock is only held while waiting, not while working.
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        Workers {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    /// Apply `f` to every item on the threads of the pool, and return the
+    /// results in order. A panic in `f` is raised again here.
+    pub(crate) fn map<T, U, F>(&self, items: Vec<T>, f: F) -> Vec<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let count = items.len();
+        let (results, received) = mpsc::channel();
+        for (i, item) in items.into_iter().enumerate() {
+            let (f, results) = (Arc::clone(&f), results.clone());
+            let job: Job = Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)));
+                let _ = results.send((i, result));
+            });
+            self.jobs
+                .as_ref()
+                .unwrap()
+                .send(job)
+                .expect("workers run until the pool is dropped");
+        }
+        drop(results);
+
+        let mut out: Vec<Option<U>> = (0..count).map(|_| None).collect();
+        for (i, result) in received.iter().take(count) {
+            match result {
+                Ok(result) => out[i] = Some(result),
+                Err(e) => panic::resume_unwind(e),
+            }
+        }
+        out.into_iter()
+            .map(|result| result.expect("every job sends a result"))
+            .collect()
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        // Closing the channel stops the threads once they are idle.
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn keeps_results_in_order_and_reuses_threads() {
+        let workers = Workers::new(3);
+        let mut seen = HashSet::new();
+        for batch in 0..10u64 {
+            let items: Vec<u64> = (0..5).map(|i| batch * 5 + i).collect();
+            let results = workers.map(items.clone(), |i| (i * i, std::thread::current().id()));
+            assert_eq!(
+                results
+                    .iter()
+                    .map(|(square, _)| *square)
+                    .collect::<Vec<_>>(),
+                items.iter().map(|i| i * i).collect::<Vec<_>>()
+            );
+            seen.extend(results.into_iter().map(|(_, id)| id));
+        }
+        assert!(seen.len() <= 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad item")]
+    fn raises_panics_of_jobs() {
+        let workers = Workers::new(2);
+        workers.map(vec![1, 2, 3], |i| {
+            if i == 2 {
+                panic!("bad item");
+            }
+            i
+        });
+    }
+}